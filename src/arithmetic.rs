@@ -0,0 +1,481 @@
+use crate::interpreter::Executor;
+
+/// Operators, longest first so that `<<=` isn't taken for `<<` or `<`
+static OPERATORS: [&str; 34] = [
+    "<<=", ">>=", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=", "/=", "%=", "+=", "-=",
+    "&=", "^=", "|=", "*", "/", "%", "+", "-", "<", ">", "&", "^", "|", "!", "~", "?", ":", "=",
+    "(",
+];
+
+static ASSIGNMENT_OPERATORS: [&str; 11] = [
+    "=", "*=", "/=", "%=", "+=", "-=", "<<=", ">>=", "&=", "^=", "|=",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(String),
+    Name(String),
+    Operator(&'static str),
+    ClosingParenthesis,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Expression {
+    Number(i64),
+    Variable(String),
+    Unary(&'static str, Box<Expression>),
+    Binary(Box<Expression>, &'static str, Box<Expression>),
+
+    /// `condition ? then : otherwise`
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+
+    /// `name = value`, or any other assignment operator like `+=`
+    Assignment(String, &'static str, Box<Expression>),
+}
+
+fn tokenize(expression: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = expression.chars().collect();
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut index = 0;
+    'next_token: while index < chars.len() {
+        let character = chars[index];
+        if character.is_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        if character.is_ascii_alphanumeric() || character == '_' {
+            let start = index;
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+            {
+                index += 1;
+            }
+            let word: String = chars[start..index].iter().collect();
+            if character.is_ascii_digit() {
+                tokens.push(Token::Number(word));
+            } else {
+                tokens.push(Token::Name(word));
+            }
+            continue;
+        }
+
+        if character == ')' {
+            tokens.push(Token::ClosingParenthesis);
+            index += 1;
+            continue;
+        }
+
+        let rest: String = chars[index..].iter().take(3).collect();
+        for operator in OPERATORS {
+            if rest.starts_with(operator) {
+                tokens.push(Token::Operator(operator));
+                index += operator.len();
+                continue 'next_token;
+            }
+        }
+
+        return Err(format!("Unexpected character {}", character));
+    }
+
+    return Ok(tokens);
+}
+
+/// Parses an integer constant, which can be decimal, octal with a leading
+/// `0`, or hexadecimal with a leading `0x`
+fn parse_number(text: &str) -> Result<i64, String> {
+    let (digits, radix) =
+        if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            (hex, 16)
+        } else if text.len() > 1 && text.starts_with('0') {
+            (&text[1..], 8)
+        } else {
+            (text, 10)
+        };
+
+    match i64::from_str_radix(digits, radix) {
+        Ok(number) => return Ok(number),
+        Err(error) => {
+            if matches!(
+                error.kind(),
+                std::num::IntErrorKind::PosOverflow | std::num::IntErrorKind::NegOverflow
+            ) {
+                return Err(format!("Number too large: {}", text));
+            }
+            return Err(format!("Not a number: {}", text));
+        }
+    }
+}
+
+/// Binary operators and how hard they bind, higher binds harder
+fn precedence(operator: &str) -> Option<u8> {
+    return match operator {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    };
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        return self.tokens.get(self.position);
+    }
+
+    fn peek_operator(&self) -> Option<&'static str> {
+        if let Some(Token::Operator(operator)) = self.peek() {
+            return Some(operator);
+        }
+        return None;
+    }
+
+    fn parse_assignment(&mut self) -> Result<Expression, String> {
+        if let Some(Token::Name(name)) = self.peek() {
+            if let Some(Token::Operator(operator)) = self.tokens.get(self.position + 1) {
+                if ASSIGNMENT_OPERATORS.contains(operator) {
+                    let name = name.to_string();
+                    let operator = *operator;
+                    self.position += 2;
+
+                    // Right associative, "a = b = 1" sets both
+                    let value = self.parse_assignment()?;
+                    return Ok(Expression::Assignment(name, operator, Box::new(value)));
+                }
+            }
+        }
+
+        return self.parse_conditional();
+    }
+
+    fn parse_conditional(&mut self) -> Result<Expression, String> {
+        let condition = self.parse_binary(1)?;
+        if self.peek_operator() != Some("?") {
+            return Ok(condition);
+        }
+        self.position += 1;
+
+        let then = self.parse_assignment()?;
+        if self.peek_operator() != Some(":") {
+            return Err("Expected : here".to_string());
+        }
+        self.position += 1;
+        let otherwise = self.parse_conditional()?;
+
+        return Ok(Expression::Conditional(
+            Box::new(condition),
+            Box::new(then),
+            Box::new(otherwise),
+        ));
+    }
+
+    /// Precedence climbing, parses binary operators binding at least as hard
+    /// as `minimum_precedence`
+    fn parse_binary(&mut self, minimum_precedence: u8) -> Result<Expression, String> {
+        let mut left = self.parse_unary()?;
+        loop {
+            let operator = self.peek_operator();
+            if operator.is_none() {
+                break;
+            }
+            let operator = operator.unwrap();
+            let operator_precedence = precedence(operator);
+            if operator_precedence.is_none() || operator_precedence.unwrap() < minimum_precedence {
+                break;
+            }
+            self.position += 1;
+
+            // Left associative, so the right hand side must bind harder
+            let right = self.parse_binary(operator_precedence.unwrap() + 1)?;
+            left = Expression::Binary(Box::new(left), operator, Box::new(right));
+        }
+
+        return Ok(left);
+    }
+
+    fn parse_unary(&mut self) -> Result<Expression, String> {
+        if let Some(operator) = self.peek_operator() {
+            if ["+", "-", "~", "!"].contains(&operator) {
+                self.position += 1;
+                return Ok(Expression::Unary(operator, Box::new(self.parse_unary()?)));
+            }
+        }
+
+        return self.parse_primary();
+    }
+
+    fn parse_primary(&mut self) -> Result<Expression, String> {
+        let token = self.peek().cloned();
+        self.position += 1;
+        match token {
+            Some(Token::Number(number)) => return Ok(Expression::Number(parse_number(&number)?)),
+            Some(Token::Name(name)) => return Ok(Expression::Variable(name)),
+            Some(Token::Operator("(")) => {
+                let expression = self.parse_assignment()?;
+                if self.peek() != Some(&Token::ClosingParenthesis) {
+                    return Err("Expected ) here".to_string());
+                }
+                self.position += 1;
+                return Ok(expression);
+            }
+            _ => return Err("Expected a number or a variable name".to_string()),
+        }
+    }
+}
+
+fn parse(expression: &str) -> Result<Expression, String> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        position: 0,
+    };
+
+    let parsed = parser.parse_assignment()?;
+    if let Some(token) = parser.peek() {
+        let text = match token {
+            Token::Number(text) | Token::Name(text) => text.to_string(),
+            Token::Operator(operator) => operator.to_string(),
+            Token::ClosingParenthesis => ")".to_string(),
+        };
+        return Err(format!("Unexpected {}", text));
+    }
+    return Ok(parsed);
+}
+
+fn overflow() -> String {
+    return "Arithmetic overflow".to_string();
+}
+
+fn apply(left: i64, operator: &str, right: i64) -> Result<i64, String> {
+    let result = match operator {
+        "*" => left.checked_mul(right),
+        "/" | "%" => {
+            if right == 0 {
+                return Err("Division by zero".to_string());
+            }
+            if operator == "/" {
+                left.checked_div(right)
+            } else {
+                left.checked_rem(right)
+            }
+        }
+        "+" => left.checked_add(right),
+        "-" => left.checked_sub(right),
+        "<<" | ">>" => {
+            if !(0..64).contains(&right) {
+                return Err(format!("Shift count out of range: {}", right));
+            }
+            if operator == "<<" {
+                left.checked_shl(right as u32)
+            } else {
+                left.checked_shr(right as u32)
+            }
+        }
+        "<" => Some((left < right) as i64),
+        "<=" => Some((left <= right) as i64),
+        ">" => Some((left > right) as i64),
+        ">=" => Some((left >= right) as i64),
+        "==" => Some((left == right) as i64),
+        "!=" => Some((left != right) as i64),
+        "&" => Some(left & right),
+        "^" => Some(left ^ right),
+        "|" => Some(left | right),
+        _ => panic!("Not a binary operator: {}", operator),
+    };
+
+    return result.ok_or_else(overflow);
+}
+
+fn variable_value(executor: &dyn Executor, name: &str) -> Result<i64, String> {
+    let value = executor.get_variable(name).unwrap_or_default();
+    let value = value.trim();
+    if value.is_empty() {
+        // Unset and empty variables count as zero
+        return Ok(0);
+    }
+
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Not a number: {}={}", name, value));
+    }
+    let number = parse_number(digits).map_err(|_| format!("Not a number: {}={}", name, value))?;
+    if negative {
+        return number.checked_neg().ok_or_else(overflow);
+    }
+    return Ok(number);
+}
+
+fn evaluate_expression(
+    executor: &mut dyn Executor,
+    expression: &Expression,
+) -> Result<i64, String> {
+    match expression {
+        Expression::Number(number) => return Ok(*number),
+        Expression::Variable(name) => return variable_value(executor, name),
+        Expression::Unary(operator, operand) => {
+            let operand = evaluate_expression(executor, operand)?;
+            return match *operator {
+                "+" => Ok(operand),
+                "-" => operand.checked_neg().ok_or_else(overflow),
+                "~" => Ok(!operand),
+                "!" => Ok((operand == 0) as i64),
+                _ => panic!("Not a unary operator: {}", operator),
+            };
+        }
+        Expression::Binary(left, "&&", right) => {
+            if evaluate_expression(executor, left)? == 0 {
+                return Ok(0);
+            }
+            return Ok((evaluate_expression(executor, right)? != 0) as i64);
+        }
+        Expression::Binary(left, "||", right) => {
+            if evaluate_expression(executor, left)? != 0 {
+                return Ok(1);
+            }
+            return Ok((evaluate_expression(executor, right)? != 0) as i64);
+        }
+        Expression::Binary(left, operator, right) => {
+            let left = evaluate_expression(executor, left)?;
+            let right = evaluate_expression(executor, right)?;
+            return apply(left, operator, right);
+        }
+        Expression::Conditional(condition, then, otherwise) => {
+            if evaluate_expression(executor, condition)? != 0 {
+                return evaluate_expression(executor, then);
+            }
+            return evaluate_expression(executor, otherwise);
+        }
+        Expression::Assignment(name, operator, value) => {
+            let mut value = evaluate_expression(executor, value)?;
+            if *operator != "=" {
+                let current = variable_value(executor, name)?;
+                value = apply(current, operator.trim_end_matches('='), value)?;
+            }
+            executor.set_variable(name, &value.to_string());
+            return Ok(value);
+        }
+    }
+}
+
+/// Evaluates an arithmetic expression, as in the already expanded insides of
+/// `$((...))`. Variables are referenced by name, like in `i + 1`, and can be
+/// assigned to, like in `i += 1`.
+///
+/// Returns an error message if the expression doesn't parse, divides by
+/// zero or overflows.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap01.html#tag_17_01_02_01
+pub(crate) fn evaluate(executor: &mut dyn Executor, expression: &str) -> Result<i64, String> {
+    let parsed = parse(expression)?;
+    return evaluate_expression(executor, &parsed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testexecutor::TestExecutor;
+
+    fn calculate(expression: &str) -> i64 {
+        return evaluate(&mut TestExecutor::new(), expression).unwrap();
+    }
+
+    fn calculate_error(expression: &str) -> String {
+        return evaluate(&mut TestExecutor::new(), expression).unwrap_err();
+    }
+
+    #[test]
+    fn test_numbers() {
+        assert_eq!(calculate("42"), 42);
+        assert_eq!(calculate(" 010 "), 8);
+        assert_eq!(calculate("0x1F"), 31);
+        assert_eq!(calculate("-9223372036854775807 - 1"), i64::MIN);
+        assert_eq!(calculate_error("09"), "Not a number: 09");
+        assert_eq!(
+            calculate_error("9223372036854775808"),
+            "Number too large: 9223372036854775808"
+        );
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(calculate("1 + 2 * 3"), 7);
+        assert_eq!(calculate("(1 + 2) * 3"), 9);
+        assert_eq!(calculate("10 - 4 - 3"), 3);
+        assert_eq!(calculate("2 * 3 % 4"), 2);
+        assert_eq!(calculate("1 << 2 + 1"), 8);
+        assert_eq!(calculate("1 < 2 == 1"), 1);
+        assert_eq!(calculate("6 & 3 ^ 1 | 8"), 11);
+        assert_eq!(calculate("-2 * -3"), 6);
+        assert_eq!(calculate("!0 + ~0"), 0);
+        assert_eq!(calculate("0 || 2 && 3"), 1);
+        assert_eq!(calculate("1 ? 2 : 3 ? 4 : 5"), 2);
+        assert_eq!(calculate("0 ? 2 : 0 ? 4 : 5"), 5);
+    }
+
+    #[test]
+    fn test_variables() {
+        let mut executor = TestExecutor::new();
+        executor.set_variable("i", "5");
+        executor.set_variable("negative", " -3 ");
+        executor.set_variable("empty", "");
+        executor.set_variable("text", "hello");
+
+        assert_eq!(evaluate(&mut executor, "i + 1").unwrap(), 6);
+        assert_eq!(evaluate(&mut executor, "i * negative").unwrap(), -15);
+        assert_eq!(evaluate(&mut executor, "empty + unset").unwrap(), 0);
+        assert_eq!(
+            evaluate(&mut executor, "text + 1").unwrap_err(),
+            "Not a number: text=hello"
+        );
+
+        assert_eq!(evaluate(&mut executor, "i += 2").unwrap(), 7);
+        assert_eq!(executor.get_variable("i"), Some("7".to_string()));
+        assert_eq!(evaluate(&mut executor, "a = b = i <<= 1").unwrap(), 14);
+        assert_eq!(executor.get_variable("a"), Some("14".to_string()));
+        assert_eq!(executor.get_variable("b"), Some("14".to_string()));
+
+        // Short circuiting skips assignments
+        assert_eq!(evaluate(&mut executor, "0 && (x = 1)").unwrap(), 0);
+        assert_eq!(evaluate(&mut executor, "1 || (x = 1)").unwrap(), 1);
+        assert_eq!(evaluate(&mut executor, "1 ? 2 : (x = 1)").unwrap(), 2);
+        assert_eq!(executor.get_variable("x"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(calculate_error("1 / 0"), "Division by zero");
+        assert_eq!(calculate_error("1 % 0"), "Division by zero");
+        assert_eq!(
+            calculate_error("9223372036854775807 + 1"),
+            "Arithmetic overflow"
+        );
+        assert_eq!(
+            calculate_error("-9223372036854775807 - 2"),
+            "Arithmetic overflow"
+        );
+        assert_eq!(calculate_error("1 << 64"), "Shift count out of range: 64");
+        assert_eq!(
+            calculate_error("1 +"),
+            "Expected a number or a variable name"
+        );
+        assert_eq!(calculate_error("(1"), "Expected ) here");
+        assert_eq!(calculate_error("1 2"), "Unexpected 2");
+        assert_eq!(calculate_error("1 ? 2"), "Expected : here");
+        assert_eq!(calculate_error("1 = 2"), "Unexpected =");
+        assert_eq!(calculate_error("1 @ 2"), "Unexpected character @");
+        assert_eq!(calculate_error(""), "Expected a number or a variable name");
+    }
+}
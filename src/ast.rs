@@ -0,0 +1,220 @@
+use std::fmt::Display;
+use std::rc::Rc;
+
+/// A word as it was written on the command line, before expansion
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Word {
+    /// The raw text, including any quotes and backslashes
+    pub text: String,
+
+    /// Byte offset of the first character of this word into the command line
+    pub offset: usize,
+}
+
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_07
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum RedirectionOperator {
+    /// `<`
+    Input,
+
+    /// `>`
+    Output,
+
+    /// `>|`
+    Clobber,
+
+    /// `>>`
+    Append,
+
+    /// `<>`
+    ReadWrite,
+
+    /// `<&`
+    DuplicateInput,
+
+    /// `>&`
+    DuplicateOutput,
+}
+
+impl Display for RedirectionOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = match self {
+            RedirectionOperator::Input => "<",
+            RedirectionOperator::Output => ">",
+            RedirectionOperator::Clobber => ">|",
+            RedirectionOperator::Append => ">>",
+            RedirectionOperator::ReadWrite => "<>",
+            RedirectionOperator::DuplicateInput => "<&",
+            RedirectionOperator::DuplicateOutput => ">&",
+        };
+        f.write_str(operator)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Redirection {
+    /// The IO number in front of the operator, as in the `2` in `2>&1`
+    pub fd: Option<i32>,
+
+    pub operator: RedirectionOperator,
+
+    /// File name or file descriptor number
+    pub target: Word,
+}
+
+/// A variable assignment in front of a command, as in `FOO=1 cmd`, or on its
+/// own as in `FOO=1`.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap04.html#tag_04_23
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Assignment {
+    pub name: String,
+
+    /// Everything after the `=`, before expansion
+    pub value: Word,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct SimpleCommand {
+    pub assignments: Vec<Assignment>,
+
+    /// The command name followed by its arguments. Can be empty if the
+    /// command consists of only assignments and redirections.
+    pub words: Vec<Word>,
+
+    pub redirections: Vec<Redirection>,
+}
+
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum CompoundCommand {
+    /// `{ list; }`
+    BraceGroup(List),
+
+    /// `( list )`
+    Subshell(List),
+
+    /// `if list; then list; [elif list; then list;]... [else list;] fi`
+    If {
+        /// Each condition with the list to run if it succeeds, first the
+        /// `if` and then any `elif`s
+        conditionals: Vec<(List, List)>,
+
+        /// The `else` part
+        otherwise: Option<List>,
+    },
+
+    /// `while list; do list; done`
+    While { condition: List, body: List },
+
+    /// `until list; do list; done`
+    Until { condition: List, body: List },
+
+    /// `for name [in word...]; do list; done`
+    For {
+        name: String,
+
+        /// `None` if there was no `in`, meaning the loop should go over the
+        /// positional parameters
+        words: Option<Vec<Word>>,
+
+        body: List,
+    },
+
+    /// `case word in [(]pattern[|pattern]...) list;; ... esac`
+    Case { word: Word, items: Vec<CaseItem> },
+}
+
+/// One `pattern|pattern) list;;` part of a `case` command
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct CaseItem {
+    pub patterns: Vec<Word>,
+    pub body: List,
+}
+
+/// `name() compound-command`
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_05
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct FunctionDefinition {
+    pub name: String,
+
+    /// Shared, since the function table keeps this around after the command
+    /// line that defined it is gone
+    pub body: Rc<Command>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Command {
+    Simple(SimpleCommand),
+
+    Compound(CompoundCommand, Vec<Redirection>),
+
+    FunctionDefinition(FunctionDefinition),
+}
+
+/// One or more commands separated by `|`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Pipeline {
+    /// True if the pipeline started with a `!`, inverting its exit status
+    pub bang: bool,
+
+    pub commands: Vec<Command>,
+
+    /// The pipeline as it was written on the command line, for describing
+    /// jobs
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum AndOrOperator {
+    /// `&&`
+    And,
+
+    /// `||`
+    Or,
+}
+
+/// Pipelines separated by `&&` or `||`
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AndOr {
+    pub first: Pipeline,
+    pub rest: Vec<(AndOrOperator, Pipeline)>,
+}
+
+impl AndOr {
+    /// The and-or list as it was written on the command line, for describing
+    /// jobs
+    pub fn text(&self) -> String {
+        let mut text = self.first.text.clone();
+        for (operator, pipeline) in &self.rest {
+            let operator = match operator {
+                AndOrOperator::And => "&&",
+                AndOrOperator::Or => "||",
+            };
+            text.push_str(&format!(" {} {}", operator, pipeline.text));
+        }
+        return text;
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Separator {
+    /// `;` or newline, or nothing at all after the last command
+    Sequential,
+
+    /// `&`
+    Background,
+}
+
+/// And-or lists separated by `;`, `&` or newlines.
+///
+/// This is the root of the syntax tree, following the [POSIX shell
+/// grammar][1]. The tree owns all of its data so that it can outlive the
+/// command line it was parsed from.
+///
+/// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_10
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct List {
+    pub items: Vec<(AndOr, Separator)>,
+}
@@ -0,0 +1,301 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{setpgid, Pid};
+
+use crate::signals::restore_default_signal_handlers;
+
+/// Give up on completion helpers taking longer than this, the user would
+/// rather get no completions than a hanging prompt
+#[cfg(not(test))]
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Short enough to not slow down the tests, long enough for the quick helpers
+/// they run
+#[cfg(test)]
+const TIMEOUT: Duration = Duration::from_millis(300);
+
+/// How long to remember completions for. Pressing tab twice asks for the same
+/// completions twice.
+const CACHE_LIFETIME: Duration = Duration::from_secs(5);
+
+/// Loads the completion scripts into `bash`, calls the completion function
+/// and prints the resulting `COMPREPLY` array one entry per line. Exits with
+/// an error if there is no completion function.
+///
+/// Arguments are the main `bash_completion` script, the completion script for
+/// the command, `COMP_CWORD`, `COMP_LINE` and then `COMP_WORDS`.
+///
+/// Ref: https://www.gnu.org/software/bash/manual/html_node/Programmable-Completion.html
+static HELPER_SCRIPT: &str = r#"
+main_script=$1
+script=$2
+COMP_CWORD=$3
+COMP_LINE=$4
+COMP_POINT=${#COMP_LINE}
+COMP_TYPE=9
+COMP_KEY=9
+shift 4
+COMP_WORDS=("$@")
+
+if [[ -r $main_script ]]; then
+    source "$main_script" >/dev/null 2>&1
+fi
+source "$script" >/dev/null 2>&1
+
+read -ra spec <<< "$(complete -p -- "$1" 2>/dev/null)"
+for ((i = 0; i < ${#spec[@]}; i++)); do
+    if [[ ${spec[i]} == -F ]]; then
+        function=${spec[i + 1]}
+    fi
+done
+if [[ -z $function ]]; then
+    exit 1 # NO_COMPLETION_FUNCTION
+fi
+
+"$function" "$1" "${COMP_WORDS[COMP_CWORD]}" "${COMP_WORDS[COMP_CWORD - 1]}" >/dev/null 2>&1
+if ((${#COMPREPLY[@]} > 0)); then
+    printf '%s\n' "${COMPREPLY[@]}"
+fi
+"#;
+
+/// `HELPER_SCRIPT` exits with this if there is no completion function
+const NO_COMPLETION_FUNCTION: i32 = 1;
+
+/// Why a helper command gave us no output
+#[derive(Debug, PartialEq)]
+pub(crate) enum HelperFailure {
+    /// It couldn't be started, took too long or was killed
+    NoAnswer,
+
+    /// It exited with this non-zero exit code
+    ExitCode(i32),
+}
+
+/// A completion request together with its answer
+struct CachedCompletion {
+    current_dir: PathBuf,
+    words: Vec<String>,
+    time: Instant,
+    completions: Vec<String>,
+}
+
+/// Completes command arguments using the completion scripts that come with
+/// `bash`, so that we don't have to maintain our own.
+///
+/// For each completion, a `bash` helper process loads the completion script
+/// for the command and runs the completion function it registers with
+/// `complete -F`.
+pub(crate) struct BashCompleter {
+    /// Most completion scripts need the functions in here
+    main_script: PathBuf,
+
+    /// Contains one completion script per command, named like the command
+    completions_dir: PathBuf,
+
+    /// Commands whose completion script turned out not to work for us
+    unsupported: RefCell<HashSet<String>>,
+
+    cache: RefCell<Option<CachedCompletion>>,
+}
+
+impl BashCompleter {
+    /// Uses the completion scripts from the `bash-completion` package
+    pub fn new() -> Self {
+        return BashCompleter::with_scripts(Path::new("/usr/share/bash-completion"));
+    }
+
+    /// Uses `directory/bash_completion` and the scripts in
+    /// `directory/completions`
+    pub fn with_scripts(directory: &Path) -> Self {
+        return BashCompleter {
+            main_script: directory.join("bash_completion"),
+            completions_dir: directory.join("completions"),
+            unsupported: RefCell::new(HashSet::new()),
+            cache: RefCell::new(None),
+        };
+    }
+
+    /// Completes the last of the `words`, where the first word is the command
+    /// name. Returns `None` if there is no completion script for the
+    /// command, or if it failed.
+    pub fn complete(&self, words: &[String], current_dir: &Path) -> Option<Vec<String>> {
+        let command = words.first()?;
+        if command.contains('/') || self.unsupported.borrow().contains(command) {
+            return None;
+        }
+        if let Some(cached) = self.cache.borrow().as_ref() {
+            if cached.current_dir == current_dir
+                && cached.words == words
+                && cached.time.elapsed() < CACHE_LIFETIME
+            {
+                return Some(cached.completions.clone());
+            }
+        }
+
+        let script = self.completions_dir.join(command);
+        if !script.is_file() {
+            return None;
+        }
+
+        let completions = match self.run_helper(&script, words, current_dir) {
+            Ok(completions) => completions,
+            Err(HelperFailure::ExitCode(NO_COMPLETION_FUNCTION)) => {
+                // No point in trying this one again
+                self.unsupported.borrow_mut().insert(command.to_string());
+                return None;
+            }
+            Err(_) => {
+                // Might work next time, like after a timeout caused by a cold
+                // disk cache
+                return None;
+            }
+        };
+
+        self.cache.replace(Some(CachedCompletion {
+            current_dir: current_dir.to_path_buf(),
+            words: words.to_vec(),
+            time: Instant::now(),
+            completions: completions.clone(),
+        }));
+        return Some(completions);
+    }
+
+    /// Runs `HELPER_SCRIPT` in `bash`
+    fn run_helper(
+        &self,
+        script: &Path,
+        words: &[String],
+        current_dir: &Path,
+    ) -> Result<Vec<String>, HelperFailure> {
+        // As if the user had typed the words with single spaces between them
+        let line = words.join(" ");
+
+        let mut command = Command::new("bash");
+        command
+            .args(["--norc", "--noprofile", "-c", HELPER_SCRIPT, "vpixsh"])
+            .arg(&self.main_script)
+            .arg(script)
+            .arg((words.len() - 1).to_string())
+            .arg(line)
+            .args(words)
+            .current_dir(current_dir);
+        let output = output_with_timeout(&mut command)?;
+        return Ok(output.lines().map(str::to_string).collect());
+    }
+}
+
+/// Runs a helper command for completion and returns what it printed
+///
+/// The helper gets a process group of its own, so that if it takes too long
+/// we can kill it together with anything it started.
+pub(crate) fn output_with_timeout(command: &mut Command) -> Result<String, HelperFailure> {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null());
+    unsafe {
+        command.pre_exec(|| {
+            let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+            restore_default_signal_handlers();
+            return Ok(());
+        });
+    }
+    let mut child = command.spawn().map_err(|_| HelperFailure::NoAnswer)?;
+
+    // Read on another thread so that we can stop waiting at any time
+    let mut stdout = child.stdout.take().ok_or(HelperFailure::NoAnswer)?;
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut output = String::new();
+        let _ = stdout.read_to_string(&mut output);
+        let _ = sender.send(output);
+    });
+    let output = receiver.recv_timeout(TIMEOUT);
+    if output.is_err() {
+        // Until the whole group is gone, its processes keep stdout open and
+        // the reader thread waiting
+        let _ = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL);
+    }
+    let status = child.wait().map_err(|_| HelperFailure::NoAnswer)?;
+    if !status.success() {
+        return Err(status
+            .code()
+            .map_or(HelperFailure::NoAnswer, HelperFailure::ExitCode));
+    }
+
+    return output.map_err(|_| HelperFailure::NoAnswer);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    use std::fs;
+
+    fn words(words: &[&str]) -> Vec<String> {
+        return words.iter().map(|word| word.to_string()).collect();
+    }
+
+    #[test]
+    fn test_complete() {
+        let directory = TestDir::new("bashcompletion");
+        fs::create_dir_all(directory.join("completions")).unwrap();
+
+        // The helper function proves that the main script gets loaded
+        fs::write(
+            directory.join("bash_completion"),
+            "_fake_candidates() { echo alpha beta \"cword$COMP_CWORD\"; }\n",
+        )
+        .unwrap();
+        fs::write(
+            directory.join("completions/fake"),
+            r#"_fake() { COMPREPLY=($(compgen -W "$(_fake_candidates) ${COMP_WORDS[0]}" -- "$2")); }
+complete -F _fake fake
+"#,
+        )
+        .unwrap();
+        fs::write(directory.join("completions/broken"), "exit 1\n").unwrap();
+        // Lingers in the background unless the whole process group is killed
+        fs::write(
+            directory.join("completions/slow"),
+            "(sleep 0.5 && touch lingered) &\nwait\n",
+        )
+        .unwrap();
+
+        let completer = BashCompleter::with_scripts(&directory);
+        let complete = |typed: &[&str]| completer.complete(&words(typed), &directory);
+
+        assert_eq!(
+            complete(&["fake", ""]),
+            Some(words(&["alpha", "beta", "cword1", "fake"]))
+        );
+        assert_eq!(complete(&["fake", "b"]), Some(words(&["beta"])));
+        assert_eq!(complete(&["fake", "x", "c"]), Some(words(&["cword2"])));
+        assert_eq!(complete(&["fake", "nothing"]), Some(vec![]));
+
+        assert_eq!(complete(&["missing", "a"]), None);
+        assert_eq!(complete(&["broken", "a"]), None);
+        assert!(completer.unsupported.borrow().contains("broken"));
+
+        let start = Instant::now();
+        assert_eq!(complete(&["slow", "a"]), None);
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(!completer.unsupported.borrow().contains("slow"));
+        thread::sleep(Duration::from_millis(700) - start.elapsed());
+        assert!(!directory.join("lingered").exists());
+
+        // Cached, the script is gone but the answer is still there
+        fs::remove_dir_all(&directory).unwrap();
+        assert_eq!(complete(&["fake", "nothing"]), Some(vec![]));
+    }
+}
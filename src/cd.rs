@@ -54,7 +54,7 @@ impl Shell {
         }
         target = canonicalize_result.unwrap();
 
-        if let Err(error) = fs::read_dir(target.to_owned()) {
+        if let Err(error) = fs::read_dir(&target) {
             println!(
                 "ERROR: Target directory <{}> is inaccessible: {}",
                 target.to_string_lossy(),
@@ -83,12 +83,12 @@ impl Shell {
 
     pub(crate) fn cd(&mut self, args: &[String]) -> String {
         if args.is_empty() {
-            let env_home = env::var("HOME");
-            if let Err(error) = env_home {
-                println!("ERROR: Cannot read HOME environment variable: {}", error);
+            let home = self.variables.get("HOME");
+            if home.is_none() {
+                println!("ERROR: HOME variable not set");
                 return "HOME not set".to_string();
             }
-            return self.cd(&[env_home.unwrap()]);
+            return self.cd(&[home.unwrap().to_string()]);
         }
 
         if args.len() != 1 {
@@ -99,12 +99,11 @@ impl Shell {
         let dir_before = env::current_dir();
 
         let target = &args[0];
-        let problem: String;
-        if target == "-" {
-            problem = self.cd_minus();
+        let problem: String = if target == "-" {
+            self.cd_minus()
         } else {
-            problem = self.cd_directory(PathBuf::from(target));
-        }
+            self.cd_directory(PathBuf::from(target))
+        };
 
         if !problem.is_empty() {
             // Trouble, don't update oldpwd
@@ -119,6 +118,11 @@ impl Shell {
             }
         }
 
+        // Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/cd.html
+        if let Ok(current_dir) = env::current_dir() {
+            self.variables.set("PWD", &current_dir.to_string_lossy());
+        }
+
         return problem;
     }
 }
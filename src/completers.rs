@@ -0,0 +1,626 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use nix::sys::signal::Signal;
+use nix::unistd::getuid;
+use rustyline::completion::Pair;
+
+use crate::bashcompletion::output_with_timeout;
+use crate::completion::list_directory;
+
+/// What an `ArgumentCompleter` gets to work with
+pub(crate) struct CompletionContext<'a> {
+    /// The words of the command before the one being completed, starting
+    /// with the command name. Any quoting is still in there.
+    pub words: &'a [String],
+
+    /// The word being completed, with its quotes removed
+    pub word: &'a str,
+
+    pub current_dir: &'a Path,
+
+    /// The shell variables as of the last prompt
+    pub variables: &'a HashMap<String, String>,
+
+    /// Job IDs like `%1`, each with the command line of its job
+    pub jobs: &'a [(String, String)],
+}
+
+impl<'a> CompletionContext<'a> {
+    fn variable(&self, name: &str) -> Option<&str> {
+        return self.variables.get(name).map(String::as_str);
+    }
+}
+
+/// Completes the arguments of some command
+pub(crate) trait ArgumentCompleter {
+    /// Returns all candidates for the word being completed. Candidate
+    /// replacements are unquoted, and the caller picks the ones matching what
+    /// the user typed, see `fuzzy_filter()`. Replacements ending in `/` are
+    /// not complete words yet, like directory names.
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair>;
+}
+
+fn pair(replacement: &str) -> Pair {
+    return Pair {
+        display: replacement.to_string(),
+        replacement: replacement.to_string(),
+    };
+}
+
+/// True if all characters of `typed` are in `candidate`, in order. Case is
+/// ignored.
+fn is_fuzzy_match(candidate: &str, typed: &str) -> bool {
+    let mut candidate = candidate.chars().flat_map(char::to_lowercase);
+    return typed
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|wanted| candidate.any(|character| character == wanted));
+}
+
+/// Keeps the candidates starting with `typed`. If there are none, keeps the
+/// ones fuzzily matching it instead, so that `man sgnl` finds `signal`.
+///
+/// The result is sorted, without duplicates.
+pub(crate) fn fuzzy_filter(mut candidates: Vec<Pair>, typed: &str) -> Vec<Pair> {
+    candidates.sort_by(|a, b| a.replacement.cmp(&b.replacement));
+    candidates.dedup_by(|a, b| a.replacement == b.replacement);
+
+    if candidates
+        .iter()
+        .any(|candidate| candidate.replacement.starts_with(typed))
+    {
+        candidates.retain(|candidate| candidate.replacement.starts_with(typed));
+    } else {
+        candidates.retain(|candidate| is_fuzzy_match(&candidate.replacement, typed));
+    }
+    return candidates;
+}
+
+/// Where `man` looks when `MANPATH` doesn't say
+static DEFAULT_MANPATH: &str = "/usr/local/share/man:/usr/share/man:/usr/local/man";
+
+/// Completes manual page names, found in the `man*` directories of the
+/// `MANPATH` directories
+#[derive(Default)]
+pub(crate) struct ManCompleter {
+    /// The manual page names, together with the `MANPATH` used for finding
+    /// them
+    index: RefCell<Option<(String, Vec<String>)>>,
+}
+
+/// Turns a file name like `signal.7.gz` into a page name like `signal`
+fn man_page_name(file_name: &str) -> Option<&str> {
+    let mut name = file_name;
+    for compression in [".gz", ".bz2", ".xz", ".lzma", ".zst", ".Z"] {
+        name = name.strip_suffix(compression).unwrap_or(name);
+    }
+    let (name, _section) = name.rsplit_once('.')?;
+    if name.is_empty() {
+        return None;
+    }
+    return Some(name);
+}
+
+/// Lists the manual page names in the `manpath` directories, sorted and
+/// without duplicates. Empty entries in `manpath` stand for the default
+/// directories.
+fn index_man_pages(manpath: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for directory in manpath.split(':') {
+        let directories = if directory.is_empty() {
+            DEFAULT_MANPATH.split(':').collect()
+        } else {
+            vec![directory]
+        };
+
+        for directory in directories {
+            let sections = match fs::read_dir(directory) {
+                Ok(sections) => sections,
+                Err(_) => continue,
+            };
+            for section in sections.flatten() {
+                if !section.file_name().to_string_lossy().starts_with("man") {
+                    continue;
+                }
+                let pages = match fs::read_dir(section.path()) {
+                    Ok(pages) => pages,
+                    Err(_) => continue,
+                };
+                for page in pages.flatten() {
+                    if let Some(name) = man_page_name(&page.file_name().to_string_lossy()) {
+                        names.push(name.to_string());
+                    }
+                }
+            }
+        }
+    }
+
+    names.sort();
+    names.dedup();
+    return names;
+}
+
+impl ArgumentCompleter for ManCompleter {
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair> {
+        let manpath = context.variable("MANPATH").unwrap_or_default();
+        let mut index = self.index.borrow_mut();
+        if index.as_ref().map(|(path, _)| path.as_str()) != Some(manpath) {
+            *index = Some((manpath.to_string(), index_man_pages(manpath)));
+        }
+
+        return index
+            .as_ref()
+            .unwrap()
+            .1
+            .iter()
+            .map(|name| pair(name))
+            .collect();
+    }
+}
+
+/// Completes directory names, also looking in the `CDPATH` directories
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/cd.html
+pub(crate) struct CdCompleter {}
+
+impl ArgumentCompleter for CdCompleter {
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair> {
+        let (directory, prefix) = match context.word.rfind('/') {
+            Some(slash) => context.word.split_at(slash + 1),
+            None => ("", context.word),
+        };
+
+        let mut lookups: Vec<PathBuf> = vec![context.current_dir.join(directory)];
+        if let (Some(relative), Some(home)) =
+            (directory.strip_prefix("~/"), context.variable("HOME"))
+        {
+            lookups = vec![Path::new(home).join(relative)];
+        } else if !directory.starts_with('/')
+            && !directory.starts_with("./")
+            && !directory.starts_with("../")
+        {
+            if let Some(cdpath) = context.variable("CDPATH") {
+                // An empty entry means the current directory
+                let bases = cdpath.split(':').map(|base| context.current_dir.join(base));
+                lookups.extend(bases.map(|base| base.join(directory)));
+            }
+        }
+
+        // List everything and leave the matching to `fuzzy_filter()`, except
+        // that hidden directories are only wanted when asked for
+        let hidden_prefix = if prefix.starts_with('.') { "." } else { "" };
+        let mut candidates: Vec<Pair> = Vec::new();
+        for lookup in lookups {
+            for name in list_directory(&lookup, hidden_prefix) {
+                if name.ends_with('/') {
+                    candidates.push(Pair {
+                        replacement: format!("{}{}", directory, name),
+                        display: name,
+                    });
+                }
+            }
+        }
+        return candidates;
+    }
+}
+
+/// Subcommands taking branch, tag or remote branch names
+static GIT_REF_COMMANDS: [&str; 13] = [
+    "branch",
+    "checkout",
+    "cherry-pick",
+    "diff",
+    "log",
+    "merge",
+    "pull",
+    "push",
+    "rebase",
+    "reset",
+    "show",
+    "switch",
+    "tag",
+];
+
+/// Global `git` options taking a separate argument, as in `git -C dir log`
+static GIT_OPTIONS_WITH_ARGUMENT: [&str; 5] =
+    ["-C", "-c", "--git-dir", "--work-tree", "--namespace"];
+
+/// Completes `git` subcommands, and branch and tag names for the subcommands
+/// taking those
+#[derive(Default)]
+pub(crate) struct GitCompleter {
+    /// Asking `git` takes a while, and the subcommands don't change much
+    subcommands: RefCell<Option<Vec<String>>>,
+}
+
+/// Returns the subcommand of a `git` command line, like `log` for
+/// `git -C dir log`. `None` means the subcommand is what's being completed.
+fn git_subcommand(words: &[String]) -> Option<&str> {
+    let mut words = words.iter().skip(1);
+    while let Some(word) = words.next() {
+        if GIT_OPTIONS_WITH_ARGUMENT.contains(&word.as_str()) {
+            words.next();
+            continue;
+        }
+        if !word.starts_with('-') {
+            return Some(word);
+        }
+    }
+    return None;
+}
+
+/// Runs `git` with the given arguments and returns its output lines
+fn git_lines(args: &[&str], current_dir: &Path) -> Vec<String> {
+    let mut command = Command::new("git");
+    command.args(args).current_dir(current_dir);
+    match output_with_timeout(&mut command) {
+        Ok(output) => return output.lines().map(str::to_string).collect(),
+        Err(_) => return vec![],
+    }
+}
+
+impl ArgumentCompleter for GitCompleter {
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair> {
+        match git_subcommand(context.words) {
+            None => {
+                let mut subcommands = self.subcommands.borrow_mut();
+                if subcommands.is_none() {
+                    *subcommands = Some(git_lines(
+                        &["--list-cmds=list-mainporcelain,others,nohelpers,alias"],
+                        context.current_dir,
+                    ));
+                }
+                return subcommands
+                    .as_ref()
+                    .unwrap()
+                    .iter()
+                    .map(|name| pair(name))
+                    .collect();
+            }
+            Some(subcommand) if GIT_REF_COMMANDS.contains(&subcommand) => {
+                let refs = git_lines(
+                    &[
+                        "for-each-ref",
+                        "--format=%(refname:short)",
+                        "refs/heads",
+                        "refs/remotes",
+                        "refs/tags",
+                    ],
+                    context.current_dir,
+                );
+                return refs.iter().map(|name| pair(name)).collect();
+            }
+            Some(_) => return vec![],
+        }
+    }
+}
+
+/// Completes host names from `~/.ssh/config` and `~/.ssh/known_hosts`
+pub(crate) struct SshCompleter {}
+
+/// Lists the host names from the `Host` lines of an `ssh_config(5)` file,
+/// skipping patterns
+fn ssh_config_hosts(config: &str) -> Vec<String> {
+    let mut hosts: Vec<String> = Vec::new();
+    for line in config.lines() {
+        let line = line.trim();
+        let (keyword, value) = match line.split_once(|c: char| c == '=' || c.is_whitespace()) {
+            Some(split) => split,
+            None => continue,
+        };
+        if !keyword.eq_ignore_ascii_case("host") {
+            continue;
+        }
+        for host in value
+            .trim_start_matches(['=', ' ', '\t'])
+            .split_whitespace()
+        {
+            if !host.contains(['*', '?', '!']) {
+                hosts.push(host.to_string());
+            }
+        }
+    }
+    return hosts;
+}
+
+/// Lists the host names in an `ssh` `known_hosts` file, skipping hashed ones
+///
+/// Ref: https://man.openbsd.org/sshd.8#SSH_KNOWN_HOSTS_FILE_FORMAT
+fn known_hosts(known_hosts: &str) -> Vec<String> {
+    let mut hosts: Vec<String> = Vec::new();
+    for line in known_hosts.lines() {
+        let mut fields = line.split_whitespace();
+        let mut names = fields.next().unwrap_or_default();
+        if names.starts_with('@') {
+            // A marker like @cert-authority, the names come after it
+            names = fields.next().unwrap_or_default();
+        }
+        if names.starts_with('#') || names.starts_with('|') {
+            continue;
+        }
+
+        for name in names.split(',') {
+            // Non-standard ports are written like [host]:2222
+            let name = match name.strip_prefix('[') {
+                Some(bracketed) => bracketed.split(']').next().unwrap_or_default(),
+                None => name,
+            };
+            if !name.is_empty() && !name.contains(['*', '?', '!']) {
+                hosts.push(name.to_string());
+            }
+        }
+    }
+    return hosts;
+}
+
+impl ArgumentCompleter for SshCompleter {
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair> {
+        let home = match context.variable("HOME") {
+            Some(home) => Path::new(home).join(".ssh"),
+            None => return vec![],
+        };
+
+        let mut hosts =
+            ssh_config_hosts(&fs::read_to_string(home.join("config")).unwrap_or_default());
+        hosts.extend(known_hosts(
+            &fs::read_to_string(home.join("known_hosts")).unwrap_or_default(),
+        ));
+
+        // Keep any user@ in front of the host name
+        let user = match context.word.split_once('@') {
+            Some((user, _)) => format!("{}@", user),
+            None => "".to_string(),
+        };
+        return hosts
+            .iter()
+            .map(|host| Pair {
+                display: host.to_string(),
+                replacement: format!("{}{}", user, host),
+            })
+            .collect();
+    }
+}
+
+/// Completes job IDs, process IDs of the user's own processes and signal
+/// names like `-TERM`
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/kill.html
+pub(crate) struct KillCompleter {}
+
+/// Lists the process IDs of our user's processes, each with its command name.
+/// Asks `ps` rather than reading `/proc`, which only Linux has.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/ps.html
+fn own_processes() -> Vec<Pair> {
+    let mut command = Command::new("ps");
+    command.args(["-o", "pid=,comm=", "-U", &getuid().to_string()]);
+    let output = match output_with_timeout(&mut command) {
+        Ok(output) => output,
+        Err(_) => return vec![],
+    };
+
+    let mut processes: Vec<Pair> = Vec::new();
+    for line in output.lines() {
+        let (pid, name) = line.trim_start().split_once(' ').unwrap_or((line, ""));
+        if pid.is_empty() || !pid.chars().all(|c| c.is_ascii_digit()) {
+            continue;
+        }
+        processes.push(Pair {
+            display: format!("{} {}", pid, name.trim()),
+            replacement: pid.to_string(),
+        });
+    }
+    return processes;
+}
+
+impl ArgumentCompleter for KillCompleter {
+    fn complete(&self, context: &CompletionContext) -> Vec<Pair> {
+        if context.word.starts_with('-') {
+            return Signal::iterator()
+                .map(|signal| pair(&format!("-{}", &signal.as_str()[3..])))
+                .collect();
+        }
+
+        let mut candidates: Vec<Pair> = context
+            .jobs
+            .iter()
+            .map(|(id, text)| Pair {
+                display: format!("{} {}", id, text),
+                replacement: id.to_string(),
+            })
+            .collect();
+        if !context.word.starts_with('%') {
+            candidates.extend(own_processes());
+        }
+        return candidates;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn replacements(candidates: Vec<Pair>) -> Vec<String> {
+        return candidates
+            .into_iter()
+            .map(|pair| pair.replacement)
+            .collect();
+    }
+
+    /// Completes `word` as the last argument of `words`, and filters the
+    /// candidates like the shell does
+    fn complete(
+        completer: &dyn ArgumentCompleter,
+        words: &[&str],
+        word: &str,
+        variables: &[(&str, &str)],
+    ) -> Vec<String> {
+        let words: Vec<String> = words.iter().map(|word| word.to_string()).collect();
+        let variables: HashMap<String, String> = variables
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let jobs = vec![("%1".to_string(), "sleep 100 &".to_string())];
+        let context = CompletionContext {
+            words: &words,
+            word,
+            current_dir: Path::new("/"),
+            variables: &variables,
+            jobs: &jobs,
+        };
+        return replacements(fuzzy_filter(completer.complete(&context), word));
+    }
+
+    #[test]
+    fn test_fuzzy_filter() {
+        let candidates = || {
+            vec![
+                pair("signal"),
+                pair("sigaction"),
+                pair("sig"),
+                pair("Signal"),
+            ]
+        };
+        assert_eq!(
+            replacements(fuzzy_filter(candidates(), "sig")),
+            vec!["sig", "sigaction", "signal"]
+        );
+        assert_eq!(
+            replacements(fuzzy_filter(candidates(), "sgnl")),
+            vec!["Signal", "signal"]
+        );
+        assert_eq!(
+            replacements(fuzzy_filter(candidates(), "xyz")),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_man() {
+        let directory = TestDir::new("man");
+        fs::create_dir_all(directory.join("man7")).unwrap();
+        fs::create_dir_all(directory.join("man2")).unwrap();
+        fs::write(directory.join("man7/signal.7.gz"), "").unwrap();
+        fs::write(directory.join("man2/signal.2"), "").unwrap();
+        fs::write(directory.join("man2/sigaction.2.gz"), "").unwrap();
+        let manpath = directory.display().to_string();
+        let variables = [("MANPATH", manpath.as_str())];
+
+        let man = ManCompleter::default();
+        assert_eq!(
+            complete(&man, &["man"], "signa", &variables),
+            vec!["signal"]
+        );
+        assert_eq!(
+            complete(&man, &["man", "7"], "sgnl", &variables),
+            vec!["signal"]
+        );
+        assert_eq!(
+            complete(&man, &["man"], "sig", &variables),
+            vec!["sigaction", "signal"]
+        );
+
+        assert_eq!(man_page_name("Foo::Bar.3pm.gz"), Some("Foo::Bar"));
+        assert_eq!(man_page_name("README"), None);
+    }
+
+    #[test]
+    fn test_cd() {
+        let directory = TestDir::new("cd");
+        fs::create_dir_all(directory.join("src/vpixsh")).unwrap();
+        fs::create_dir_all(directory.join("src/vpixel")).unwrap();
+        fs::write(directory.join("src/vpixsh.txt"), "").unwrap();
+        let base = directory.display().to_string();
+        let cdpath = format!("{}/src", base);
+
+        let cd = CdCompleter {};
+        assert_eq!(
+            complete(&cd, &["cd"], &format!("{}/src/vp", base), &[]),
+            vec![
+                format!("{}/src/vpixel/", base),
+                format!("{}/src/vpixsh/", base)
+            ]
+        );
+        assert_eq!(
+            complete(&cd, &["cd"], "vpsh", &[("CDPATH", &cdpath)]),
+            vec!["vpixsh/"]
+        );
+        assert_eq!(complete(&cd, &["cd"], "vpsh", &[]), Vec::<String>::new());
+        assert_eq!(
+            complete(&cd, &["cd"], "~/src/vpixs", &[("HOME", &base)]),
+            vec!["~/src/vpixsh/"]
+        );
+    }
+
+    #[test]
+    fn test_git_subcommand() {
+        let words = |words: &[&str]| -> Vec<String> {
+            return words.iter().map(|word| word.to_string()).collect();
+        };
+        assert_eq!(git_subcommand(&words(&["git"])), None);
+        assert_eq!(git_subcommand(&words(&["git", "-C", "dir"])), None);
+        assert_eq!(git_subcommand(&words(&["git", "--no-pager"])), None);
+        assert_eq!(
+            git_subcommand(&words(&["git", "-C", "dir", "log", "-p"])),
+            Some("log")
+        );
+
+        // Not a subcommand taking refs, so that files get completed instead
+        assert_eq!(
+            complete(&GitCompleter::default(), &["git", "add"], "", &[]),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_ssh() {
+        assert_eq!(
+            ssh_config_hosts("Host work *.example.com\n  HostName w.example.com\nhost=laptop\n"),
+            vec!["work", "laptop"]
+        );
+        assert_eq!(
+            known_hosts(
+                "github.com,140.82.121.4 ssh-ed25519 AAAA\n\
+                 |1|hashed= ssh-rsa AAAA\n\
+                 [pi.local]:2222 ssh-ed25519 AAAA\n\
+                 @cert-authority *.corp ssh-rsa AAAA\n"
+            ),
+            vec!["github.com", "140.82.121.4", "pi.local"]
+        );
+
+        let home = TestDir::new("ssh");
+        fs::create_dir_all(home.join(".ssh")).unwrap();
+        fs::write(home.join(".ssh/config"), "Host work\n").unwrap();
+        fs::write(
+            home.join(".ssh/known_hosts"),
+            "github.com ssh-ed25519 AAAA\n",
+        )
+        .unwrap();
+        let home_variable = home.display().to_string();
+        let variables = [("HOME", home_variable.as_str())];
+
+        assert_eq!(
+            complete(&SshCompleter {}, &["ssh"], "gh", &variables),
+            vec!["github.com"]
+        );
+        assert_eq!(
+            complete(&SshCompleter {}, &["ssh"], "me@w", &variables),
+            vec!["me@work"]
+        );
+    }
+
+    #[test]
+    fn test_kill() {
+        let kill = KillCompleter {};
+        assert_eq!(complete(&kill, &["kill"], "%", &[]), vec!["%1"]);
+        assert_eq!(complete(&kill, &["kill"], "-KIL", &[]), vec!["-KILL"]);
+
+        let own_pid = std::process::id().to_string();
+        assert!(complete(&kill, &["kill"], "", &[]).contains(&own_pid));
+    }
+}
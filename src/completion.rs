@@ -0,0 +1,664 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use rustyline::completion::Pair;
+
+use crate::bashcompletion::BashCompleter;
+use crate::completers::{
+    fuzzy_filter, ArgumentCompleter, CdCompleter, CompletionContext, GitCompleter, KillCompleter,
+    ManCompleter, SshCompleter,
+};
+use crate::expansion::is_name;
+use crate::tokenizer::{to_tokens, Token};
+use crate::{Shell, BUILTINS};
+
+/// Characters that need a backslash in front of them outside of quotes for
+/// the tokenizer to read them back as-is
+static UNQUOTED_SPECIAL: &str = " \t\n\\'\"$`&|;<>()*?[]#~{}!";
+
+/// Characters that need a backslash in front of them inside of double quotes
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_03
+static DOUBLE_QUOTED_SPECIAL: &str = "\\\"$`";
+
+/// Reserved words after which a new command starts
+static COMMAND_STARTERS: [&str; 9] = [
+    "if", "then", "else", "elif", "while", "until", "do", "{", "!",
+];
+
+/// Completes command names, command arguments and file names on the command
+/// line
+pub(crate) struct ShellCompleter {
+    /// The shell variables as of the last prompt, for `$PATH`, `~/...` and
+    /// the argument completers
+    pub variables: HashMap<String, String>,
+
+    /// The names of all shell functions as of the last prompt
+    pub functions: Vec<String>,
+
+    /// Job IDs like `%1` as of the last prompt, each with the command line of
+    /// its job
+    pub jobs: Vec<(String, String)>,
+
+    /// The executables found in `$PATH`, together with the `$PATH` value they
+    /// were found using. Scanning all of `$PATH` on every tab press would be
+    /// slow.
+    path_commands: RefCell<Option<(String, Vec<String>)>>,
+
+    /// Completes the arguments of specific commands, by command name
+    completers: HashMap<String, Box<dyn ArgumentCompleter>>,
+
+    /// Completes arguments for commands that have `bash` completion scripts
+    bash: BashCompleter,
+}
+
+/// The word the cursor is at the end of
+#[derive(Debug, PartialEq)]
+struct CurrentWord {
+    /// Byte offset of where the word starts on the command line
+    start: usize,
+
+    /// The quote character if the word starts with a quote that's still open
+    open_quote: Option<char>,
+
+    /// True if the word is the command name of a simple command
+    is_command: bool,
+
+    /// The words of the command before this one, starting with the command
+    /// name
+    command: Vec<String>,
+}
+
+/// Finds the word ending at the end of `line`. Returns `None` if we shouldn't
+/// complete anything, like inside of a comment.
+fn find_word(line: &str) -> Option<CurrentWord> {
+    let (tokens, end, open_quote) = match to_tokens(line) {
+        Ok(tokens) => (tokens, line.len(), None),
+        Err(error) => {
+            // The word is either the quote and what comes after it, or those
+            // plus a token that the quote is glued to
+            let quote = error.token.text.fragment().chars().next();
+            if quote != Some('\'') && quote != Some('"') {
+                return None;
+            }
+            let quote_index = error.token.text.location_offset();
+            (to_tokens(&line[..quote_index]).ok()?, quote_index, quote)
+        }
+    };
+
+    if tokens.last().is_some_and(|token| token.is_comment) {
+        return None;
+    }
+
+    // Does the last token continue up to the cursor?
+    let mut previous_tokens = &tokens[..];
+    let mut start = end;
+    if let Some((last, before)) = tokens.split_last() {
+        if !last.is_operator() && last.text.location_offset() + last.text.len() == end {
+            start = last.text.location_offset();
+            previous_tokens = before;
+        }
+    }
+
+    return Some(CurrentWord {
+        start,
+        open_quote: if start == end { open_quote } else { None },
+        is_command: is_command_position(previous_tokens),
+        command: current_command(previous_tokens),
+    });
+}
+
+/// True for operators after which a new command starts
+fn is_command_separator(token: &Token) -> bool {
+    return token.is_operator()
+        && [";", "&", "&&", "|", "||", "(", "\n"].contains(token.text.fragment());
+}
+
+/// Returns the words of the last command in `tokens`, without any
+/// assignments or reserved words in front of the command name
+fn current_command(tokens: &[Token]) -> Vec<String> {
+    let command_start = match tokens.iter().rposition(is_command_separator) {
+        Some(separator) => separator + 1,
+        None => 0,
+    };
+
+    return tokens[command_start..]
+        .iter()
+        .skip_while(|token| {
+            let text = token.text.fragment();
+            return !token.is_quoted && (is_assignment(text) || COMMAND_STARTERS.contains(text));
+        })
+        .map(|token| token.text.to_string())
+        .collect();
+}
+
+/// True if a word following `tokens` would be a command name
+fn is_command_position(tokens: &[Token]) -> bool {
+    match tokens.split_last() {
+        None => return true,
+        Some((last, before)) => {
+            if last.is_operator() {
+                return is_command_separator(last);
+            }
+            if last.is_quoted {
+                return false;
+            }
+            if is_assignment(last.text.fragment())
+                || COMMAND_STARTERS.contains(last.text.fragment())
+            {
+                // Only counts if it's in command position itself, so that
+                // `echo if` is not followed by a command
+                return is_command_position(before);
+            }
+            return false;
+        }
+    }
+}
+
+/// True for words like `FOO=bar`
+fn is_assignment(word: &str) -> bool {
+    match word.split_once('=') {
+        Some((name, _)) => return is_name(name),
+        None => return false,
+    }
+}
+
+/// Removes quotes and backslashes from a partially typed word, so that we can
+/// look for files matching it. A quote that's still open is taken to last
+/// until the end of the word.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_07
+fn unquote(word: &str) -> String {
+    let mut unquoted = String::new();
+    let mut quote: Option<char> = None;
+    let mut chars = word.chars();
+    while let Some(character) = chars.next() {
+        match (quote, character) {
+            (None, '\'') | (None, '"') => quote = Some(character),
+            (Some(open), _) if open == character => quote = None,
+            (None, '\\') => unquoted.extend(chars.next()),
+            (Some('"'), '\\') => match chars.next() {
+                Some(escaped) if DOUBLE_QUOTED_SPECIAL.contains(escaped) => unquoted.push(escaped),
+                Some(escaped) => {
+                    unquoted.push('\\');
+                    unquoted.push(escaped);
+                }
+                None => unquoted.push('\\'),
+            },
+            _ => unquoted.push(character),
+        }
+    }
+
+    return unquoted;
+}
+
+/// Quotes `text` so that the tokenizer reads it back as-is. With an
+/// `open_quote`, the text goes after that quote character.
+fn escape(text: &str, open_quote: Option<char>) -> String {
+    let mut escaped = String::new();
+    match open_quote {
+        Some('\'') => {
+            // Single quotes can't be escaped inside of single quotes, so
+            // close the quote, add an escaped quote and open a new one
+            escaped.push('\'');
+            escaped.push_str(&text.replace('\'', "'\\''"));
+        }
+        Some(quote) => {
+            escaped.push(quote);
+            for character in text.chars() {
+                if DOUBLE_QUOTED_SPECIAL.contains(character) {
+                    escaped.push('\\');
+                }
+                escaped.push(character);
+            }
+        }
+        None => {
+            for character in text.chars() {
+                if UNQUOTED_SPECIAL.contains(character) {
+                    escaped.push('\\');
+                }
+                escaped.push(character);
+            }
+        }
+    }
+
+    return escaped;
+}
+
+/// Lists the names in `directory` starting with `prefix`, sorted. The names of
+/// directories end in a slash. Hidden files are only listed if `prefix`
+/// starts with a dot.
+pub(crate) fn list_directory(directory: &Path, prefix: &str) -> Vec<String> {
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut names: Vec<String> = Vec::new();
+    for entry in entries.flatten() {
+        let mut name = entry.file_name().to_string_lossy().to_string();
+        if !name.starts_with(prefix) || (name.starts_with('.') && !prefix.starts_with('.')) {
+            continue;
+        }
+
+        // Follows symlinks, so that links to directories get a slash as well
+        if entry.path().is_dir() {
+            name.push('/');
+        }
+        names.push(name);
+    }
+
+    names.sort();
+    return names;
+}
+
+/// Lists the names of all executable files in the `$PATH` directories, sorted
+/// and without duplicates.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap08.html#tag_08_03
+fn scan_path(path: &str) -> Vec<String> {
+    let mut commands: Vec<String> = Vec::new();
+    for directory in path.split(':').filter(|directory| !directory.is_empty()) {
+        let entries = match fs::read_dir(directory) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let is_executable = match fs::metadata(entry.path()) {
+                Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+                Err(_) => false,
+            };
+            if is_executable {
+                commands.push(entry.file_name().to_string_lossy().to_string());
+            }
+        }
+    }
+
+    commands.sort();
+    commands.dedup();
+    return commands;
+}
+
+impl ShellCompleter {
+    pub fn new() -> Self {
+        let mut completer = ShellCompleter {
+            variables: HashMap::new(),
+            functions: vec![],
+            jobs: vec![],
+            path_commands: RefCell::new(None),
+            completers: HashMap::new(),
+            bash: BashCompleter::new(),
+        };
+        completer.register("man", Box::new(ManCompleter::default()));
+        completer.register("cd", Box::new(CdCompleter {}));
+        completer.register("git", Box::new(GitCompleter::default()));
+        completer.register("ssh", Box::new(SshCompleter {}));
+        completer.register("kill", Box::new(KillCompleter {}));
+        return completer;
+    }
+
+    /// Makes `completer` complete the arguments of `command`, replacing any
+    /// completer it had before
+    pub fn register(&mut self, command: &str, completer: Box<dyn ArgumentCompleter>) {
+        self.completers.insert(command.to_string(), completer);
+    }
+
+    fn variable(&self, name: &str) -> Option<&str> {
+        return self.variables.get(name).map(String::as_str);
+    }
+
+    /// Makes the next command completion scan `$PATH` again, for the `hash`
+    /// builtin
+    pub fn forget_commands(&self) {
+        self.path_commands.replace(None);
+    }
+
+    /// Completes the word ending at `pos` on the command line. Returns where
+    /// the word starts together with the candidates to replace it with.
+    pub fn complete(&self, line: &str, pos: usize) -> (usize, Vec<Pair>) {
+        let current_dir = env::current_dir().unwrap_or_else(|_| PathBuf::from("/"));
+        return self.complete_in(&line[..pos], &current_dir);
+    }
+
+    /// Like `complete()`, but completes relative paths in `current_dir`
+    fn complete_in(&self, line: &str, current_dir: &Path) -> (usize, Vec<Pair>) {
+        let word = match find_word(line) {
+            Some(word) => word,
+            None => return (line.len(), vec![]),
+        };
+        let typed = unquote(&line[word.start..]);
+
+        if word.is_command && !typed.contains('/') {
+            return (word.start, self.complete_command(&typed, word.open_quote));
+        }
+
+        if !word.is_command {
+            if let Some(candidates) = self.complete_argument(line, &word, &typed, current_dir) {
+                return (word.start, to_candidates(candidates, word.open_quote));
+            }
+        }
+
+        return (
+            word.start,
+            self.complete_file(&typed, word.open_quote, current_dir),
+        );
+    }
+
+    /// Completes an argument using the completer registered for the command
+    /// if there is one, or using `bash` completion scripts otherwise. Returns
+    /// unquoted candidates, or `None` if file names should be completed
+    /// instead.
+    ///
+    /// Registered completers know what they are doing, so when they find
+    /// nothing, that's the answer. Otherwise `cd` would complete file names.
+    fn complete_argument(
+        &self,
+        line: &str,
+        word: &CurrentWord,
+        typed: &str,
+        current_dir: &Path,
+    ) -> Option<Vec<Pair>> {
+        let command_name = word.command.first().map(String::as_str).unwrap_or_default();
+        if let Some(completer) = self.completers.get(command_name) {
+            let context = CompletionContext {
+                words: &word.command,
+                word: typed,
+                current_dir,
+                variables: &self.variables,
+                jobs: &self.jobs,
+            };
+            return Some(fuzzy_filter(completer.complete(&context), typed));
+        }
+
+        let mut words = word.command.clone();
+        words.push(line[word.start..].to_string());
+        let completions = self.bash.complete(&words, current_dir)?;
+        if completions.is_empty() {
+            return None;
+        }
+        return Some(
+            completions
+                .into_iter()
+                .map(|completion| Pair {
+                    display: completion.to_string(),
+                    replacement: completion,
+                })
+                .collect(),
+        );
+    }
+
+    /// Completes builtins, functions and executables in `$PATH`
+    fn complete_command(&self, prefix: &str, open_quote: Option<char>) -> Vec<Pair> {
+        let path = self.variable("PATH").unwrap_or_default();
+        let mut path_commands = self.path_commands.borrow_mut();
+        if path_commands.as_ref().map(|(scanned, _)| scanned.as_str()) != Some(path) {
+            *path_commands = Some((path.to_string(), scan_path(path)));
+        }
+
+        let mut names: Vec<&str> = BUILTINS.to_vec();
+        names.extend(self.functions.iter().map(String::as_str));
+        names.extend(path_commands.as_ref().unwrap().1.iter().map(String::as_str));
+        names.retain(|name| name.starts_with(prefix));
+        names.sort_unstable();
+        names.dedup();
+
+        return names
+            .into_iter()
+            .map(|name| Pair {
+                display: name.to_string(),
+                replacement: finish(escape(name, open_quote), open_quote),
+            })
+            .collect();
+    }
+
+    /// Completes file names, with `~/` meaning the home directory
+    fn complete_file(
+        &self,
+        typed: &str,
+        open_quote: Option<char>,
+        current_dir: &Path,
+    ) -> Vec<Pair> {
+        let (directory, prefix) = match typed.rfind('/') {
+            Some(slash) => typed.split_at(slash + 1),
+            None => ("", typed),
+        };
+
+        let mut lookup = current_dir.join(directory);
+        let mut tilde_prefix = "";
+        if open_quote.is_none() && directory.starts_with("~/") {
+            if let Some(home) = self.variable("HOME") {
+                lookup = Path::new(home).join(&directory[2..]);
+                tilde_prefix = "~/";
+            }
+        }
+
+        return list_directory(&lookup, prefix)
+            .into_iter()
+            .map(|name| {
+                let path = format!("{}{}", &directory[tilde_prefix.len()..], name);
+                let mut replacement = format!("{}{}", tilde_prefix, escape(&path, open_quote));
+                if !name.ends_with('/') {
+                    replacement = finish(replacement, open_quote);
+                }
+                return Pair {
+                    display: name,
+                    replacement,
+                };
+            })
+            .collect();
+    }
+}
+
+impl Shell {
+    /// Tells the completer about the current shell state, call before
+    /// prompting
+    pub(crate) fn update_completer(&mut self) {
+        let variables = self
+            .variables
+            .iter()
+            .filter_map(|(name, variable)| Some((name.to_string(), variable.value.clone()?)))
+            .collect();
+        let functions = self.functions.keys().cloned().collect();
+        let jobs = self.jobs.ids();
+        if let Some(helper) = self.readline.helper_mut() {
+            helper.completer.variables = variables;
+            helper.completer.functions = functions;
+            helper.completer.jobs = jobs;
+        }
+    }
+
+    /// Forgets which commands are in `$PATH`, useful after installing
+    /// something new
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/hash.html
+    pub(crate) fn hash(&mut self) -> String {
+        if let Some(helper) = self.readline.helper() {
+            helper.completer.forget_commands();
+        }
+        return "".to_string();
+    }
+}
+
+/// Quotes unquoted argument candidates. Candidates ending in `/` or `=`, like
+/// directories or `--option=`, are probably not done yet.
+///
+/// A leading `~/` is left as-is, so that it still means the home directory.
+fn to_candidates(candidates: Vec<Pair>, open_quote: Option<char>) -> Vec<Pair> {
+    return candidates
+        .into_iter()
+        .map(|candidate| {
+            let value = candidate.replacement;
+            let (tilde_prefix, path) = match value.strip_prefix("~/") {
+                Some(path) if open_quote.is_none() => ("~/", path),
+                _ => ("", value.as_str()),
+            };
+
+            let mut replacement = format!("{}{}", tilde_prefix, escape(path, open_quote));
+            if !value.ends_with('/') && !value.ends_with('=') {
+                replacement = finish(replacement, open_quote);
+            }
+            return Pair {
+                display: candidate.display,
+                replacement,
+            };
+        })
+        .collect();
+}
+
+/// Ends a fully completed word by closing any open quote and adding a space,
+/// so that the user can go on typing the next word
+fn finish(mut replacement: String, open_quote: Option<char>) -> String {
+    replacement.extend(open_quote);
+    replacement.push(' ');
+    return replacement;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn word(start: usize, open_quote: Option<char>, command: &[&str]) -> Option<CurrentWord> {
+        return Some(CurrentWord {
+            start,
+            open_quote,
+            is_command: command.is_empty(),
+            command: command.iter().map(|word| word.to_string()).collect(),
+        });
+    }
+
+    #[test]
+    fn test_find_word() {
+        assert_eq!(find_word(""), word(0, None, &[]));
+        assert_eq!(find_word("ec"), word(0, None, &[]));
+        assert_eq!(find_word("echo "), word(5, None, &["echo"]));
+        assert_eq!(find_word("echo a|w"), word(7, None, &[]));
+        assert_eq!(find_word("echo a && "), word(10, None, &[]));
+        assert_eq!(find_word("FOO=1 l"), word(6, None, &[]));
+        assert_eq!(find_word("if tr"), word(3, None, &[]));
+        assert_eq!(find_word("echo if "), word(8, None, &["echo", "if"]));
+        assert_eq!(find_word("ls > fi"), word(5, None, &["ls", ">"]));
+        assert_eq!(find_word("ls My\\ Do"), word(3, None, &["ls"]));
+        assert_eq!(find_word("ls \"My Do"), word(3, Some('"'), &["ls"]));
+        assert_eq!(find_word("ls 'My Do"), word(3, Some('\''), &["ls"]));
+        assert_eq!(find_word("ls dir/'My Do"), word(3, None, &["ls"]));
+        assert_eq!(
+            find_word("cd /; FOO=1 git -C x "),
+            word(21, None, &["git", "-C", "x"])
+        );
+        assert_eq!(find_word("echo # comment"), None);
+        assert_eq!(find_word("echo $(l"), None);
+    }
+
+    #[test]
+    fn test_unquote() {
+        assert_eq!(unquote(r"My\ Do"), "My Do");
+        assert_eq!(unquote("\"My Do"), "My Do");
+        assert_eq!(unquote(r#""a\"b\c""#), r#"a"b\c"#);
+        assert_eq!(unquote(r"'a\b'c"), r"a\bc");
+        assert_eq!(unquote("dir/'My Do"), "dir/My Do");
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("My Documents/", None), r"My\ Documents/");
+        assert_eq!(escape("a&b(1)*", None), r"a\&b\(1\)\*");
+        assert_eq!(escape("My $5", Some('"')), r#""My \$5"#);
+        assert_eq!(escape("it's", Some('\'')), r"'it'\''s");
+
+        // Whatever we escape must come back the same from the tokenizer
+        let name = "a b'c\"d$e`f\\g&h|i;j<k>l(m)n*o?p[q]r#s~t{u}v!w";
+        for open_quote in [None, Some('"'), Some('\'')] {
+            let escaped = finish(escape(name, open_quote), open_quote);
+            let tokens = to_tokens(&escaped).unwrap();
+            assert_eq!(tokens.len(), 1, "{}", escaped);
+            assert_eq!(unquote(tokens[0].text.fragment()), name);
+        }
+    }
+
+    #[test]
+    fn test_complete() {
+        let directory = TestDir::new("complete");
+        fs::create_dir_all(directory.join("My Documents")).unwrap();
+        fs::create_dir_all(directory.join("bin")).unwrap();
+        fs::write(directory.join("My File.txt"), "").unwrap();
+        fs::write(directory.join(".hidden"), "").unwrap();
+        fs::write(directory.join("bin/vpixsh-test-command"), "").unwrap();
+        fs::set_permissions(
+            directory.join("bin/vpixsh-test-command"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::write(directory.join("bin/vpixsh-test-data"), "").unwrap();
+        fs::create_dir_all(directory.join("bash/completions")).unwrap();
+        fs::write(
+            directory.join("bash/completions/vpixsh-test-command"),
+            "_f() { COMPREPLY=($(compgen -W '--help --version --color=' -- \"$2\")); }\n\
+             complete -F _f vpixsh-test-command\n",
+        )
+        .unwrap();
+
+        let mut completer = ShellCompleter::new();
+        completer.variables.insert(
+            "PATH".to_string(),
+            directory.join("bin").display().to_string(),
+        );
+        completer
+            .variables
+            .insert("HOME".to_string(), directory.display().to_string());
+        completer.functions = vec!["vpixsh-test-function".to_string()];
+        completer.bash = BashCompleter::with_scripts(&directory.join("bash"));
+        // Returns the completed command lines
+        let complete = |line: &str| -> Vec<String> {
+            let (start, candidates) = completer.complete_in(line, &directory);
+            return candidates
+                .into_iter()
+                .map(|pair| format!("{}{}", &line[..start], pair.replacement))
+                .collect();
+        };
+
+        assert_eq!(
+            complete("vpixsh-test-"),
+            vec!["vpixsh-test-command ", "vpixsh-test-function "]
+        );
+        assert_eq!(complete("expo"), vec!["export "]);
+        assert_eq!(complete("echo a | expo"), vec!["echo a | export "]);
+
+        assert_eq!(
+            complete("ls My"),
+            vec![r"ls My\ Documents/", r"ls My\ File.txt "]
+        );
+        assert_eq!(complete("ls \"My F"), vec!["ls \"My File.txt\" "]);
+        assert_eq!(complete("ls 'My D"), vec!["ls 'My Documents/"]);
+        assert_eq!(complete("ls My\\ F"), vec![r"ls My\ File.txt "]);
+        assert_eq!(complete("ls ~/My\\ F"), vec![r"ls ~/My\ File.txt "]);
+        assert_eq!(complete("ls .h"), vec!["ls .hidden "]);
+        assert_eq!(complete("ls nothing"), Vec::<String>::new());
+
+        // Using the bash completion script, with file names as a fallback
+        assert_eq!(
+            complete("vpixsh-test-command --v"),
+            vec!["vpixsh-test-command --version "]
+        );
+        assert_eq!(
+            complete("vpixsh-test-command --c"),
+            vec!["vpixsh-test-command --color="]
+        );
+        assert_eq!(
+            complete("vpixsh-test-command My\\ F"),
+            vec![r"vpixsh-test-command My\ File.txt "]
+        );
+        assert_eq!(
+            complete("./bin/vpixsh-test-d"),
+            vec!["./bin/vpixsh-test-data "]
+        );
+
+        // Registered completers have the final say, no file names for cd
+        assert_eq!(complete("cd My"), vec![r"cd My\ Documents/"]);
+        assert_eq!(complete("cd My\\ F"), Vec::<String>::new());
+    }
+}
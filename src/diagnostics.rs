@@ -0,0 +1,79 @@
+/// Renders an error message the way rustc does, by echoing the line of the
+/// command line that the error is on and marking the offending part of it with
+/// `^^^`, followed by any help:
+///
+/// ```text
+/// Unterminated double quote
+///   echo "hej
+///        ^ Did you mean to close the quote opened here?
+/// ```
+///
+/// `offset` and `length` are in bytes. Errors pointing at the end of the
+/// command line have zero length, those get a single marker after the line.
+pub(crate) fn render(
+    commandline: &str,
+    offset: usize,
+    length: usize,
+    message: &str,
+    help: Option<&str>,
+) -> String {
+    let line_start = commandline[..offset]
+        .rfind('\n')
+        .map_or(0, |newline| newline + 1);
+    let line_end = commandline[offset..]
+        .find('\n')
+        .map_or(commandline.len(), |newline| offset + newline);
+
+    // Keep any tabs so that the markers line up with the echoed line
+    let indentation: String = commandline[line_start..offset]
+        .chars()
+        .map(|character| if character == '\t' { '\t' } else { ' ' })
+        .collect();
+
+    // Multi line spans get their first line marked
+    let span_end = (offset + length).min(line_end);
+    let marker_count = commandline[offset..span_end].chars().count().max(1);
+
+    let mut rendered = format!(
+        "{}\n  {}\n  {}{}",
+        message,
+        &commandline[line_start..line_end],
+        indentation,
+        "^".repeat(marker_count)
+    );
+    if let Some(help) = help {
+        rendered.push(' ');
+        rendered.push_str(help);
+    }
+
+    return rendered;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            render("echo \"hej", 5, 1, "Unterminated", Some("Close it")),
+            "Unterminated\n  echo \"hej\n       ^ Close it"
+        );
+
+        // Pointing at the end of the command line
+        assert_eq!(
+            render("echo |", 6, 0, "Expected a command here", None),
+            "Expected a command here\n  echo |\n        ^"
+        );
+
+        // Only the line with the error is shown, and markers count characters
+        assert_eq!(
+            render("echo a\n\tåäö x\necho b", 8, 6, "Bad", None),
+            "Bad\n  \tåäö x\n  \t^^^"
+        );
+        assert_eq!(
+            render("if true\nthen", 0, 8, "Unclosed if", None),
+            "Unclosed if\n  if true\n  ^^^^^^^"
+        );
+    }
+}
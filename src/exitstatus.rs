@@ -0,0 +1,132 @@
+use nix::sys::signal::Signal;
+
+/// The result of running a command
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ExitStatus {
+    /// The exit code as seen by `$?`, zero means success
+    pub code: i32,
+
+    /// Shown in the prompt if the command failed, empty on success
+    pub description: String,
+
+    /// Set if the command was killed by a signal
+    pub signal: Option<i32>,
+}
+
+/// Like `SIGSEGV` for 11, or `SIG99` for signals we don't know about
+pub(crate) fn signal_name(signal: i32) -> String {
+    match Signal::try_from(signal) {
+        Ok(signal) => return signal.to_string(),
+        Err(_) => return format!("SIG{}", signal),
+    }
+}
+
+impl ExitStatus {
+    pub fn success() -> Self {
+        return ExitStatus {
+            code: 0,
+            description: "".to_string(),
+            signal: None,
+        };
+    }
+
+    pub fn failure(code: i32, description: &str) -> Self {
+        return ExitStatus {
+            code,
+            description: description.to_string(),
+            signal: None,
+        };
+    }
+
+    /// A status for a command that exited with the given exit code
+    pub fn from_code(code: i32) -> Self {
+        if code == 0 {
+            return ExitStatus::success();
+        }
+        return ExitStatus::failure(code, &code.to_string());
+    }
+
+    /// A status for a command that was killed by the given signal. As in
+    /// other shells, `$?` is 128 plus the signal number.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_08_02
+    pub fn from_signal(signal: i32) -> Self {
+        return ExitStatus {
+            code: 128 + signal,
+            description: signal_name(signal),
+            signal: Some(signal),
+        };
+    }
+
+    /// Like `from_signal()`, but for a command that dumped core on its way
+    /// out, described like `SIGSEGV+core`
+    pub fn from_core_dump(signal: i32) -> Self {
+        let mut status = ExitStatus::from_signal(signal);
+        status.description.push_str("+core");
+        return status;
+    }
+
+    pub fn is_success(&self) -> bool {
+        return self.code == 0;
+    }
+}
+
+/// Describes the exit statuses of all commands in a pipeline, like
+/// `0|1|SIGSEGV`, or returns the empty string if the pipeline went fine.
+///
+/// Commands other than the last one dying from `SIGPIPE` is fine. That's how
+/// writers like `yes` in `yes | head -1` usually end.
+pub(crate) fn describe_pipestatus(statuses: &[ExitStatus]) -> String {
+    let sigpipe = Some(Signal::SIGPIPE as i32);
+    let last = statuses.len() - 1;
+    let is_fine = |(index, status): (usize, &ExitStatus)| {
+        return status.is_success() || (index < last && status.signal == sigpipe);
+    };
+    if statuses.iter().enumerate().all(is_fine) {
+        return "".to_string();
+    }
+
+    let descriptions: Vec<String> = statuses
+        .iter()
+        .map(|status| {
+            if status.is_success() {
+                return "0".to_string();
+            }
+            return status.description.to_string();
+        })
+        .collect();
+    return descriptions.join("|");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_signal() {
+        let status = ExitStatus::from_signal(11);
+        assert_eq!(status.code, 139);
+        assert_eq!(status.description, "SIGSEGV");
+
+        assert_eq!(ExitStatus::from_core_dump(6).description, "SIGABRT+core");
+        assert_eq!(ExitStatus::from_signal(99).description, "SIG99");
+    }
+
+    #[test]
+    fn test_describe_pipestatus() {
+        let ok = ExitStatus::success();
+        let sigpipe = ExitStatus::from_signal(Signal::SIGPIPE as i32);
+        let failed = ExitStatus::from_code(1);
+
+        assert_eq!(describe_pipestatus(&[ok.clone(), ok.clone()]), "");
+        assert_eq!(describe_pipestatus(&[sigpipe.clone(), ok.clone()]), "");
+        assert_eq!(
+            describe_pipestatus(&[sigpipe.clone(), failed.clone()]),
+            "SIGPIPE|1"
+        );
+        assert_eq!(describe_pipestatus(&[failed, ok.clone()]), "1|0");
+
+        // The last command has nobody to blame but itself
+        assert_eq!(describe_pipestatus(&[ok, sigpipe]), "0|SIGPIPE");
+    }
+}
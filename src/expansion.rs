@@ -0,0 +1,1459 @@
+use std::env;
+use std::fmt::Display;
+
+use nix::unistd::{getuid, User};
+
+use crate::arithmetic::evaluate;
+use crate::exitstatus::ExitStatus;
+use crate::globbing::{glob, Component};
+use crate::interpreter::Executor;
+use crate::parser::parse;
+use crate::pattern::Pattern;
+use crate::tokenizer::{arithmetic_length, substitution_length};
+
+/// Characters that a backslash escapes inside of double quotes. Before any
+/// other character, the backslash is kept as-is.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_03
+static DOUBLE_QUOTE_ESCAPABLE: [char; 5] = ['$', '`', '"', '\\', '\n'];
+
+/// Parameters with single-character names that aren't variable names
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_02
+static SPECIAL_PARAMETERS: [char; 8] = ['@', '*', '#', '?', '-', '$', '!', '0'];
+
+/// Characters that a backslash escapes inside of backticks. Before any other
+/// character, the backslash is kept as-is.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+static BACKTICK_ESCAPABLE: [char; 3] = ['$', '`', '\\'];
+
+/// `IFS` when it's unset
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_03
+static DEFAULT_IFS: &str = " \t\n";
+
+/// Characters that need escaping to be matched literally in a `Pattern`
+static PATTERN_SPECIAL: [char; 7] = ['\\', '*', '?', '[', ']', '!', '-'];
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ExpansionError {
+    pub message: String,
+}
+
+impl Display for ExpansionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+fn error<T>(message: &str) -> Result<T, ExpansionError> {
+    return Err(ExpansionError {
+        message: message.to_string(),
+    });
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Origin {
+    /// Unquoted text from the command line
+    Literal,
+
+    /// Unquoted result of an expansion
+    Expanded,
+
+    /// Quoted text, either quoted on the command line or the result of an
+    /// expansion inside of double quotes
+    Quoted,
+
+    /// Goes between the positional parameters in `$@`, so that they end up
+    /// in separate fields. The text is a space, for when the word is used
+    /// without field splitting.
+    FieldBreak,
+}
+
+/// Part of an expanded word, with quotes removed
+#[derive(Debug, Clone, PartialEq)]
+struct Segment {
+    text: String,
+    origin: Origin,
+}
+
+fn is_name_start(character: char) -> bool {
+    return character == '_' || character.is_ascii_alphabetic();
+}
+
+fn is_name_character(character: char) -> bool {
+    return character == '_' || character.is_ascii_alphanumeric();
+}
+
+/// True if `name` can be used as a variable name
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap03.html#tag_03_235
+pub(crate) fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(first) if is_name_start(first) => return chars.all(is_name_character),
+        _ => return false,
+    }
+}
+
+/// Turns the segments into a pattern where quoted characters are matched
+/// literally.
+fn to_pattern(segments: &[Segment]) -> Pattern {
+    let mut pattern = String::new();
+    for segment in segments {
+        if segment.origin != Origin::Quoted {
+            pattern.push_str(&segment.text);
+            continue;
+        }
+
+        for character in segment.text.chars() {
+            if PATTERN_SPECIAL.contains(&character) {
+                pattern.push('\\');
+            }
+            pattern.push(character);
+        }
+    }
+
+    return Pattern::new(&pattern);
+}
+
+/// Adds text to the last segment if it has the same origin, or as a new
+/// segment otherwise
+fn append(segments: &mut Vec<Segment>, text: &str, origin: Origin) {
+    if let Some(last) = segments.last_mut() {
+        if last.origin == origin && origin != Origin::FieldBreak {
+            last.text.push_str(text);
+            return;
+        }
+    }
+
+    segments.push(Segment {
+        text: text.to_string(),
+        origin,
+    });
+}
+
+/// Quoted text makes a field even if it's empty, as in `""`. Unquoted
+/// expansions that expand to nothing don't.
+fn is_field(segments: &[Segment]) -> bool {
+    return segments
+        .iter()
+        .any(|segment| segment.origin == Origin::Quoted || !segment.text.is_empty());
+}
+
+/// Splits unquoted expansion results into fields at the characters in `ifs`.
+///
+/// `IFS` white space around the results is ignored, and any run of it
+/// separates fields. Other `IFS` characters separate fields one by one, so
+/// that `a::b` becomes three fields with `IFS=:`.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_05
+fn split_fields(segments: Vec<Segment>, ifs: &str) -> Vec<Vec<Segment>> {
+    let mut fields: Vec<Vec<Segment>> = Vec::new();
+    let mut field: Vec<Segment> = Vec::new();
+
+    // True after IFS white space, which ends the current field if anything
+    // but more IFS characters follow
+    let mut after_whitespace = false;
+
+    for segment in segments {
+        match segment.origin {
+            Origin::FieldBreak => {
+                if is_field(&field) {
+                    fields.push(std::mem::take(&mut field));
+                }
+                after_whitespace = false;
+            }
+            Origin::Expanded => {
+                for character in segment.text.chars() {
+                    if ifs.contains(character) {
+                        if DEFAULT_IFS.contains(character) {
+                            after_whitespace = true;
+                        } else {
+                            // Ends the field even if it's empty
+                            fields.push(std::mem::take(&mut field));
+                            after_whitespace = false;
+                        }
+                        continue;
+                    }
+
+                    if after_whitespace && is_field(&field) {
+                        fields.push(std::mem::take(&mut field));
+                    }
+                    after_whitespace = false;
+                    append(
+                        &mut field,
+                        character.encode_utf8(&mut [0; 4]),
+                        Origin::Expanded,
+                    );
+                }
+            }
+            _ => {
+                if after_whitespace && is_field(&field) {
+                    fields.push(std::mem::take(&mut field));
+                }
+                after_whitespace = false;
+                append(&mut field, &segment.text, segment.origin);
+            }
+        }
+    }
+
+    if is_field(&field) {
+        fields.push(field);
+    }
+    return fields;
+}
+
+fn to_string(segments: &[Segment]) -> String {
+    return segments
+        .iter()
+        .map(|segment| segment.text.as_str())
+        .collect();
+}
+
+/// True if there are unquoted pattern characters in the segments
+fn has_pattern_characters(segments: &[Segment]) -> bool {
+    return segments
+        .iter()
+        .any(|segment| segment.origin != Origin::Quoted && segment.text.contains(['*', '?', '[']));
+}
+
+/// Splits the segments at each `/`, quoted or not
+fn split_path(segments: &[Segment]) -> Vec<Vec<Segment>> {
+    let mut components: Vec<Vec<Segment>> = vec![vec![]];
+    for segment in segments {
+        for (index, part) in segment.text.split('/').enumerate() {
+            if index > 0 {
+                components.push(vec![]);
+            }
+            components.last_mut().unwrap().push(Segment {
+                text: part.to_string(),
+                origin: segment.origin,
+            });
+        }
+    }
+    return components;
+}
+
+/// Replaces a word containing unquoted pattern characters with the paths it
+/// matches.
+///
+/// If nothing matches, POSIX says to keep the word as-is. Setting
+/// `VPIXSH_NOMATCH_ERROR` makes that an error instead, like in `fish` and
+/// `zsh`.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_06
+fn expand_pathname(
+    executor: &dyn Executor,
+    segments: &[Segment],
+) -> Result<Vec<String>, ExpansionError> {
+    let word = to_string(segments);
+    if !has_pattern_characters(segments) {
+        return Ok(vec![word]);
+    }
+
+    let mut components: Vec<Component> = split_path(segments)
+        .iter()
+        .map(|component| Component {
+            pattern: to_pattern(component),
+            text: to_string(component),
+            is_pattern: has_pattern_characters(component),
+        })
+        .collect();
+    let absolute = components.len() > 1 && components[0].text.is_empty();
+    if absolute {
+        components.remove(0);
+    }
+
+    let paths = glob(absolute, &components);
+    if !paths.is_empty() {
+        return Ok(paths);
+    }
+
+    // FIXME: Get this setting from some config file
+    if executor.get_variable("VPIXSH_NOMATCH_ERROR").is_some() {
+        return error(&format!("No matches for: {}", word));
+    }
+    return Ok(vec![word]);
+}
+
+/// The operators that can follow the parameter name in `${name...}`
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Modifier {
+    /// `-`, or `:-` if the bool is true
+    UseDefault(bool),
+
+    /// `=` or `:=`
+    AssignDefault(bool),
+
+    /// `?` or `:?`
+    ErrorIfUnset(bool),
+
+    /// `+` or `:+`
+    UseAlternative(bool),
+
+    /// `%` or `%%` if the bool is true
+    RemoveSuffix(bool),
+
+    /// `#` or `##` if the bool is true
+    RemovePrefix(bool),
+}
+
+struct Expander<'a> {
+    executor: &'a mut dyn Executor,
+
+    chars: Vec<char>,
+
+    /// Index into `chars`
+    position: usize,
+
+    /// Whether we're currently between double quotes
+    in_double_quotes: bool,
+
+    /// If true, everything is considered quoted. Used for expanding the words
+    /// in `"${X:-word}"`.
+    all_quoted: bool,
+
+    /// True for the values in `NAME=value` assignments, where tilde expansion
+    /// also happens after each unquoted `:`
+    is_assignment: bool,
+
+    result: Vec<Segment>,
+
+    /// Exit status of the last command substitution, if there has been one
+    substitution_status: Option<ExitStatus>,
+
+    /// Set if there was a `"$@"` with no positional parameters, which should
+    /// expand to no field at all rather than to an empty one
+    quoted_at_was_empty: bool,
+}
+
+impl<'a> Expander<'a> {
+    fn new(executor: &'a mut dyn Executor, word: &str, all_quoted: bool) -> Self {
+        return Expander {
+            executor,
+            chars: word.chars().collect(),
+            position: 0,
+            in_double_quotes: false,
+            all_quoted,
+            is_assignment: false,
+            result: Vec::new(),
+            substitution_status: None,
+            quoted_at_was_empty: false,
+        };
+    }
+
+    fn is_quoted(&self) -> bool {
+        return self.all_quoted || self.in_double_quotes;
+    }
+
+    fn push(&mut self, text: &str, origin: Origin) {
+        append(&mut self.result, text, origin);
+    }
+
+    fn push_char(&mut self, character: char, origin: Origin) {
+        self.push(character.encode_utf8(&mut [0; 4]), origin);
+    }
+
+    /// Push some text from the command line
+    fn push_literal(&mut self, character: char) {
+        if self.is_quoted() {
+            self.push_char(character, Origin::Quoted);
+        } else {
+            self.push_char(character, Origin::Literal);
+        }
+    }
+
+    /// Push the result of some expansion
+    fn push_expanded(&mut self, text: &str) {
+        if self.is_quoted() {
+            self.push(text, Origin::Quoted);
+        } else {
+            self.push(text, Origin::Expanded);
+        }
+    }
+
+    /// Push the value of a parameter. `$@` and unquoted `$*` become one field
+    /// per positional parameter, while `"$*"` joins them using the first
+    /// character of `IFS`.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_02
+    fn push_parameter(&mut self, name: &str) {
+        if name == "*" && self.is_quoted() {
+            let separator = match self.executor.get_variable("IFS") {
+                Some(ifs) => ifs.chars().next().map(String::from).unwrap_or_default(),
+                None => " ".to_string(),
+            };
+            let value = self.executor.get_positional_parameters().join(&separator);
+            self.push_expanded(&value);
+            return;
+        }
+
+        if name == "@" || name == "*" {
+            let parameters = self.executor.get_positional_parameters();
+            if parameters.is_empty() && self.is_quoted() {
+                self.quoted_at_was_empty = true;
+            }
+            for (index, parameter) in parameters.iter().enumerate() {
+                if index > 0 {
+                    self.push(" ", Origin::FieldBreak);
+                }
+                self.push_expanded(parameter);
+            }
+            return;
+        }
+
+        let value = self.executor.get_variable(name).unwrap_or_default();
+        self.push_expanded(&value);
+    }
+
+    fn expand(&mut self) -> Result<Vec<Segment>, ExpansionError> {
+        while self.position < self.chars.len() {
+            let character = self.chars[self.position];
+            self.position += 1;
+
+            if character == '~' && self.at_tilde_prefix() && self.expand_tilde() {
+                continue;
+            }
+
+            match character {
+                '\\' => self.expand_backslash(),
+                '\'' if !self.is_quoted() => self.expand_single_quote(),
+                '"' => {
+                    self.in_double_quotes = !self.in_double_quotes;
+
+                    // Mark that there were quotes here, even if they are empty
+                    self.push("", Origin::Quoted);
+                }
+                '$' => self.expand_dollar()?,
+                '`' => self.expand_backticks()?,
+                _ => self.push_literal(character),
+            }
+        }
+
+        return Ok(std::mem::take(&mut self.result));
+    }
+
+    /// True if the `~` we just passed is unquoted and first in the word, or in
+    /// an assignment, first after a `:`
+    fn at_tilde_prefix(&self) -> bool {
+        if self.is_quoted() {
+            return false;
+        }
+        if self.position == 1 {
+            return true;
+        }
+        if !self.is_assignment || self.chars[self.position - 2] != ':' {
+            return false;
+        }
+
+        // Make sure the : wasn't quoted
+        return match self.result.last() {
+            Some(segment) => segment.origin == Origin::Literal && segment.text.ends_with(':'),
+            None => false,
+        };
+    }
+
+    /// The directory a tilde prefix stands for, or `None` if we can't tell
+    fn tilde_directory(&self, login: &str) -> Option<String> {
+        let directory = match login {
+            "" => {
+                if let Some(home) = self.executor.get_variable("HOME") {
+                    return Some(home);
+                }
+                User::from_uid(getuid()).ok()??.dir
+            }
+            "+" => env::current_dir().ok()?,
+            "-" => return self.executor.get_oldpwd(),
+            _ => User::from_name(login).ok()??.dir,
+        };
+
+        // FIXME: Support non-UTF-8 directory names
+        return directory.into_os_string().into_string().ok();
+    }
+
+    /// Called with `position` right after the `~`. Returns false if this
+    /// turned out not to be a tilde prefix, and the `~` should be taken
+    /// literally.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_01
+    fn expand_tilde(&mut self) -> bool {
+        let mut end = self.position;
+        while end < self.chars.len() {
+            let character = self.chars[end];
+            if character == '/' || (self.is_assignment && character == ':') {
+                break;
+            }
+            if ['\'', '"', '\\', '$', '`'].contains(&character) {
+                // "If none of the characters in the tilde-prefix are quoted"
+                return false;
+            }
+            end += 1;
+        }
+
+        let login: String = self.chars[self.position..end].iter().collect();
+        match self.tilde_directory(&login) {
+            Some(directory) => {
+                // The result is never split or globbed
+                self.push(&directory, Origin::Quoted);
+                self.position = end;
+                return true;
+            }
+            None => return false,
+        }
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_01
+    fn expand_backslash(&mut self) {
+        let escaped = self.chars.get(self.position).copied();
+        if escaped.is_none() {
+            // Backslash last, just keep it
+            self.push_literal('\\');
+            return;
+        }
+        let escaped = escaped.unwrap();
+
+        if self.is_quoted() && !DOUBLE_QUOTE_ESCAPABLE.contains(&escaped) {
+            // Not an escape, the backslash stays
+            self.push_char('\\', Origin::Quoted);
+            return;
+        }
+
+        self.position += 1;
+        if escaped != '\n' {
+            self.push_char(escaped, Origin::Quoted);
+        }
+        // else: Backslash-newline is a line continuation, drop both
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_02
+    fn expand_single_quote(&mut self) {
+        let mut quoted = String::new();
+        while self.position < self.chars.len() {
+            let character = self.chars[self.position];
+            self.position += 1;
+
+            if character == '\'' {
+                break;
+            }
+            quoted.push(character);
+        }
+
+        self.push(&quoted, Origin::Quoted);
+    }
+
+    /// Returns the index of the last character of the `${...}`, `$(...)` or
+    /// `` `...` `` construct starting at `start`
+    fn find_end(&self, start: usize) -> Option<usize> {
+        let rest: String = self.chars[start..].iter().collect();
+        let length = substitution_length(&rest)?;
+        return Some(start + rest[..length].chars().count() - 1);
+    }
+
+    /// Like `find_end()`, but for `$((...))`. Returns `None` if there is no
+    /// arithmetic expansion at `start`.
+    fn find_arithmetic_end(&self, start: usize) -> Option<usize> {
+        let rest: String = self.chars[start..].iter().collect();
+        let length = arithmetic_length(&rest)?;
+        return Some(start + rest[..length].chars().count() - 1);
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_04
+    ///
+    /// Called with `position` at the first `(`, `closing_index` is the index
+    /// of the last `)`.
+    fn expand_arithmetic(&mut self, closing_index: usize) -> Result<(), ExpansionError> {
+        let expression: String = self.chars[(self.position - 1)..=closing_index]
+            .iter()
+            .collect();
+        let contents: String = self.chars[(self.position + 2)..(closing_index - 1)]
+            .iter()
+            .collect();
+        self.position = closing_index + 1;
+
+        // "The expression shall be treated as if it were in double-quotes"
+        let mut expander = Expander::new(self.executor, &contents, true);
+        let segments = expander.expand()?;
+        if expander.substitution_status.is_some() {
+            self.substitution_status = expander.substitution_status;
+        }
+
+        match evaluate(self.executor, &to_string(&segments)) {
+            Ok(result) => self.push_expanded(&result.to_string()),
+            Err(message) => return error(&format!("{}: {}", message, expression)),
+        }
+        return Ok(());
+    }
+
+    /// Called after consuming a `$`
+    fn expand_dollar(&mut self) -> Result<(), ExpansionError> {
+        let next = self.chars.get(self.position).copied();
+        match next {
+            Some('{') => return self.expand_braced_parameter(),
+            Some('(') => {
+                if let Some(closing_index) = self.find_arithmetic_end(self.position - 1) {
+                    return self.expand_arithmetic(closing_index);
+                }
+                return self.expand_command_substitution();
+            }
+            Some(character) if is_name_start(character) => {
+                let mut name = String::new();
+                while self.position < self.chars.len()
+                    && is_name_character(self.chars[self.position])
+                {
+                    name.push(self.chars[self.position]);
+                    self.position += 1;
+                }
+
+                self.push_parameter(&name);
+            }
+            Some(character)
+                if character.is_ascii_digit() || SPECIAL_PARAMETERS.contains(&character) =>
+            {
+                self.position += 1;
+                self.push_parameter(&character.to_string());
+            }
+            _ => {
+                // Just a dollar sign
+                self.push_literal('$');
+            }
+        }
+
+        return Ok(());
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+    ///
+    /// Called with `position` at the `(`.
+    fn expand_command_substitution(&mut self) -> Result<(), ExpansionError> {
+        let dollar_index = self.position - 1;
+        let closing_index = self.find_end(dollar_index);
+        if closing_index.is_none() {
+            return error("Unterminated $(");
+        }
+        let closing_index = closing_index.unwrap();
+        let command: String = self.chars[(self.position + 1)..closing_index]
+            .iter()
+            .collect();
+        self.position = closing_index + 1;
+
+        return self.substitute(&command);
+    }
+
+    /// Called after consuming the opening backtick.
+    ///
+    /// Inside of backticks, backslashes only escape `$`, `` ` `` and `\`, plus
+    /// `"` when the backticks are inside of double quotes.
+    fn expand_backticks(&mut self) -> Result<(), ExpansionError> {
+        let closing_index = self.find_end(self.position - 1);
+        if closing_index.is_none() {
+            return error("Unterminated `");
+        }
+        let closing_index = closing_index.unwrap();
+
+        let mut command = String::new();
+        while self.position < closing_index {
+            let character = self.chars[self.position];
+            self.position += 1;
+
+            if character == '\\' && self.position < closing_index {
+                let escaped = self.chars[self.position];
+                if BACKTICK_ESCAPABLE.contains(&escaped)
+                    || (escaped == '"' && self.in_double_quotes)
+                {
+                    command.push(escaped);
+                    self.position += 1;
+                    continue;
+                }
+            }
+            command.push(character);
+        }
+        self.position = closing_index + 1;
+
+        return self.substitute(&command);
+    }
+
+    /// Runs the command and pushes its output, minus any trailing newlines
+    fn substitute(&mut self, command: &str) -> Result<(), ExpansionError> {
+        let parsed = parse(command);
+        if let Err(parse_error) = parsed {
+            return error(&format!("Command substitution: {}", parse_error));
+        }
+
+        let (output, status) = self.executor.capture(&parsed.unwrap().program);
+
+        // "$?" should reflect the substitution until the next command is done
+        self.executor.set_exit_status(&status);
+        self.substitution_status = Some(status);
+
+        self.push_expanded(output.trim_end_matches('\n'));
+        return Ok(());
+    }
+
+    /// Parses a parameter name at the start of `chars`, returning the name
+    /// and the number of chars it occupied.
+    fn parse_parameter_name(chars: &[char]) -> Option<(String, usize)> {
+        let first = *chars.first()?;
+
+        if is_name_start(first) {
+            let name: String = chars
+                .iter()
+                .take_while(|character| is_name_character(**character))
+                .collect();
+            let length = name.len();
+            return Some((name, length));
+        }
+
+        if first.is_ascii_digit() {
+            let name: String = chars
+                .iter()
+                .take_while(|character| character.is_ascii_digit())
+                .collect();
+            let length = name.len();
+            return Some((name, length));
+        }
+
+        if SPECIAL_PARAMETERS.contains(&first) {
+            return Some((first.to_string(), 1));
+        }
+
+        return None;
+    }
+
+    fn parse_modifier(chars: &[char]) -> Option<(Modifier, usize)> {
+        let colon = chars.first() == Some(&':');
+        let operator_index = if colon { 1 } else { 0 };
+
+        let modifier = match chars.get(operator_index) {
+            Some('-') => Modifier::UseDefault(colon),
+            Some('=') => Modifier::AssignDefault(colon),
+            Some('?') => Modifier::ErrorIfUnset(colon),
+            Some('+') => Modifier::UseAlternative(colon),
+            Some('%') if !colon => {
+                let longest = chars.get(1) == Some(&'%');
+                return Some((Modifier::RemoveSuffix(longest), if longest { 2 } else { 1 }));
+            }
+            Some('#') if !colon => {
+                let longest = chars.get(1) == Some(&'#');
+                return Some((Modifier::RemovePrefix(longest), if longest { 2 } else { 1 }));
+            }
+            _ => return None,
+        };
+
+        return Some((modifier, operator_index + 1));
+    }
+
+    /// Expand the word after a modifier, as in the `word` in `${X:-word}`
+    fn expand_modifier_word(&mut self, word: &str) -> Result<Vec<Segment>, ExpansionError> {
+        let all_quoted = self.is_quoted();
+        let mut expander = Expander::new(self.executor, word, all_quoted);
+        let segments = expander.expand()?;
+        if expander.substitution_status.is_some() {
+            self.substitution_status = expander.substitution_status;
+        }
+        return Ok(segments);
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_02
+    ///
+    /// Called with `position` at the `{`.
+    fn expand_braced_parameter(&mut self) -> Result<(), ExpansionError> {
+        let dollar_index = self.position - 1;
+        let closing_index = self.find_end(dollar_index);
+        if closing_index.is_none() {
+            return error("Unterminated ${");
+        }
+        let closing_index = closing_index.unwrap();
+        let contents: Vec<char> = self.chars[(self.position + 1)..closing_index].to_vec();
+        let expression: String = self.chars[dollar_index..=closing_index].iter().collect();
+        self.position = closing_index + 1;
+        let bad_substitution = format!("Bad substitution: {}", expression);
+
+        // "${#X}" is the length of X, but "${#}" is the number of positional
+        // parameters
+        if contents.len() > 1 && contents[0] == '#' {
+            let parsed = Expander::parse_parameter_name(&contents[1..]);
+            if parsed.is_none() || parsed.as_ref().unwrap().1 != contents.len() - 1 {
+                return error(&bad_substitution);
+            }
+            let name = parsed.unwrap().0;
+            let value = self.executor.get_variable(&name).unwrap_or_default();
+            self.push_expanded(&value.chars().count().to_string());
+            return Ok(());
+        }
+
+        let parsed = Expander::parse_parameter_name(&contents);
+        if parsed.is_none() {
+            return error(&bad_substitution);
+        }
+        let (name, name_length) = parsed.unwrap();
+        let value = self.executor.get_variable(&name);
+
+        if name_length == contents.len() {
+            // Just "${X}"
+            self.push_parameter(&name);
+            return Ok(());
+        }
+
+        let modifier = Expander::parse_modifier(&contents[name_length..]);
+        if modifier.is_none() {
+            return error(&bad_substitution);
+        }
+        let (modifier, modifier_length) = modifier.unwrap();
+        let word: String = contents[(name_length + modifier_length)..].iter().collect();
+
+        let is_set = |check_null: bool| -> bool {
+            match &value {
+                None => return false,
+                Some(value) => return !(check_null && value.is_empty()),
+            }
+        };
+
+        match modifier {
+            Modifier::UseDefault(check_null) => {
+                if is_set(check_null) {
+                    self.push_expanded(&value.unwrap());
+                } else {
+                    let default = self.expand_modifier_word(&word)?;
+                    self.result.extend(default);
+                }
+            }
+            Modifier::AssignDefault(check_null) => {
+                if is_set(check_null) {
+                    self.push_expanded(&value.unwrap());
+                } else {
+                    if !is_name(&name) {
+                        return error(&format!("{}: Cannot assign in this way", name));
+                    }
+                    let default = to_string(&self.expand_modifier_word(&word)?);
+                    self.executor.set_variable(&name, &default);
+                    self.push_expanded(&default);
+                }
+            }
+            Modifier::ErrorIfUnset(check_null) => {
+                if is_set(check_null) {
+                    self.push_expanded(&value.unwrap());
+                } else {
+                    let mut message = to_string(&self.expand_modifier_word(&word)?);
+                    if message.is_empty() {
+                        if check_null {
+                            message = "Parameter null or not set".to_string();
+                        } else {
+                            message = "Parameter not set".to_string();
+                        }
+                    }
+                    return error(&format!("{}: {}", name, message));
+                }
+            }
+            Modifier::UseAlternative(check_null) => {
+                if is_set(check_null) {
+                    let alternative = self.expand_modifier_word(&word)?;
+                    self.result.extend(alternative);
+                }
+            }
+            Modifier::RemoveSuffix(longest) => {
+                let value = value.unwrap_or_default();
+                let pattern = to_pattern(&self.expand_modifier_word(&word)?);
+                let removed = pattern.remove_suffix(&value, longest);
+                self.push_expanded(&removed.unwrap_or(value));
+            }
+            Modifier::RemovePrefix(longest) => {
+                let value = value.unwrap_or_default();
+                let pattern = to_pattern(&self.expand_modifier_word(&word)?);
+                let removed = pattern.remove_prefix(&value, longest);
+                self.push_expanded(&removed.unwrap_or(value));
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+/// Turns a token into the string that should be passed to the command being
+/// executed.
+///
+/// This is where [word expansions][1] happen. Currently [tilde expansion][5],
+/// [parameter expansion][2], [command substitution][3], [arithmetic
+/// expansion][6] and [quote removal][4] are done.
+///
+/// The input is expected to have passed through the tokenizer, so all quotes
+/// are balanced and no backslash is last.
+///
+/// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06
+/// [2]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_02
+/// [3]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+/// [4]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_07
+/// [5]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_01
+/// [6]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_04
+pub(crate) fn expand(executor: &mut dyn Executor, word: &str) -> Result<String, ExpansionError> {
+    let segments = Expander::new(executor, word, false).expand()?;
+    return Ok(to_string(&segments));
+}
+
+/// Like `expand()`, but for words that can become more than one field, like
+/// command arguments. This is where [field splitting][1] and [pathname
+/// expansion][2] happen.
+///
+/// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_05
+/// [2]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_06
+pub(crate) fn expand_fields(
+    executor: &mut dyn Executor,
+    word: &str,
+) -> Result<Vec<String>, ExpansionError> {
+    let mut expander = Expander::new(executor, word, false);
+    let segments = expander.expand()?;
+    if expander.quoted_at_was_empty && to_string(&segments).is_empty() {
+        return Ok(vec![]);
+    }
+
+    let ifs = executor
+        .get_variable("IFS")
+        .unwrap_or_else(|| DEFAULT_IFS.to_string());
+
+    let mut fields: Vec<String> = Vec::new();
+    for field in split_fields(segments, &ifs) {
+        fields.extend(expand_pathname(executor, &field)?);
+    }
+    return Ok(fields);
+}
+
+/// Expands the value of a `NAME=value` assignment. Unlike in `expand()`,
+/// tilde prefixes are also expanded after each `:`, as in `PATH=~/bin:~/.bin`.
+///
+/// Also returns the exit status of the last command substitution if there
+/// was one. A command consisting of only assignments gets its exit status
+/// from there.
+pub(crate) fn expand_assignment(
+    executor: &mut dyn Executor,
+    word: &str,
+) -> Result<(String, Option<ExitStatus>), ExpansionError> {
+    let mut expander = Expander::new(executor, word, false);
+    expander.is_assignment = true;
+    let segments = expander.expand()?;
+    return Ok((to_string(&segments), expander.substitution_status));
+}
+
+/// Expands a word into a pattern, as for the patterns in `case` commands.
+/// Quoted characters in the word are matched literally.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_05
+pub(crate) fn expand_pattern(
+    executor: &mut dyn Executor,
+    word: &str,
+) -> Result<Pattern, ExpansionError> {
+    let segments = Expander::new(executor, word, false).expand()?;
+    return Ok(to_pattern(&segments));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::testexecutor::TestExecutor;
+
+    fn expand_with(variables: &[(&str, &str)], word: &str) -> String {
+        let mut executor = TestExecutor::new();
+        for (name, value) in variables {
+            executor.set_variable(name, value);
+        }
+        return expand(&mut executor, word).unwrap();
+    }
+
+    fn expand_error(variables: &[(&str, &str)], word: &str) -> String {
+        let mut executor = TestExecutor::new();
+        for (name, value) in variables {
+            executor.set_variable(name, value);
+        }
+        return expand(&mut executor, word).unwrap_err().message;
+    }
+
+    fn expand_plain(word: &str) -> String {
+        return expand_with(&[], word);
+    }
+
+    #[test]
+    fn test_unquoted() {
+        assert_eq!(expand_plain("echo"), "echo");
+        assert_eq!(expand_plain("ödla"), "ödla");
+        assert_eq!(expand_plain(""), "");
+    }
+
+    #[test]
+    fn test_backslash_removal() {
+        assert_eq!(expand_plain(r"echo\ hej"), "echo hej");
+        assert_eq!(expand_plain(r"\>"), ">");
+        assert_eq!(expand_plain(r"\\"), r"\");
+        assert_eq!(expand_plain(r"hej\'"), "hej'");
+        assert_eq!(expand_plain("hej\\\ndå"), "hejdå");
+    }
+
+    #[test]
+    fn test_single_quote_removal() {
+        assert_eq!(expand_plain("'hello world'"), "hello world");
+        assert_eq!(expand_plain("a'b c'd"), "ab cd");
+        assert_eq!(expand_plain(r"'\'"), r"\");
+        assert_eq!(expand_plain(r#"'"'"#), r#"""#);
+        assert_eq!(expand_plain("''"), "");
+        assert_eq!(expand_plain("'$HOME'"), "$HOME");
+    }
+
+    #[test]
+    fn test_double_quote_removal() {
+        assert_eq!(expand_plain(r#""fix bug""#), "fix bug");
+        assert_eq!(expand_plain(r#""a \" b""#), r#"a " b"#);
+        assert_eq!(expand_plain(r#""\\""#), r"\");
+        assert_eq!(expand_plain(r#""\$HOME""#), "$HOME");
+
+        // Backslashes not followed by one of the special characters stay
+        assert_eq!(expand_plain(r#""\a\'""#), r"\a\'");
+
+        assert_eq!(expand_plain(r#""it's""#), "it's");
+        assert_eq!(expand_plain(r#"x"""#), "x");
+    }
+
+    #[test]
+    fn test_simple_parameters() {
+        let variables = [("HOME", "/home/johan"), ("X", "x")];
+        assert_eq!(expand_with(&variables, "$HOME"), "/home/johan");
+        assert_eq!(expand_with(&variables, "${HOME}/src"), "/home/johan/src");
+        assert_eq!(expand_with(&variables, "$HOME/src"), "/home/johan/src");
+        assert_eq!(expand_with(&variables, r#""$X$X""#), "xx");
+        assert_eq!(expand_with(&variables, "$Xy"), "");
+        assert_eq!(expand_with(&variables, "${X}y"), "xy");
+        assert_eq!(expand_with(&variables, "$UNSET"), "");
+        assert_eq!(expand_with(&variables, "a$"), "a$");
+        assert_eq!(expand_with(&variables, "$-x"), "x");
+        assert_eq!(expand_with(&variables, r"\$X"), "$X");
+    }
+
+    #[test]
+    fn test_special_parameters() {
+        let variables = [("?", "1"), ("$", "4711"), ("!", "42"), ("0", "vpixsh")];
+        assert_eq!(expand_with(&variables, "$?"), "1");
+        assert_eq!(expand_with(&variables, "$$"), "4711");
+        assert_eq!(expand_with(&variables, "${$}"), "4711");
+        assert_eq!(expand_with(&variables, "$!"), "42");
+        assert_eq!(expand_with(&variables, "$0"), "vpixsh");
+        assert_eq!(expand_with(&variables, "$?$?"), "11");
+    }
+
+    #[test]
+    fn test_default_values() {
+        let variables = [("EMPTY", ""), ("X", "x")];
+        assert_eq!(expand_with(&variables, "${X:-default}"), "x");
+        assert_eq!(expand_with(&variables, "${EMPTY:-default}"), "default");
+        assert_eq!(expand_with(&variables, "${UNSET:-default}"), "default");
+        assert_eq!(expand_with(&variables, "${EMPTY-default}"), "");
+        assert_eq!(expand_with(&variables, "${UNSET-default}"), "default");
+        assert_eq!(expand_with(&variables, "${UNSET:-}"), "");
+        assert_eq!(expand_with(&variables, "${UNSET:-$X}"), "x");
+        assert_eq!(expand_with(&variables, "${UNSET:-${EMPTY:-y}}"), "y");
+        assert_eq!(expand_with(&variables, r#"${UNSET:-"a b"}"#), "a b");
+        assert_eq!(expand_with(&variables, r#""${UNSET:-'a'}""#), "'a'");
+        assert_eq!(expand_with(&variables, r"${UNSET:-\}}"), "}");
+    }
+
+    #[test]
+    fn test_assign_default() {
+        let mut executor = TestExecutor::new();
+        executor.set_variable("EMPTY", "");
+
+        assert_eq!(expand(&mut executor, "${X:=x}").unwrap(), "x");
+        assert_eq!(executor.get_variable("X"), Some("x".to_string()));
+        assert_eq!(expand(&mut executor, "${X:=y}").unwrap(), "x");
+
+        assert_eq!(expand(&mut executor, "${EMPTY=y}").unwrap(), "");
+        assert_eq!(expand(&mut executor, "${EMPTY:=y}").unwrap(), "y");
+        assert_eq!(executor.get_variable("EMPTY"), Some("y".to_string()));
+
+        assert_eq!(
+            expand(&mut executor, "${1:=y}").unwrap_err().message,
+            "1: Cannot assign in this way"
+        );
+    }
+
+    #[test]
+    fn test_error_if_unset() {
+        let variables = [("EMPTY", ""), ("X", "x")];
+        assert_eq!(expand_with(&variables, "${X:?}"), "x");
+        assert_eq!(expand_with(&variables, "${EMPTY?}"), "");
+        assert_eq!(
+            expand_error(&variables, "${EMPTY:?}"),
+            "EMPTY: Parameter null or not set"
+        );
+        assert_eq!(
+            expand_error(&variables, "${UNSET?}"),
+            "UNSET: Parameter not set"
+        );
+        assert_eq!(
+            expand_error(&variables, "${UNSET:?Please set $X}"),
+            "UNSET: Please set x"
+        );
+    }
+
+    #[test]
+    fn test_use_alternative() {
+        let variables = [("EMPTY", ""), ("X", "x")];
+        assert_eq!(expand_with(&variables, "${X:+alt}"), "alt");
+        assert_eq!(expand_with(&variables, "${EMPTY:+alt}"), "");
+        assert_eq!(expand_with(&variables, "${EMPTY+alt}"), "alt");
+        assert_eq!(expand_with(&variables, "${UNSET+alt}"), "");
+    }
+
+    #[test]
+    fn test_length() {
+        let variables = [("X", "ödla"), ("EMPTY", "")];
+        assert_eq!(expand_with(&variables, "${#X}"), "4");
+        assert_eq!(expand_with(&variables, "${#EMPTY}"), "0");
+        assert_eq!(expand_with(&variables, "${#UNSET}"), "0");
+    }
+
+    #[test]
+    fn test_pattern_removal() {
+        let variables = [("FILE", "/usr/src/main.tar.gz"), ("STAR", "*x*")];
+        assert_eq!(expand_with(&variables, "${FILE%.*}"), "/usr/src/main.tar");
+        assert_eq!(expand_with(&variables, "${FILE%%.*}"), "/usr/src/main");
+        assert_eq!(expand_with(&variables, "${FILE#*/}"), "usr/src/main.tar.gz");
+        assert_eq!(expand_with(&variables, "${FILE##*/}"), "main.tar.gz");
+        assert_eq!(
+            expand_with(&variables, "${FILE%.zip}"),
+            "/usr/src/main.tar.gz"
+        );
+        assert_eq!(expand_with(&variables, "${UNSET%x}"), "");
+
+        // Quoted pattern characters match literally
+        assert_eq!(expand_with(&variables, r#"${STAR#"*"}"#), "x*");
+        assert_eq!(expand_with(&variables, r"${STAR%\*}"), "*x");
+        assert_eq!(expand_with(&variables, "${STAR#*}"), "*x*");
+        assert_eq!(expand_with(&variables, "${STAR##*}"), "");
+    }
+
+    #[test]
+    fn test_bad_substitution() {
+        assert_eq!(expand_error(&[], "${}"), "Bad substitution: ${}");
+        assert_eq!(expand_error(&[], "${X!}"), "Bad substitution: ${X!}");
+        assert_eq!(expand_error(&[], "${X:x}"), "Bad substitution: ${X:x}");
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        assert_eq!(expand_plain("$(echo hej)"), "hej");
+        assert_eq!(expand_plain("a$(echo b c)d"), "ab cd");
+        assert_eq!(expand_plain("$(echo $(echo nested))"), "nested");
+        assert_eq!(expand_plain(r#""$(echo "a  b")""#), "a  b");
+        assert_eq!(expand_plain("$(echo a; echo b)"), "a\nb");
+        assert_eq!(expand_plain("$(true)"), "");
+        assert_eq!(expand_plain("${X:-$(echo })}"), "}");
+
+        // Variables set in the substitution don't leak out
+        assert_eq!(expand_plain("$(: ${X:=x})${X:-unset}"), "unset");
+    }
+
+    #[test]
+    fn test_backticks() {
+        assert_eq!(expand_plain("`echo hej`"), "hej");
+        assert_eq!(expand_plain(r"`echo \`echo nested\``"), "nested");
+        assert_eq!(expand_plain(r"`echo \$X`"), "");
+        assert_eq!(expand_plain(r#""`echo \"a\"`""#), "a");
+    }
+
+    #[test]
+    fn test_substitution_exit_status() {
+        let mut executor = TestExecutor::new();
+        assert_eq!(expand(&mut executor, "$(false)").unwrap(), "");
+        assert_eq!(executor.get_variable("?"), Some("1".to_string()));
+
+        assert_eq!(
+            expand_assignment(&mut executor, "${X:-$(false)}$(true)"),
+            Ok(("".to_string(), Some(ExitStatus::success())))
+        );
+        assert_eq!(
+            expand_assignment(&mut executor, "${X:-$(false)}"),
+            Ok(("".to_string(), Some(ExitStatus::from_code(1))))
+        );
+        assert_eq!(
+            expand_assignment(&mut executor, "x"),
+            Ok(("x".to_string(), None))
+        );
+    }
+
+    #[test]
+    fn test_substitution_parse_error() {
+        assert_eq!(
+            expand_error(&[], "$(|)"),
+            "Command substitution: Expected a command here\n  |\n  ^"
+        );
+    }
+
+    #[test]
+    fn test_pathname_expansion() {
+        let directory = TestDir::new("expand");
+        for file in ["b.txt", "a.txt", "*.txt"] {
+            std::fs::write(directory.join(file), "").unwrap();
+        }
+        let base = directory.display().to_string();
+
+        let mut executor = TestExecutor::new();
+        executor.set_variable("DIR", &base);
+        let mut fields = |word: &str| -> Vec<String> {
+            return expand_fields(&mut executor, word)
+                .unwrap()
+                .iter()
+                .map(|field| field.replace(&base, "DIR"))
+                .collect();
+        };
+
+        assert_eq!(
+            fields("$DIR/*.txt"),
+            vec!["DIR/*.txt", "DIR/a.txt", "DIR/b.txt"]
+        );
+        assert_eq!(fields("$DIR/[ab].txt"), vec!["DIR/a.txt", "DIR/b.txt"]);
+
+        // Quoted pattern characters don't expand
+        assert_eq!(fields("$DIR/'*'.txt"), vec!["DIR/*.txt"]);
+        assert_eq!(fields("\"$DIR/*.txt\""), vec!["DIR/*.txt"]);
+        assert_eq!(fields("$DIR/\\?.txt"), vec!["DIR/?.txt"]);
+
+        // No match, keep the word as-is unless asked not to
+        assert_eq!(fields("$DIR/*.rs"), vec!["DIR/*.rs"]);
+        executor.set_variable("VPIXSH_NOMATCH_ERROR", "");
+        assert_eq!(
+            expand_fields(&mut executor, "$DIR/*.rs")
+                .unwrap_err()
+                .message,
+            format!("No matches for: {}/*.rs", base)
+        );
+    }
+
+    #[test]
+    fn test_tilde_expansion() {
+        let home = &[("HOME", "/home/johan"), ("OLDPWD", "/old")];
+        assert_eq!(expand_with(home, "~"), "/home/johan");
+        assert_eq!(expand_with(home, "~/src"), "/home/johan/src");
+
+        // Not /root everywhere, macOS has it in /var/root
+        let root_home = User::from_name("root").unwrap().unwrap().dir;
+        assert_eq!(
+            expand_with(home, "~root/x"),
+            root_home.join("x").to_string_lossy()
+        );
+
+        assert_eq!(expand_with(home, "~-"), "/old");
+        assert_eq!(
+            expand_with(home, "~+"),
+            env::current_dir().unwrap().to_string_lossy()
+        );
+
+        // Not tilde prefixes
+        assert_eq!(expand_with(home, "a~"), "a~");
+        assert_eq!(expand_with(home, "'~'"), "~");
+        assert_eq!(expand_with(home, "\"~\""), "~");
+        assert_eq!(expand_with(home, "\\~"), "~");
+        assert_eq!(expand_with(home, "~'root'"), "~root");
+        assert_eq!(expand_with(home, "~no-such-user/x"), "~no-such-user/x");
+        assert_eq!(expand_with(home, "a:~"), "a:~");
+        assert_eq!(expand_with(home, "${X:-~}"), "/home/johan");
+        assert_eq!(expand_with(home, "\"${X:-~}\""), "~");
+    }
+
+    #[test]
+    fn test_tilde_in_assignments() {
+        let mut executor = TestExecutor::new();
+        executor.set_variable("HOME", "/h");
+
+        let (value, _) = expand_assignment(&mut executor, "~/bin:~/.bin:a~:'~'").unwrap();
+        assert_eq!(value, "/h/bin:/h/.bin:a~:~");
+
+        let (value, _) = expand_assignment(&mut executor, "x\\:~").unwrap();
+        assert_eq!(value, "x:~");
+    }
+
+    /// Like `record_execs()` in parser.rs, but with some variables and
+    /// positional parameters set first
+    fn record_execs_with(
+        variables: &[(&str, &str)],
+        parameters: &[&str],
+        commandline: &str,
+    ) -> Vec<String> {
+        let mut executor = TestExecutor::new();
+        for (name, value) in variables {
+            executor.set_variable(name, value);
+        }
+        executor.set_positional_parameters(parameters.iter().map(|p| p.to_string()).collect());
+
+        crate::interpreter::run(&mut executor, &parse(commandline).unwrap().program);
+        return executor.executions;
+    }
+
+    fn execs_with(variables: &[(&str, &str)], commandline: &str) -> Vec<String> {
+        return record_execs_with(variables, &[], commandline);
+    }
+
+    #[test]
+    fn test_field_splitting_whitespace() {
+        let x = &[("X", " a  b\tc\n ")];
+        assert_eq!(
+            execs_with(x, "echo $X"),
+            vec!["exec('echo', 'a', 'b', 'c')"]
+        );
+        assert_eq!(
+            execs_with(x, "echo \"$X\""),
+            vec!["exec('echo', ' a  b\tc\n ')"]
+        );
+        assert_eq!(
+            execs_with(x, "echo x${X}y"),
+            vec!["exec('echo', 'x', 'a', 'b', 'c', 'y')"]
+        );
+
+        // Literal text is never split, only expansion results
+        assert_eq!(
+            execs_with(&[("IFS", "o")], "echo foo$IFS'o'"),
+            vec!["exec('echo', 'foo', 'o')"]
+        );
+
+        assert_eq!(
+            execs_with(&[], "echo $(echo a b)"),
+            vec!["exec('echo', 'a', 'b')", "exec('echo', 'a', 'b')"]
+        );
+        assert_eq!(
+            execs_with(&[("HOME", "/a b")], "echo ~"),
+            vec!["exec('echo', '/a b')"]
+        );
+    }
+
+    #[test]
+    fn test_field_splitting_empty() {
+        let empty = &[("EMPTY", ""), ("BLANK", "   ")];
+        assert_eq!(
+            execs_with(empty, "echo $EMPTY x $BLANK"),
+            vec!["exec('echo', 'x')"]
+        );
+        assert_eq!(
+            execs_with(empty, "echo \"$EMPTY\" ''$BLANK"),
+            vec!["exec('echo', '', '')"]
+        );
+
+        // No command left at all is OK
+        assert!(execs_with(empty, "$EMPTY").is_empty());
+    }
+
+    #[test]
+    fn test_field_splitting_non_whitespace() {
+        // Examples from the POSIX rationale, with one field per character
+        // that isn't IFS white space
+        let colon = |value: &'static str| -> Vec<(&'static str, &'static str)> {
+            return vec![("IFS", ": "), ("X", value)];
+        };
+        assert_eq!(
+            execs_with(&colon("a::b"), "echo $X"),
+            vec!["exec('echo', 'a', '', 'b')"]
+        );
+        assert_eq!(
+            execs_with(&colon("a : b"), "echo $X"),
+            vec!["exec('echo', 'a', 'b')"]
+        );
+        assert_eq!(
+            execs_with(&colon(":a:"), "echo $X"),
+            vec!["exec('echo', '', 'a')"]
+        );
+        assert_eq!(
+            execs_with(&colon(" : a"), "echo $X"),
+            vec!["exec('echo', '', 'a')"]
+        );
+
+        // Empty IFS means no splitting
+        assert_eq!(
+            execs_with(&[("IFS", ""), ("X", "a b")], "echo $X"),
+            vec!["exec('echo', 'a b')"]
+        );
+    }
+
+    #[test]
+    fn test_positional_parameter_fields() {
+        let parameters = &["a", "b c", ""];
+        assert_eq!(
+            record_execs_with(&[], parameters, "echo \"$@\""),
+            vec!["exec('echo', 'a', 'b c', '')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], parameters, "echo $@"),
+            vec!["exec('echo', 'a', 'b', 'c')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], parameters, "echo \"x$@y\""),
+            vec!["exec('echo', 'xa', 'b c', 'y')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], parameters, "echo \"${@}\""),
+            vec!["exec('echo', 'a', 'b c', '')"]
+        );
+        assert_eq!(
+            record_execs_with(&[("IFS", ":")], parameters, "echo \"$*\""),
+            vec!["exec('echo', 'a:b c:')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], parameters, "echo \"$*\""),
+            vec!["exec('echo', 'a b c ')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], parameters, "for p in \"$@\"; do echo \"<$p>\"; done"),
+            vec![
+                "exec('echo', '<a>')",
+                "exec('echo', '<b c>')",
+                "exec('echo', '<>')"
+            ]
+        );
+
+        // No parameters, no fields
+        assert_eq!(
+            record_execs_with(&[], &[], "echo \"$@\""),
+            vec!["exec('echo')"]
+        );
+        assert_eq!(
+            record_execs_with(&[], &[], "echo \"x$@\""),
+            vec!["exec('echo', 'x')"]
+        );
+    }
+
+    #[test]
+    fn test_arithmetic_expansion() {
+        let i = &[("i", "4"), ("X", "2 + 3")];
+        assert_eq!(expand_with(i, "$((i+1))"), "5");
+        assert_eq!(expand_with(i, "$(( $i * 2 ))"), "8");
+        // Expansions happen before evaluation, so this is "2 + 3 * 2"
+        assert_eq!(expand_with(i, "$(($X * 2))"), "8");
+        assert_eq!(expand_with(i, "\"$((\"$i\" - 5))\""), "-1");
+        assert_eq!(expand_with(i, "x$((${i:-0}<<1))y"), "x8y");
+        assert_eq!(
+            expand_error(i, "$((i / 0))"),
+            "Division by zero: $((i / 0))"
+        );
+        assert_eq!(
+            expand_error(i, "$((i +))"),
+            "Expected a number or a variable name: $((i +))"
+        );
+
+        // Assignments stick
+        let mut executor = TestExecutor::new();
+        assert_eq!(
+            expand(&mut executor, "$((n += 2))$((n *= 3))").unwrap(),
+            "26"
+        );
+        assert_eq!(executor.get_variable("n"), Some("6".to_string()));
+
+        // A command substitution starting with a subshell isn't arithmetic
+        assert_eq!(
+            execs_with(&[], "echo $((echo a); (echo b))"),
+            vec![
+                "(",
+                "exec('echo', 'a')",
+                ")",
+                "(",
+                "exec('echo', 'b')",
+                ")",
+                "exec('echo', 'a', 'b')"
+            ]
+        );
+    }
+}
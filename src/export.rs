@@ -0,0 +1,130 @@
+use crate::expansion::is_name;
+use crate::Shell;
+
+/// Quotes the value so that the shell reads it back as-is
+fn quote(value: &str) -> String {
+    return format!("'{}'", value.replace('\'', r"'\''"));
+}
+
+impl Shell {
+    /// Prints all exported variables in a form that can be read back by the
+    /// shell
+    fn print_exported(&self) {
+        for (name, variable) in self.variables.iter() {
+            if !variable.exported {
+                continue;
+            }
+
+            match &variable.value {
+                Some(value) => println!("export {}={}", name, quote(value)),
+                None => println!("export {}", name),
+            }
+        }
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#export
+    pub(crate) fn export(&mut self, args: &[String]) -> String {
+        if args.is_empty() || (args.len() == 1 && args[0] == "-p") {
+            self.print_exported();
+            return "".to_string();
+        }
+
+        let mut problem = "".to_string();
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !is_name(name) {
+                println!("ERROR: export: Not a valid variable name: {}", name);
+                problem = "Bad variable name".to_string();
+                continue;
+            }
+
+            if let Some(value) = value {
+                self.variables.set(name, value);
+            }
+            self.variables.export(name);
+        }
+
+        return problem;
+    }
+
+    /// Makes variables local to the function we're in, optionally setting
+    /// them as in `local X=1`. Not in POSIX, but both `bash` and `dash` have
+    /// it.
+    ///
+    /// Ref: https://www.gnu.org/software/bash/manual/html_node/Bash-Builtins.html#index-local
+    pub(crate) fn local(&mut self, args: &[String]) -> String {
+        let mut problem = "".to_string();
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+
+            if !is_name(name) {
+                println!("ERROR: local: Not a valid variable name: {}", name);
+                problem = "Bad variable name".to_string();
+                continue;
+            }
+
+            if !self.variables.make_local(name) {
+                println!("ERROR: local: Can only be used in a function");
+                return "Not in a function".to_string();
+            }
+            if let Some(value) = value {
+                self.variables.set(name, value);
+            }
+        }
+
+        return problem;
+    }
+
+    /// Unsets variables, or functions with `-f`.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#unset
+    pub(crate) fn unset(&mut self, args: &[String]) -> String {
+        let mut names = args;
+        let mut functions = false;
+        if let Some(first) = names.first() {
+            if first == "-v" || first == "-f" {
+                functions = first == "-f";
+                names = &names[1..];
+            } else if first.starts_with('-') {
+                println!("ERROR: unset: Unsupported option: {}", first);
+                return "Bad option".to_string();
+            }
+        }
+
+        let mut problem = "".to_string();
+        for name in names {
+            if !is_name(name) {
+                println!("ERROR: unset: Not a valid name: {}", name);
+                problem = "Bad name".to_string();
+                continue;
+            }
+
+            if functions {
+                self.functions.remove(name);
+            } else {
+                self.variables.unset(name);
+            }
+        }
+
+        return problem;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quote() {
+        assert_eq!(quote("a b"), "'a b'");
+        assert_eq!(quote(""), "''");
+        assert_eq!(quote("it's"), r"'it'\''s'");
+    }
+}
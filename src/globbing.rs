@@ -0,0 +1,147 @@
+use std::fs;
+use std::path::Path;
+
+use crate::pattern::Pattern;
+
+/// One `/` separated part of a pathname pattern
+pub(crate) struct Component {
+    pub pattern: Pattern,
+
+    /// The component with quotes removed, used as-is if it's not a pattern
+    pub text: String,
+
+    /// False if there are no unquoted `*`, `?` or `[` in this component
+    pub is_pattern: bool,
+}
+
+/// Names in `directory` matching the pattern, without the directory part.
+///
+/// Names starting with a `.` only match if the pattern starts with one as
+/// well.
+fn matching_names(directory: &str, component: &Component) -> Vec<String> {
+    let directory = if directory.is_empty() { "." } else { directory };
+    let entries = fs::read_dir(directory);
+    if entries.is_err() {
+        return vec![];
+    }
+
+    let mut names: Vec<String> = Vec::new();
+    for entry in entries.unwrap().flatten() {
+        let name = entry.file_name().into_string();
+        if name.is_err() {
+            // FIXME: Support non-UTF-8 file names
+            continue;
+        }
+        let name = name.unwrap();
+
+        if name.starts_with('.') && !component.text.starts_with('.') {
+            continue;
+        }
+        if component.pattern.matches(&name) {
+            names.push(name);
+        }
+    }
+
+    return names;
+}
+
+/// Finds all paths matching the components, sorted. Returns an empty vector
+/// if nothing matched.
+///
+/// `absolute` is true for patterns starting with a `/`.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_13_03
+pub(crate) fn glob(absolute: bool, components: &[Component]) -> Vec<String> {
+    // Each of these is a directory ending in a slash, or the empty string for
+    // the current directory
+    let mut paths: Vec<String> = vec![if absolute { "/" } else { "" }.to_string()];
+
+    for (index, component) in components.iter().enumerate() {
+        let is_last = index == components.len() - 1;
+
+        let mut next_paths: Vec<String> = Vec::new();
+        for path in &paths {
+            if !component.is_pattern {
+                // Whether this exists is checked at the end
+                next_paths.push(format!("{}{}", path, component.text));
+                continue;
+            }
+
+            for name in matching_names(path, component) {
+                let candidate = format!("{}{}", path, name);
+                if !is_last && !Path::new(&candidate).is_dir() {
+                    continue;
+                }
+                next_paths.push(candidate);
+            }
+        }
+
+        if !is_last {
+            for path in next_paths.iter_mut() {
+                path.push('/');
+            }
+        }
+        paths = next_paths;
+    }
+
+    paths.retain(|path| fs::symlink_metadata(path).is_ok());
+    paths.sort();
+    return paths;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+
+    fn component(text: &str) -> Component {
+        return Component {
+            pattern: Pattern::new(text),
+            text: text.to_string(),
+            is_pattern: text.contains(['*', '?', '[']),
+        };
+    }
+
+    fn glob_in(directory: &Path, pattern: &str) -> Vec<String> {
+        let components: Vec<Component> = pattern.split('/').map(component).collect();
+        let prefix = format!("{}/", directory.display());
+        return glob(true, &components[1..])
+            .into_iter()
+            .map(|path| path.strip_prefix(&prefix).unwrap().to_string())
+            .collect();
+    }
+
+    #[test]
+    fn test_glob() {
+        let directory = TestDir::new("glob");
+        fs::create_dir_all(directory.join("src/sub")).unwrap();
+        for file in ["b.rs", "a.rs", ".hidden.rs", "src/main.rs", "README"] {
+            fs::write(directory.join(file), "").unwrap();
+        }
+        let base = format!("{}", directory.display());
+
+        assert_eq!(
+            glob_in(&directory, &format!("{}/*.rs", base)),
+            vec!["a.rs", "b.rs"]
+        );
+        assert_eq!(
+            glob_in(&directory, &format!("{}/.*", base)),
+            vec![".hidden.rs"]
+        );
+        assert_eq!(
+            glob_in(&directory, &format!("{}/*/*.rs", base)),
+            vec!["src/main.rs"]
+        );
+        assert_eq!(glob_in(&directory, &format!("{}/*/", base)), vec!["src/"]);
+        assert_eq!(
+            glob_in(&directory, &format!("{}/src/?ain.rs", base)),
+            vec!["src/main.rs"]
+        );
+        assert_eq!(
+            glob_in(&directory, &format!("{}/[A-Z]*", base)),
+            vec!["README"]
+        );
+        assert!(glob_in(&directory, &format!("{}/*.txt", base)).is_empty());
+        assert!(glob_in(&directory, &format!("{}/nothere/*", base)).is_empty());
+    }
+}
@@ -0,0 +1,945 @@
+use std::fs;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+use crate::ast::{
+    AndOr, AndOrOperator, CaseItem, Command, CompoundCommand, List, Pipeline, Redirection,
+    Separator, SimpleCommand, Word,
+};
+use crate::exitstatus::ExitStatus;
+use crate::expansion::{expand, expand_assignment, expand_fields, expand_pattern, ExpansionError};
+use crate::parser::parse;
+use crate::redirection::{Redirect, SavedFd};
+
+pub(crate) trait Executor {
+    /// command is the binary to executs
+    ///
+    /// argv is all the command line arguments. argv does *not* include the
+    /// command itself, and will be empty if no arguments are required.
+    ///
+    /// assignments are the expanded `NAME=value` pairs from in front of the
+    /// command, as in `FOO=1 cmd`. Except for special builtins like `export`,
+    /// they only apply while this command runs.
+    fn execute(
+        &mut self,
+        command: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus;
+
+    /// Like `execute()`, but called for the last command of a forked child.
+    /// Once the words are expanded there is nothing left for the child to do,
+    /// so an external command may replace the current process rather than
+    /// being spawned.
+    fn execute_in_place(
+        &mut self,
+        command: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus {
+        return self.execute(command, args, assignments);
+    }
+
+    /// Runs all commands at the same time, with the output of each command
+    /// connected to the input of the next one. Use `run_command()` to run the
+    /// individual commands.
+    ///
+    /// Will be called with single-command pipelines as well, those should be
+    /// run in the current process so that for example `cd` works.
+    ///
+    /// Returns one exit status per command.
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> Vec<ExitStatus>;
+
+    /// Starts running the and-or list in the background, as in `sleep 5 &`,
+    /// without waiting for it to finish. Run it using `run_and_or()`.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_03_02
+    fn execute_background(&mut self, and_or: &AndOr) -> ExitStatus;
+
+    /// Applies redirections to the current process, see
+    /// `redirection::redirect()`.
+    ///
+    /// Redirections are applied before running any command, builtin or not,
+    /// and are undone using `restore()` afterwards.
+    fn redirect(&mut self, redirects: &[Redirect]) -> Result<Vec<SavedFd>, ExitStatus>;
+
+    /// Undoes what `redirect()` did
+    fn restore(&mut self, saved: Vec<SavedFd>);
+
+    /// Returns the value of a variable or a special parameter like `?`, or
+    /// `None` if it isn't set
+    fn get_variable(&self, name: &str) -> Option<String>;
+
+    fn set_variable(&mut self, name: &str, value: &str);
+
+    /// Called with the exit status of each pipeline, for `$?`
+    fn set_exit_status(&mut self, status: &ExitStatus);
+
+    /// Runs the program in a subshell, as in `(cd /tmp && ls)`. Nothing the
+    /// program does to variables or the current directory should be visible
+    /// afterwards.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_12
+    fn execute_subshell(&mut self, program: &List) -> ExitStatus;
+
+    /// Runs the program in a subshell with its output captured, for command
+    /// substitution.
+    ///
+    /// Returns everything the program printed to stdout, and its exit status.
+    fn capture(&mut self, program: &List) -> (String, ExitStatus);
+
+    /// Stores a function, replacing any earlier function with the same name.
+    /// When the function is executed, run it using `call_function()`.
+    fn define_function(&mut self, name: &str, body: Rc<Command>);
+
+    /// Replaces `$1`, `$2` and so on, returning the old ones
+    fn set_positional_parameters(&mut self, parameters: Vec<String>) -> Vec<String>;
+
+    /// `$1`, `$2` and so on
+    fn get_positional_parameters(&self) -> Vec<String>;
+
+    /// The directory `cd -` would go to, for `~-`
+    fn get_oldpwd(&self) -> Option<String>;
+
+    /// Interpreter bookkeeping that needs to live as long as the executor
+    fn state(&mut self) -> &mut InterpreterState;
+}
+
+/// How the interpreter should continue after the last command
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum ControlFlow {
+    #[default]
+    Normal,
+
+    /// `return` was executed, unwind until we get out of the innermost
+    /// function or sourced file
+    Return,
+
+    /// `break n`, leave the `n` innermost loops
+    Break(usize),
+
+    /// `continue n`, leave the `n - 1` innermost loops and continue with the
+    /// next iteration of the one outside of those
+    Continue(usize),
+
+    /// The user pressed ctrl-c, unwind all the way and skip the rest of the
+    /// command line
+    Interrupt,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct InterpreterState {
+    pub control_flow: ControlFlow,
+
+    /// How many functions and sourced files we are currently in. `return`
+    /// is only valid inside of those.
+    pub function_depth: usize,
+
+    /// How many loops we are currently in, in the current function
+    pub loop_depth: usize,
+}
+
+/// Executes a parsed command line by walking its syntax tree.
+///
+/// Returns the exit status of the last command executed.
+pub(crate) fn run(executor: &mut dyn Executor, program: &List) -> ExitStatus {
+    let mut status = ExitStatus::success();
+    for (and_or, separator) in &program.items {
+        if *separator == Separator::Background {
+            status = executor.execute_background(and_or);
+            continue;
+        }
+
+        status = run_and_or(executor, and_or);
+        if is_unwinding(executor) {
+            break;
+        }
+    }
+
+    return status;
+}
+
+/// True while a `return`, `break`, `continue` or ctrl-c is on its way out
+fn is_unwinding(executor: &mut dyn Executor) -> bool {
+    return executor.state().control_flow != ControlFlow::Normal;
+}
+
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_03_07
+pub(crate) fn run_and_or(executor: &mut dyn Executor, and_or: &AndOr) -> ExitStatus {
+    let mut status = run_pipeline(executor, &and_or.first);
+    if is_unwinding(executor) {
+        return status;
+    }
+
+    for (operator, pipeline) in &and_or.rest {
+        let should_run = match operator {
+            AndOrOperator::And => status.is_success(),
+            AndOrOperator::Or => !status.is_success(),
+        };
+        if should_run {
+            status = run_pipeline(executor, pipeline);
+        }
+        if is_unwinding(executor) {
+            break;
+        }
+    }
+
+    return status;
+}
+
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_02
+fn run_pipeline(executor: &mut dyn Executor, pipeline: &Pipeline) -> ExitStatus {
+    let statuses = executor.execute_pipeline(pipeline);
+
+    // POSIX says the status of a pipeline is the status of its last command
+    let mut status = statuses.last().unwrap().clone();
+
+    if pipeline.bang {
+        if status.is_success() {
+            status = ExitStatus::from_code(1);
+        } else {
+            status = ExitStatus::success();
+        }
+    }
+
+    executor.set_exit_status(&status);
+    return status;
+}
+
+pub(crate) fn run_command(executor: &mut dyn Executor, command: &Command) -> ExitStatus {
+    match command {
+        Command::Simple(simple_command) => {
+            return run_simple_command(executor, simple_command, false)
+        }
+        Command::Compound(compound, redirections) => {
+            let saved = apply_redirections(executor, redirections);
+            if let Err(status) = saved {
+                return status;
+            }
+
+            let status = run_compound_command(executor, compound);
+            executor.restore(saved.unwrap());
+            return status;
+        }
+        Command::FunctionDefinition(definition) => {
+            executor.define_function(&definition.name, Rc::clone(&definition.body));
+            return ExitStatus::success();
+        }
+    }
+}
+
+/// Like `run_command()`, for the last command of a forked child. See
+/// `Executor::execute_in_place()`.
+pub(crate) fn run_command_in_place(executor: &mut dyn Executor, command: &Command) -> ExitStatus {
+    match command {
+        Command::Simple(simple_command) => {
+            return run_simple_command(executor, simple_command, true)
+        }
+        _ => return run_command(executor, command),
+    }
+}
+
+fn run_compound_command(executor: &mut dyn Executor, compound: &CompoundCommand) -> ExitStatus {
+    match compound {
+        CompoundCommand::BraceGroup(list) => return run(executor, list),
+        CompoundCommand::Subshell(list) => return executor.execute_subshell(list),
+        CompoundCommand::If {
+            conditionals,
+            otherwise,
+        } => {
+            for (condition, body) in conditionals {
+                let status = run(executor, condition);
+                if is_unwinding(executor) {
+                    return status;
+                }
+                if status.is_success() {
+                    return run(executor, body);
+                }
+            }
+
+            if let Some(otherwise) = otherwise {
+                return run(executor, otherwise);
+            }
+
+            // "The exit status shall be zero if no condition tested true"
+            return ExitStatus::success();
+        }
+        CompoundCommand::While { condition, body } => {
+            return run_loop(executor, condition, body, true);
+        }
+        CompoundCommand::Until { condition, body } => {
+            return run_loop(executor, condition, body, false);
+        }
+        CompoundCommand::For { name, words, body } => {
+            return run_for(executor, name, words, body);
+        }
+        CompoundCommand::Case { word, items } => return run_case(executor, word, items),
+    }
+}
+
+/// Call this after each round of a loop body. Returns true if the loop should
+/// stop because of `break`, `continue`, `return` or ctrl-c.
+fn should_leave_loop(executor: &mut dyn Executor) -> bool {
+    let state = executor.state();
+    match state.control_flow {
+        ControlFlow::Normal => return false,
+        ControlFlow::Return | ControlFlow::Interrupt => return true,
+        ControlFlow::Break(1) => {
+            state.control_flow = ControlFlow::Normal;
+            return true;
+        }
+        ControlFlow::Break(loops) => {
+            state.control_flow = ControlFlow::Break(loops - 1);
+            return true;
+        }
+        ControlFlow::Continue(1) => {
+            state.control_flow = ControlFlow::Normal;
+            return false;
+        }
+        ControlFlow::Continue(loops) => {
+            state.control_flow = ControlFlow::Continue(loops - 1);
+            return true;
+        }
+    }
+}
+
+/// Runs `while` loops, or `until` loops if `while_success` is false.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_09
+fn run_loop(
+    executor: &mut dyn Executor,
+    condition: &List,
+    body: &List,
+    while_success: bool,
+) -> ExitStatus {
+    // "The exit status [...] shall be zero if compound-list-2 is not executed"
+    let mut status = ExitStatus::success();
+
+    executor.state().loop_depth += 1;
+    loop {
+        let condition_status = run(executor, condition);
+        if should_leave_loop(executor) {
+            break;
+        }
+        if condition_status.is_success() != while_success {
+            break;
+        }
+
+        status = run(executor, body);
+        if should_leave_loop(executor) {
+            break;
+        }
+    }
+    executor.state().loop_depth -= 1;
+
+    return status;
+}
+
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_03
+fn run_for(
+    executor: &mut dyn Executor,
+    name: &str,
+    words: &Option<Vec<Word>>,
+    body: &List,
+) -> ExitStatus {
+    let values = match words {
+        None => executor.get_positional_parameters(),
+        Some(words) => {
+            let mut values: Vec<String> = Vec::new();
+            for word in words {
+                match expand_fields(executor, &word.text) {
+                    Ok(fields) => values.extend(fields),
+                    Err(error) => return expansion_failed(error),
+                }
+            }
+            values
+        }
+    };
+
+    let mut status = ExitStatus::success();
+    executor.state().loop_depth += 1;
+    for value in values {
+        executor.set_variable(name, &value);
+        status = run(executor, body);
+        if should_leave_loop(executor) {
+            break;
+        }
+    }
+    executor.state().loop_depth -= 1;
+
+    return status;
+}
+
+/// Runs the body of the first item with a pattern matching the word
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_05
+fn run_case(executor: &mut dyn Executor, word: &Word, items: &[CaseItem]) -> ExitStatus {
+    let word = expand(executor, &word.text);
+    if let Err(error) = word {
+        return expansion_failed(error);
+    }
+    let word = word.unwrap();
+
+    for item in items {
+        for pattern in &item.patterns {
+            match expand_pattern(executor, &pattern.text) {
+                Ok(pattern) => {
+                    if pattern.matches(&word) {
+                        return run(executor, &item.body);
+                    }
+                }
+                Err(error) => return expansion_failed(error),
+            }
+        }
+    }
+
+    // "The exit status of case shall be zero if no patterns are matched"
+    return ExitStatus::success();
+}
+
+/// Runs a function body with `args` as its positional parameters
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_05
+pub(crate) fn call_function(
+    executor: &mut dyn Executor,
+    body: &Command,
+    args: &[String],
+) -> ExitStatus {
+    let saved = executor.set_positional_parameters(args.to_vec());
+    let status = run_returnable(executor, |executor| run_command(executor, body));
+    executor.set_positional_parameters(saved);
+    return status;
+}
+
+/// Runs something that `return` can return from, like a function or a
+/// sourced file
+fn run_returnable(
+    executor: &mut dyn Executor,
+    run_body: impl FnOnce(&mut dyn Executor) -> ExitStatus,
+) -> ExitStatus {
+    // Loops outside of the function can't be broken out of from inside it
+    let loop_depth = std::mem::take(&mut executor.state().loop_depth);
+    executor.state().function_depth += 1;
+    let status = run_body(executor);
+
+    let state = executor.state();
+    state.function_depth -= 1;
+    state.loop_depth = loop_depth;
+    if state.control_flow == ControlFlow::Return {
+        state.control_flow = ControlFlow::Normal;
+    }
+    return status;
+}
+
+/// The `return` builtin. Without an argument, the exit status is the one of
+/// the last command.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#return
+fn return_builtin(executor: &mut dyn Executor, args: &[String]) -> ExitStatus {
+    if executor.state().function_depth == 0 {
+        println!("ERROR: return: Can only return from a function or a sourced file");
+        return ExitStatus::failure(1, "Not in a function");
+    }
+
+    let status = match args {
+        [] => {
+            let code = executor.get_variable("?").unwrap_or_default();
+            ExitStatus::from_code(code.parse().unwrap_or(0))
+        }
+        [code] => match code.parse::<i32>() {
+            Ok(code) => ExitStatus::from_code(code & 0xff),
+            Err(_) => {
+                println!("ERROR: return: Numeric argument required: {}", code);
+                ExitStatus::failure(2, "Bad return value")
+            }
+        },
+        _ => {
+            println!("ERROR: return: Too many arguments");
+            ExitStatus::failure(2, "Too many arguments")
+        }
+    };
+
+    executor.state().control_flow = ControlFlow::Return;
+    return status;
+}
+
+/// The `break` and `continue` builtins, `name` says which one
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#break
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#continue
+fn break_continue_builtin(executor: &mut dyn Executor, name: &str, args: &[String]) -> ExitStatus {
+    let loop_depth = executor.state().loop_depth;
+    if loop_depth == 0 {
+        println!("ERROR: {}: Only meaningful in a loop", name);
+        return ExitStatus::failure(1, "Not in a loop");
+    }
+
+    let loops = match args {
+        [] => 1,
+        [loops] => match loops.parse::<usize>() {
+            Ok(loops) if loops > 0 => loops,
+            _ => {
+                println!(
+                    "ERROR: {}: Expected a loop count of 1 or more: {}",
+                    name, loops
+                );
+                return ExitStatus::failure(2, "Bad loop count");
+            }
+        },
+        _ => {
+            println!("ERROR: {}: Too many arguments", name);
+            return ExitStatus::failure(2, "Too many arguments");
+        }
+    };
+
+    // "If n is greater than the number of enclosing loops, the outermost
+    // enclosing loop shall be exited"
+    let loops = loops.min(loop_depth);
+    executor.state().control_flow = if name == "break" {
+        ControlFlow::Break(loops)
+    } else {
+        ControlFlow::Continue(loops)
+    };
+    return ExitStatus::success();
+}
+
+/// File names without slashes are looked up in `$PATH`, and then in the current
+/// directory like `bash` does.
+fn find_sourced_file(executor: &dyn Executor, name: &str) -> PathBuf {
+    if name.contains('/') {
+        return PathBuf::from(name);
+    }
+
+    let path = executor.get_variable("PATH").unwrap_or_default();
+    for directory in path.split(':').filter(|directory| !directory.is_empty()) {
+        let candidate = PathBuf::from(directory).join(name);
+        if candidate.is_file() {
+            return candidate;
+        }
+    }
+
+    return PathBuf::from(name);
+}
+
+/// Runs the commands from a file in the current shell, for the `.` and
+/// `source` builtins. Any extra arguments become positional parameters while
+/// the file runs.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#dot
+pub(crate) fn source(executor: &mut dyn Executor, args: &[String]) -> ExitStatus {
+    if args.is_empty() {
+        println!("ERROR: source: Expected a file name");
+        return ExitStatus::failure(2, "No file name");
+    }
+
+    let path = find_sourced_file(executor, &args[0]);
+    let contents = fs::read_to_string(&path);
+    if let Err(error) = contents {
+        println!(
+            "ERROR: source: Cannot read {}: {}",
+            path.to_string_lossy(),
+            error
+        );
+        return ExitStatus::failure(1, &error.to_string());
+    }
+    let contents = contents.unwrap();
+
+    let parsed = parse(&contents);
+    if let Err(error) = parsed {
+        let line = contents[..error.text.location_offset()]
+            .matches('\n')
+            .count()
+            + 1;
+        println!("ERROR: {}:{}: {}", path.to_string_lossy(), line, error);
+        return ExitStatus::failure(2, "Syntax error");
+    }
+    let program = parsed.unwrap().program;
+
+    if args.len() == 1 {
+        return run_returnable(executor, |executor| run(executor, &program));
+    }
+
+    let saved = executor.set_positional_parameters(args[1..].to_vec());
+    let status = run_returnable(executor, |executor| run(executor, &program));
+    executor.set_positional_parameters(saved);
+    return status;
+}
+
+fn expand_redirections(
+    executor: &mut dyn Executor,
+    redirections: &[Redirection],
+) -> Result<Vec<Redirect>, ExpansionError> {
+    let mut redirects: Vec<Redirect> = Vec::new();
+    for redirection in redirections {
+        redirects.push(Redirect {
+            fd: redirection
+                .fd
+                .unwrap_or_else(|| Redirect::default_fd(redirection.operator)),
+            operator: redirection.operator,
+            target: expand(executor, &redirection.target.text)?,
+        });
+    }
+    return Ok(redirects);
+}
+
+fn expansion_failed(error: ExpansionError) -> ExitStatus {
+    println!("ERROR: {}", error);
+    return ExitStatus::failure(1, &error.message);
+}
+
+/// Expands the redirections and applies them, see `Executor::redirect()`
+fn apply_redirections(
+    executor: &mut dyn Executor,
+    redirections: &[Redirection],
+) -> Result<Vec<SavedFd>, ExitStatus> {
+    let redirects = expand_redirections(executor, redirections);
+    if let Err(error) = redirects {
+        return Err(expansion_failed(error));
+    }
+
+    return executor.redirect(&redirects.unwrap());
+}
+
+fn run_simple_command(
+    executor: &mut dyn Executor,
+    command: &SimpleCommand,
+    in_place: bool,
+) -> ExitStatus {
+    // The syntax tree keeps the raw words for highlighting, while the executor
+    // gets the expanded ones
+    let mut words: Vec<String> = Vec::new();
+    for word in &command.words {
+        match expand_fields(executor, &word.text) {
+            Ok(fields) => words.extend(fields),
+            Err(error) => return expansion_failed(error),
+        }
+    }
+
+    let saved = apply_redirections(executor, &command.redirections);
+    if let Err(status) = saved {
+        return status;
+    }
+    let saved = saved.unwrap();
+
+    // Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_01
+    let mut assignments: Vec<(String, String)> = Vec::new();
+    let mut substitution_status: Option<ExitStatus> = None;
+    for assignment in &command.assignments {
+        match expand_assignment(executor, &assignment.value.text) {
+            Ok((value, status)) => {
+                if status.is_some() {
+                    substitution_status = status;
+                }
+                if words.is_empty() {
+                    // Done in order, so that "A=1 B=$A" works
+                    executor.set_variable(&assignment.name, &value);
+                }
+                assignments.push((assignment.name.to_string(), value));
+            }
+            Err(error) => {
+                executor.restore(saved);
+                return expansion_failed(error);
+            }
+        }
+    }
+
+    let status = if words.is_empty() {
+        // Only assignments and redirections. Redirections alone, like
+        // "> file", is a valid command that just creates the file.
+        substitution_status.unwrap_or_else(ExitStatus::success)
+    } else if words[0] == "return" {
+        // These change how the interpreter continues, so we handle them
+        // ourselves rather than leaving them to the executor
+        return_builtin(executor, &words[1..])
+    } else if words[0] == "break" || words[0] == "continue" {
+        break_continue_builtin(executor, &words[0], &words[1..])
+    } else if in_place {
+        executor.execute_in_place(&words[0], &words[1..], &assignments)
+    } else {
+        executor.execute(&words[0], &words[1..], &assignments)
+    };
+
+    executor.restore(saved);
+    return status;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testdir::TestDir;
+    use crate::testexecutor::TestExecutor;
+
+    fn run_commandline(executor: &mut TestExecutor, commandline: &str) -> ExitStatus {
+        return run(executor, &parse(commandline).unwrap().program);
+    }
+
+    #[test]
+    fn test_source_venv_activate() {
+        // Generate an activate script the same way "python3 -m venv" does,
+        // from the template in CPython's venv/scripts/common/activate
+        let activate = include_str!("testdata/activate")
+            .replace("__VENV_DIR__", "/home/johan/env")
+            .replace("__VENV_BIN_NAME__", "bin")
+            .replace("__VENV_PROMPT__", "(env) ");
+        let directory = TestDir::new("activate");
+        let path = directory.join("activate");
+        fs::write(&path, activate).unwrap();
+
+        let mut executor = TestExecutor::new();
+        executor.set_variable("PATH", "/usr/bin:/bin");
+        executor.set_variable("PS1", "$ ");
+
+        let status = run_commandline(&mut executor, &format!(". {}", path.display()));
+        assert!(status.is_success());
+        assert_eq!(
+            executor.get_variable("PATH"),
+            Some("/home/johan/env/bin:/usr/bin:/bin".to_string())
+        );
+        assert_eq!(
+            executor.get_variable("VIRTUAL_ENV"),
+            Some("/home/johan/env".to_string())
+        );
+        assert_eq!(executor.get_variable("PS1"), Some("(env) $ ".to_string()));
+        assert!(executor.has_function("deactivate"));
+
+        let status = run_commandline(&mut executor, "deactivate");
+        assert!(status.is_success());
+        assert_eq!(
+            executor.get_variable("PATH"),
+            Some("/usr/bin:/bin".to_string())
+        );
+        assert_eq!(executor.get_variable("VIRTUAL_ENV"), None);
+        assert_eq!(executor.get_variable("_OLD_VIRTUAL_PATH"), None);
+        assert_eq!(executor.get_variable("PS1"), Some("$ ".to_string()));
+        assert!(!executor.has_function("deactivate"));
+    }
+
+    #[test]
+    fn test_source_errors() {
+        let mut executor = TestExecutor::new();
+        assert_eq!(run_commandline(&mut executor, ".").code, 2);
+        assert_eq!(run_commandline(&mut executor, ". /does/not/exist").code, 1);
+    }
+
+    #[test]
+    fn test_if() {
+        let mut executor = TestExecutor::new();
+        run_commandline(
+            &mut executor,
+            "if false; then echo 1; elif true; then echo 2; else echo 3; fi",
+        );
+        assert_eq!(
+            executor.executions,
+            vec!["exec('false')", "exec('true')", "exec('echo', '2')"]
+        );
+
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "if false; then echo 1; fi");
+        assert!(status.is_success());
+        assert_eq!(executor.executions, vec!["exec('false')"]);
+    }
+
+    #[test]
+    fn test_background() {
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "false && a || b & X=1 &\necho ${X:-unset}");
+        assert!(status.is_success());
+        assert_eq!(
+            executor.executions,
+            vec![
+                "(",
+                "exec('false')",
+                "exec('b')",
+                ") &",
+                "(",
+                ") &",
+                "exec('echo', 'unset')"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        let mut executor = TestExecutor::new();
+        run_commandline(
+            &mut executor,
+            "greet() { echo hello ${1:-nobody}; }\ngreet; greet world; echo \"$1\"",
+        );
+        assert_eq!(
+            executor.executions,
+            vec![
+                "exec('greet')",
+                "exec('echo', 'hello', 'nobody')",
+                "exec('greet', 'world')",
+                "exec('echo', 'hello', 'world')",
+                "exec('echo', '')"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_return() {
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(
+            &mut executor,
+            "f() { echo 1; if true; then return 3; fi; echo 2; }\nf && echo 3; echo 4",
+        );
+        assert!(status.is_success());
+        assert_eq!(
+            executor.executions,
+            vec![
+                "exec('f')",
+                "exec('echo', '1')",
+                "exec('true')",
+                "exec('echo', '4')"
+            ]
+        );
+        assert_eq!(executor.get_variable("?"), Some("0".to_string()));
+
+        // Without an argument, return the status of the last command
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "f() { false; return; }\nf");
+        assert_eq!(status.code, 1);
+
+        // Returning from a subshell doesn't return from the function
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "f() { (return 5); echo $?; }\nf");
+        assert!(status.is_success());
+        assert_eq!(executor.executions.last().unwrap(), "exec('echo', '5')");
+
+        // Not in a function
+        let mut executor = TestExecutor::new();
+        assert_eq!(run_commandline(&mut executor, "return; echo x").code, 0);
+        assert_eq!(executor.executions, vec!["exec('echo', 'x')"]);
+    }
+
+    #[test]
+    fn test_while_until() {
+        let mut executor = TestExecutor::new();
+        executor.set_variable("X", "");
+        let status = run_commandline(
+            &mut executor,
+            "while [ \"$X\" != xx ]; do X=x$X; echo $X; done",
+        );
+        assert!(status.is_success());
+        assert_eq!(executor.get_variable("X"), Some("xx".to_string()));
+        assert_eq!(
+            executor
+                .executions
+                .iter()
+                .filter(|execution| execution.starts_with("exec('echo'"))
+                .collect::<Vec<_>>(),
+            vec!["exec('echo', 'x')", "exec('echo', 'xx')"]
+        );
+
+        // Never running the body is a success
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "until true; do false; done");
+        assert!(status.is_success());
+        assert_eq!(executor.executions, vec!["exec('true')"]);
+    }
+
+    #[test]
+    fn test_for() {
+        let mut executor = TestExecutor::new();
+        run_commandline(&mut executor, "for x in a 'b c'; do echo \"$x\"; done");
+        assert_eq!(
+            executor.executions,
+            vec!["exec('echo', 'a')", "exec('echo', 'b c')"]
+        );
+
+        // Without "in", go over the positional parameters
+        let mut executor = TestExecutor::new();
+        executor.set_positional_parameters(vec!["1".to_string(), "2".to_string()]);
+        run_commandline(&mut executor, "for x do echo $x; done");
+        assert_eq!(
+            executor.executions,
+            vec!["exec('echo', '1')", "exec('echo', '2')"]
+        );
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(
+            &mut executor,
+            "f() { interrupted; echo 1; }\nwhile true; do f && echo 2; echo 3; done; echo 4",
+        );
+        assert_eq!(status.code, 130);
+        assert_eq!(
+            executor.executions,
+            vec!["exec('true')", "exec('f')", "exec('interrupted')"]
+        );
+    }
+
+    #[test]
+    fn test_break_continue() {
+        let mut executor = TestExecutor::new();
+        run_commandline(
+            &mut executor,
+            "for x in 1 2 3; do [ $x = 2 ] && continue; [ $x = 3 ] && break; echo $x; done; echo end",
+        );
+        assert_eq!(
+            executor
+                .executions
+                .iter()
+                .filter(|execution| execution.starts_with("exec('echo'"))
+                .collect::<Vec<_>>(),
+            vec!["exec('echo', '1')", "exec('echo', 'end')"]
+        );
+
+        // Leave more than one loop
+        let mut executor = TestExecutor::new();
+        run_commandline(
+            &mut executor,
+            "for x in 1 2; do for y in a b; do echo $x$y; continue 2; done; done
+            for x in 1 2; do while true; do echo $x; break 5; done; done",
+        );
+        assert_eq!(
+            executor.executions,
+            vec![
+                "exec('echo', '1a')",
+                "exec('echo', '2a')",
+                "exec('true')",
+                "exec('echo', '1')"
+            ]
+        );
+
+        // Not in a loop
+        let mut executor = TestExecutor::new();
+        assert_eq!(run_commandline(&mut executor, "break").code, 1);
+        assert_eq!(
+            run_commandline(
+                &mut executor,
+                "f() { break; }\nwhile true; do f; break; done"
+            )
+            .code,
+            0
+        );
+    }
+
+    #[test]
+    fn test_case() {
+        let mut executor = TestExecutor::new();
+        executor.set_variable("X", "hello.txt");
+        run_commandline(
+            &mut executor,
+            "case $X in *.rs) echo rust;; *.txt|*.md) echo text;; *) echo other;; esac",
+        );
+        assert_eq!(executor.executions, vec!["exec('echo', 'text')"]);
+
+        // Quoted pattern characters match literally
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(
+            &mut executor,
+            "case 'a*' in 'a*'x) false;; \"a*\") echo quoted;; esac",
+        );
+        assert!(status.is_success());
+        assert_eq!(executor.executions, vec!["exec('echo', 'quoted')"]);
+
+        // No match is a success
+        let mut executor = TestExecutor::new();
+        let status = run_commandline(&mut executor, "false; case x in y) ;; esac");
+        assert!(status.is_success());
+    }
+}
@@ -0,0 +1,685 @@
+use std::os::unix::io::RawFd;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, FcntlArg};
+use nix::sys::signal::{killpg, Signal};
+use nix::sys::termios::{tcgetattr, tcsetattr, SetArg, Termios};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::{getpgrp, isatty, tcgetpgrp, tcsetpgrp, Pid};
+
+use crate::exitstatus::ExitStatus;
+use crate::interpreter::ControlFlow;
+use crate::signals::{is_interrupted, take_interrupted};
+use crate::Shell;
+
+const STDIN: RawFd = 0;
+
+/// If we're in the foreground of a terminal, returns a copy of its file
+/// descriptor for handing the terminal over to foreground jobs, otherwise
+/// `None`.
+///
+/// Our own copy stays connected to the terminal whatever redirections do to
+/// stdin, and is closed in the commands we run.
+pub(crate) fn open_terminal() -> Option<RawFd> {
+    if !isatty(STDIN).unwrap_or(false) || tcgetpgrp(STDIN) != Ok(getpgrp()) {
+        return None;
+    }
+
+    // Out of the way of file descriptors users are likely to redirect
+    return fcntl(STDIN, FcntlArg::F_DUPFD_CLOEXEC(10)).ok();
+}
+
+/// One process in a job
+struct Process {
+    pid: Pid,
+
+    /// `None` until the process has exited or been killed
+    status: Option<ExitStatus>,
+
+    /// Set while the process is stopped
+    stopped_by: Option<Signal>,
+}
+
+impl Process {
+    fn is_running(&self) -> bool {
+        return self.status.is_none() && self.stopped_by.is_none();
+    }
+
+    /// Asks the kernel how the process is doing. With `WNOHANG`, returns
+    /// false right away if nothing has changed. Also returns false if a
+    /// signal interrupted the waiting.
+    fn poll(&mut self, flags: WaitPidFlag) -> bool {
+        match waitpid(self.pid, Some(flags)) {
+            Ok(WaitStatus::Exited(_, code)) => self.status = Some(ExitStatus::from_code(code)),
+            Ok(WaitStatus::Signaled(_, signal, false)) => {
+                self.status = Some(ExitStatus::from_signal(signal as i32))
+            }
+            Ok(WaitStatus::Signaled(_, signal, true)) => {
+                self.status = Some(ExitStatus::from_core_dump(signal as i32))
+            }
+            Ok(WaitStatus::Stopped(_, signal)) => self.stopped_by = Some(signal),
+            Ok(WaitStatus::Continued(_)) => self.stopped_by = None,
+            Ok(_) => return false,
+            Err(Errno::EINTR) => return false,
+            Err(error) => {
+                // Nothing more we can learn about this process
+                println!("Awaiting child process failed: {}", error);
+                self.status = Some(ExitStatus::failure(1, &error.to_string()));
+            }
+        }
+        return true;
+    }
+}
+
+/// A pipeline or and-or list running in its own process group, so that it
+/// can be stopped, continued and moved between the foreground and the
+/// background as a unit.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap03.html#tag_03_203
+pub(crate) struct Job {
+    /// The `n` in `%n`, zero until the job is added to the job table
+    id: usize,
+
+    pgid: Pid,
+
+    processes: Vec<Process>,
+
+    /// What the user typed to start this job
+    text: String,
+
+    /// The terminal settings the job had when it was stopped, to restore when
+    /// it's brought back to the foreground
+    terminal_modes: Option<Termios>,
+
+    /// True once the user has been told about the job's current state
+    notified: bool,
+}
+
+impl Job {
+    pub fn new(pgid: Pid, pids: &[Pid], text: &str) -> Self {
+        return Job {
+            id: 0,
+            pgid,
+            processes: pids
+                .iter()
+                .map(|pid| Process {
+                    pid: *pid,
+                    status: None,
+                    stopped_by: None,
+                })
+                .collect(),
+            text: text.to_string(),
+            terminal_modes: None,
+            notified: false,
+        };
+    }
+
+    fn is_done(&self) -> bool {
+        return self
+            .processes
+            .iter()
+            .all(|process| process.status.is_some());
+    }
+
+    fn is_stopped(&self) -> bool {
+        return !self.is_done() && !self.processes.iter().any(Process::is_running);
+    }
+
+    /// One exit status per process. Only meaningful when the job is done or
+    /// stopped.
+    fn statuses(&self) -> Vec<ExitStatus> {
+        return self
+            .processes
+            .iter()
+            .map(|process| match (&process.status, process.stopped_by) {
+                (Some(status), _) => status.clone(),
+                (None, Some(signal)) => ExitStatus::from_signal(signal as i32),
+                (None, None) => ExitStatus::success(),
+            })
+            .collect();
+    }
+
+    /// True if the user pressed ctrl-c at the job
+    fn was_interrupted(&self) -> bool {
+        return self
+            .processes
+            .iter()
+            .filter_map(|process| process.status.as_ref())
+            .any(|status| status.signal == Some(Signal::SIGINT as i32));
+    }
+
+    /// Like for a pipeline, the status of the job is that of its last process
+    fn status(&self) -> ExitStatus {
+        return self.statuses().pop().unwrap_or_else(ExitStatus::success);
+    }
+
+    fn state(&self) -> String {
+        if self.is_done() {
+            let status = self.status();
+            if status.is_success() {
+                return "Done".to_string();
+            }
+            return format!("Done ({})", status.description);
+        }
+        if self.is_stopped() {
+            return "Stopped".to_string();
+        }
+        return "Running".to_string();
+    }
+
+    /// Blocks until the job is done. If `until_stopped` is set, returns early
+    /// if it gets stopped.
+    fn wait(&mut self, until_stopped: bool) {
+        let flags = if until_stopped {
+            WaitPidFlag::WUNTRACED
+        } else {
+            WaitPidFlag::empty()
+        };
+
+        for process in self.processes.iter_mut() {
+            while process.status.is_none() && (process.stopped_by.is_none() || !until_stopped) {
+                process.poll(flags);
+            }
+        }
+    }
+
+    /// Like `wait(true)`, but gives up if the user presses ctrl-c. Returns
+    /// false if that happened.
+    fn wait_interruptibly(&mut self) -> bool {
+        for process in self.processes.iter_mut() {
+            while process.status.is_none() && process.stopped_by.is_none() {
+                if is_interrupted() {
+                    return false;
+                }
+                process.poll(WaitPidFlag::WUNTRACED);
+            }
+        }
+        return true;
+    }
+
+    /// Checks on all processes without blocking
+    fn update(&mut self) {
+        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
+        for process in self.processes.iter_mut() {
+            while process.status.is_none() && process.poll(flags) {
+                self.notified = false;
+            }
+        }
+    }
+
+    /// Sends `SIGCONT` to all processes in the job
+    fn resume(&mut self) -> Result<(), Errno> {
+        killpg(self.pgid, Signal::SIGCONT)?;
+        for process in self.processes.iter_mut() {
+            process.stopped_by = None;
+        }
+        self.notified = false;
+        return Ok(());
+    }
+}
+
+/// Background and stopped jobs, for `jobs`, `fg`, `bg`, `wait` and `disown`
+#[derive(Default)]
+pub(crate) struct Jobs {
+    /// The most recently started, stopped or continued job is last. That's the
+    /// current job, `%+`, and the one before it is the previous job, `%-`.
+    jobs: Vec<Job>,
+}
+
+impl Jobs {
+    /// Returns the job's `%n` number
+    fn add(&mut self, mut job: Job) -> usize {
+        if job.id == 0 {
+            job.id = self.jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        }
+
+        let id = job.id;
+        self.jobs.push(job);
+        return id;
+    }
+
+    /// Finds a job by a job ID like `%2`, `%%`, `%-` or `%sleep`, returning its
+    /// index into `jobs`.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/basedefs/V1_chap03.html#tag_03_204
+    fn find(&self, spec: &str) -> Result<usize, String> {
+        let name = match spec.strip_prefix('%') {
+            Some(name) => name,
+            None => return Err(format!("Not a job ID: {}", spec)),
+        };
+
+        let found: Vec<usize> = match name {
+            "" | "%" | "+" => (0..self.jobs.len()).rev().take(1).collect(),
+            "-" => (0..self.jobs.len()).rev().skip(1).take(1).collect(),
+            _ if name.chars().all(|c| c.is_ascii_digit()) => (0..self.jobs.len())
+                .filter(|index| self.jobs[*index].id.to_string() == name)
+                .collect(),
+            _ => {
+                let (substring, name) = match name.strip_prefix('?') {
+                    Some(name) => (true, name),
+                    None => (false, name),
+                };
+                (0..self.jobs.len())
+                    .filter(|index| {
+                        let text = &self.jobs[*index].text;
+                        if substring {
+                            return text.contains(name);
+                        }
+                        return text.starts_with(name);
+                    })
+                    .collect()
+            }
+        };
+
+        match found.len() {
+            0 => return Err(format!("No such job: {}", spec)),
+            1 => return Ok(found[0]),
+            _ => return Err(format!("Ambiguous job ID: {}", spec)),
+        }
+    }
+
+    /// Finds a job and removes it from the table
+    fn take(&mut self, spec: &str) -> Result<Job, String> {
+        let index = self.find(spec)?;
+        return Ok(self.jobs.remove(index));
+    }
+
+    /// Finds the job with a process with this ID
+    fn find_pid(&self, pid: Pid) -> Option<usize> {
+        return self.jobs.iter().position(|job| {
+            return job.processes.iter().any(|process| process.pid == pid);
+        });
+    }
+
+    /// Like `[1]+  Running                 sleep 5 &`
+    fn describe(&self, index: usize, with_pgid: bool) -> String {
+        let job = &self.jobs[index];
+        let marker = if index + 1 == self.jobs.len() {
+            '+'
+        } else if index + 2 == self.jobs.len() {
+            '-'
+        } else {
+            ' '
+        };
+
+        let pgid = if with_pgid {
+            format!("{} ", job.pgid)
+        } else {
+            "".to_string()
+        };
+
+        let mut text = job.text.clone();
+        if !job.is_done() && !job.is_stopped() {
+            text.push_str(" &");
+        }
+
+        return format!(
+            "[{}]{}  {}{:<24}{}",
+            job.id,
+            marker,
+            pgid,
+            job.state(),
+            text
+        );
+    }
+
+    /// Indices of all jobs, ordered by job number
+    fn by_id(&self) -> Vec<usize> {
+        let mut indices: Vec<usize> = (0..self.jobs.len()).collect();
+        indices.sort_by_key(|index| self.jobs[*index].id);
+        return indices;
+    }
+
+    /// The job IDs of all jobs, like `%1`, each with the job's command line
+    pub fn ids(&self) -> Vec<(String, String)> {
+        return self
+            .jobs
+            .iter()
+            .map(|job| (format!("%{}", job.id), job.text.to_string()))
+            .collect();
+    }
+
+    /// Checks on all jobs without blocking
+    pub fn update(&mut self) {
+        for job in self.jobs.iter_mut() {
+            job.update();
+        }
+    }
+
+    /// Tells the user about jobs that have stopped or finished since last
+    /// time, and forgets about the finished ones.
+    pub fn notify(&mut self) {
+        for index in self.by_id() {
+            let job = &self.jobs[index];
+            if job.notified || !(job.is_done() || job.is_stopped()) {
+                continue;
+            }
+
+            println!("{}", self.describe(index, false));
+            self.jobs[index].notified = true;
+        }
+
+        self.jobs.retain(|job| !job.is_done());
+    }
+}
+
+impl Shell {
+    /// Waits for a job running in the foreground, handing it the terminal
+    /// while it runs.
+    ///
+    /// If the job gets stopped, it's added to the job table and we return
+    /// right away. If it gets interrupted by ctrl-c, so does the rest of the
+    /// command line. Returns one exit status per process in the job.
+    pub(crate) fn wait_for_foreground(&mut self, mut job: Job) -> Vec<ExitStatus> {
+        let terminal = match self.terminal {
+            Some(terminal) => terminal,
+            None => {
+                // Stopping is for the shell that owns the job
+                job.wait(false);
+                if job.was_interrupted() {
+                    self.interpreter_state.control_flow = ControlFlow::Interrupt;
+                }
+                return job.statuses();
+            }
+        };
+
+        if let Some(modes) = &job.terminal_modes {
+            let _ = tcsetattr(terminal, SetArg::TCSADRAIN, modes);
+        }
+        let _ = tcsetpgrp(terminal, job.pgid);
+
+        job.wait(true);
+
+        if let Err(error) = tcsetpgrp(terminal, getpgrp()) {
+            println!("WARNING: Failed to take back the terminal: {}", error);
+        }
+
+        // If the job was stopped or killed in the middle of messing with the
+        // terminal, we want our settings back
+        if job.is_stopped() || job.status().code > 128 {
+            job.terminal_modes = tcgetattr(terminal).ok();
+            if let Some(modes) = &self.terminal_modes {
+                let _ = tcsetattr(terminal, SetArg::TCSADRAIN, modes);
+            }
+        }
+
+        if job.was_interrupted() {
+            self.interpreter_state.control_flow = ControlFlow::Interrupt;
+        }
+
+        let statuses = job.statuses();
+        if job.is_stopped() {
+            // Get past the ^Z
+            println!();
+
+            job.notified = true;
+            self.jobs.add(job);
+            println!("{}", self.jobs.describe(self.jobs.jobs.len() - 1, false));
+        }
+
+        return statuses;
+    }
+
+    /// Adds a job we just started in the background to the job table
+    pub(crate) fn add_background_job(&mut self, job: Job) {
+        let pid = job.processes[0].pid;
+        let id = self.jobs.add(job);
+        if self.terminal.is_some() {
+            println!("[{}] {}", id, pid);
+        }
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/jobs.html
+    pub(crate) fn list_jobs(&mut self, args: &[String]) -> String {
+        let mut with_pgid = false;
+        let mut pgids_only = false;
+        let mut specs: Vec<&String> = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                "-l" => with_pgid = true,
+                "-p" => pgids_only = true,
+                _ => specs.push(arg),
+            }
+        }
+
+        self.jobs.update();
+
+        let mut indices: Vec<usize> = Vec::new();
+        let mut problem = "".to_string();
+        if specs.is_empty() {
+            indices = self.jobs.by_id();
+        }
+        for spec in specs {
+            match self.jobs.find(spec) {
+                Ok(index) => indices.push(index),
+                Err(message) => {
+                    println!("ERROR: jobs: {}", message);
+                    problem = message;
+                }
+            }
+        }
+
+        for index in indices {
+            if pgids_only {
+                println!("{}", self.jobs.jobs[index].pgid);
+            } else {
+                println!("{}", self.jobs.describe(index, with_pgid));
+            }
+            self.jobs.jobs[index].notified = true;
+        }
+        self.jobs.jobs.retain(|job| !job.is_done());
+
+        return problem;
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/fg.html
+    pub(crate) fn fg(&mut self, args: &[String]) -> ExitStatus {
+        if args.len() > 1 {
+            println!("ERROR: fg: Expected at most one job ID");
+            return ExitStatus::failure(2, "Usage");
+        }
+
+        let spec = args.first().map(String::as_str).unwrap_or("%+");
+        let mut job = match self.jobs.take(spec) {
+            Ok(job) => job,
+            Err(message) => {
+                println!("ERROR: fg: {}", message);
+                return ExitStatus::failure(1, &message);
+            }
+        };
+
+        println!("{}", job.text);
+        if let Some(terminal) = self.terminal {
+            // Before continuing, or it would just get stopped again for
+            // touching the terminal
+            let _ = tcsetpgrp(terminal, job.pgid);
+        }
+        if let Err(error) = job.resume() {
+            println!("ERROR: fg: Failed to continue job: {}", error);
+        }
+
+        let statuses = self.wait_for_foreground(job);
+        return statuses.last().unwrap().clone();
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/bg.html
+    pub(crate) fn bg(&mut self, args: &[String]) -> String {
+        let specs: Vec<&str> = if args.is_empty() {
+            vec!["%+"]
+        } else {
+            args.iter().map(String::as_str).collect()
+        };
+
+        let mut problem = "".to_string();
+        for spec in specs {
+            let mut job = match self.jobs.take(spec) {
+                Ok(job) => job,
+                Err(message) => {
+                    println!("ERROR: bg: {}", message);
+                    problem = message;
+                    continue;
+                }
+            };
+
+            if let Err(error) = job.resume() {
+                println!("ERROR: bg: Failed to continue job: {}", error);
+                problem = error.to_string();
+            }
+
+            // Continuing makes this the current job
+            self.jobs.add(job);
+            let index = self.jobs.jobs.len() - 1;
+            println!("{}", self.jobs.describe(index, false));
+            self.jobs.jobs[index].notified = true;
+        }
+
+        return problem;
+    }
+
+    /// Waits for jobs given as job IDs or process IDs, or for all jobs if
+    /// there are no arguments.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/wait.html
+    pub(crate) fn wait(&mut self, args: &[String]) -> ExitStatus {
+        // Stopped jobs would otherwise have us waiting forever
+        if args.is_empty() {
+            let interrupted = !self.jobs.jobs.iter_mut().all(Job::wait_interruptibly);
+            self.jobs.jobs.retain(|job| !job.is_done());
+            if interrupted {
+                return self.interrupt_wait();
+            }
+            return ExitStatus::success();
+        }
+
+        let mut status = ExitStatus::success();
+        for arg in args {
+            let index = if arg.starts_with('%') {
+                match self.jobs.find(arg) {
+                    Ok(index) => Some(index),
+                    Err(message) => {
+                        println!("ERROR: wait: {}", message);
+                        None
+                    }
+                }
+            } else {
+                match arg.parse::<i32>() {
+                    Ok(pid) => self.jobs.find_pid(Pid::from_raw(pid)),
+                    Err(_) => {
+                        println!("ERROR: wait: Not a process or job ID: {}", arg);
+                        None
+                    }
+                }
+            };
+
+            // POSIX says unknown processes have already exited with 127
+            status = ExitStatus::failure(127, "Unknown job");
+            if let Some(index) = index {
+                let job = &mut self.jobs.jobs[index];
+                if !job.wait_interruptibly() {
+                    return self.interrupt_wait();
+                }
+                status = job.status();
+                if job.is_done() {
+                    self.jobs.jobs.remove(index);
+                }
+            }
+        }
+
+        return status;
+    }
+
+    /// Ctrl-c during `wait` stops the waiting, and like other shells we then
+    /// return the status of a command killed by SIGINT
+    fn interrupt_wait(&mut self) -> ExitStatus {
+        take_interrupted();
+        self.interpreter_state.control_flow = ControlFlow::Interrupt;
+        return ExitStatus::from_signal(Signal::SIGINT as i32);
+    }
+
+    /// Forgets about jobs without touching them
+    pub(crate) fn disown(&mut self, args: &[String]) -> String {
+        let specs: Vec<&str> = if args.is_empty() {
+            vec!["%+"]
+        } else {
+            args.iter().map(String::as_str).collect()
+        };
+
+        let mut problem = "".to_string();
+        for spec in specs {
+            if let Err(message) = self.jobs.take(spec) {
+                println!("ERROR: disown: {}", message);
+                problem = message;
+            }
+        }
+
+        return problem;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn jobs_from(texts: &[&str]) -> Jobs {
+        let mut jobs = Jobs::default();
+        for (index, text) in texts.iter().enumerate() {
+            let pid = Pid::from_raw(1000 + index as i32);
+            jobs.add(Job::new(pid, &[pid], text));
+        }
+        return jobs;
+    }
+
+    #[test]
+    fn test_find() {
+        let jobs = jobs_from(&["sleep 5", "vi x", "sleep 7"]);
+        assert_eq!(jobs.find("%2"), Ok(1));
+        assert_eq!(jobs.find("%%"), Ok(2));
+        assert_eq!(jobs.find("%+"), Ok(2));
+        assert_eq!(jobs.find("%"), Ok(2));
+        assert_eq!(jobs.find("%-"), Ok(1));
+        assert_eq!(jobs.find("%vi"), Ok(1));
+        assert_eq!(jobs.find("%?7"), Ok(2));
+
+        assert_eq!(jobs.find("%4"), Err("No such job: %4".to_string()));
+        assert_eq!(jobs.find("%emacs"), Err("No such job: %emacs".to_string()));
+        assert_eq!(
+            jobs.find("%sleep"),
+            Err("Ambiguous job ID: %sleep".to_string())
+        );
+        assert_eq!(jobs.find("2"), Err("Not a job ID: 2".to_string()));
+
+        assert_eq!(
+            Jobs::default().find("%%"),
+            Err("No such job: %%".to_string())
+        );
+        assert_eq!(
+            jobs_from(&["a"]).find("%-"),
+            Err("No such job: %-".to_string())
+        );
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut jobs = jobs_from(&["sleep 5", "vi x", "sleep 7"]);
+        jobs.jobs[1].processes[0].stopped_by = Some(Signal::SIGTSTP);
+        jobs.jobs[2].processes[0].status = Some(ExitStatus::from_code(1));
+
+        assert_eq!(
+            jobs.describe(0, false),
+            "[1]   Running                 sleep 5 &"
+        );
+        assert_eq!(
+            jobs.describe(1, false),
+            "[2]-  Stopped                 vi x"
+        );
+        assert_eq!(
+            jobs.describe(2, true),
+            "[3]+  1002 Done (1)                sleep 7"
+        );
+
+        // Job numbers keep increasing while there are jobs
+        jobs.jobs.remove(1);
+        let pid = Pid::from_raw(1003);
+        assert_eq!(jobs.add(Job::new(pid, &[pid], "x")), 4);
+    }
+}
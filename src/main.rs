@@ -1,44 +1,160 @@
 #![allow(clippy::needless_return)]
 
+use std::collections::HashMap;
 use std::env;
 use std::fs;
 use std::io;
 use std::io::Write;
-use std::os::unix::prelude::ExitStatusExt;
+use std::os::unix::io::RawFd;
+use std::os::unix::prelude::CommandExt;
 use std::path::PathBuf;
 use std::process::Command;
+use std::rc::Rc;
 
+use nix::sys::signal::Signal;
+use nix::sys::termios::{tcgetattr, Termios};
+use nix::unistd::{getpgrp, setpgid, tcsetpgrp, Pid};
 use rustyline::error::ReadlineError;
 
 use crate::ansicolor::{green, red};
-use crate::parser::{parse, Executor};
+use crate::completion::ShellCompleter;
+use crate::exitstatus::{describe_pipestatus, ExitStatus};
+use crate::expansion::expand;
+use crate::interpreter::{call_function, run, source, ControlFlow, Executor, InterpreterState};
+use crate::jobs::{open_terminal, Job, Jobs};
+use crate::parser::parse;
+use crate::readlinehelper::ReadlineHelper;
+use crate::signals::{
+    ignore_shell_signals, restore_default_signal_handlers, take_child_status_changed,
+    take_interrupted,
+};
+use crate::variables::Variables;
 
 mod ansicolor;
+mod arithmetic;
+mod ast;
+mod bashcompletion;
 mod cd;
+mod completers;
+mod completion;
+mod diagnostics;
+mod exitstatus;
+mod expansion;
+mod export;
+mod globbing;
+mod interpreter;
+mod jobs;
 mod parser;
+mod pattern;
+mod pipeline;
+mod readlinehelper;
+mod redirection;
+mod signals;
+mod subshell;
+mod testcommand;
+#[cfg(test)]
+mod testdir;
+#[cfg(test)]
+mod testexecutor;
 mod tokenizer;
+mod variables;
 
 struct Shell {
     oldpwd: PathBuf,
 
     /// Ref: https://crates.io/crates/rustyline/#user-content-example
-    readline: rustyline::Editor<()>,
+    readline: rustyline::Editor<ReadlineHelper>,
+
+    /// If set, the line editor history is saved when we're dropped
+    save_history: bool,
 
     /// Contains an exit status in string form, or a signal name, or the empty
     /// string if the last command succeeded.
     ///
     /// Will be displayed as part of the prompt.
     last_command_exit_description: String,
+
+    /// The exit statuses of all commands in the last pipeline we ran
+    last_pipestatus: Vec<ExitStatus>,
+
+    /// Exit code of the last pipeline, for `$?`
+    last_exit_code: i32,
+
+    /// Our process ID, for `$$`. Note that this should not change in forked
+    /// children.
+    pid: u32,
+
+    /// Set while running the last command of a forked child. If set, an
+    /// external command will replace the current process rather than being
+    /// spawned as a child process.
+    exec_in_place: bool,
+
+    /// All shell variables. The exported ones are the environment of the
+    /// commands we run.
+    variables: Variables,
+
+    /// Shell functions by name
+    functions: HashMap<String, Rc<ast::Command>>,
+
+    /// `$1`, `$2` and so on
+    positional_parameters: Vec<String>,
+
+    /// See `Executor::state()`
+    interpreter_state: InterpreterState,
+
+    /// Set if we do job control, putting jobs in process groups of their own
+    /// and letting the user stop and continue them. Only done by the
+    /// interactive shell, never in forked children.
+    ///
+    /// See `open_terminal()`.
+    terminal: Option<RawFd>,
+
+    /// Background and stopped jobs
+    jobs: Jobs,
+
+    /// Describes the pipeline we're currently running, in case the user stops
+    /// it
+    foreground_text: String,
+
+    /// Terminal settings to go back to if a job stops or dies while messing
+    /// with them
+    terminal_modes: Option<Termios>,
+
+    /// Process ID of the last background job, for `$!`
+    last_background_pid: Option<Pid>,
 }
 
-/// Loads history if we have any
-fn create_readline() -> rustyline::Editor<()> {
-    let mut readline = rustyline::Editor::<()>::new();
+/// Builtins where the assignments in front of them stay in effect after the
+/// builtin is done, as in `FOO=1 export BAR`.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_14
+static SPECIAL_BUILTINS: [&str; 3] = [".", "export", "unset"];
+
+/// All builtins, see `run_builtin()`
+pub(crate) static BUILTINS: [&str; 14] = [
+    "cd", "export", "unset", "local", ".", "source", "[", "test", "jobs", "fg", "bg", "wait",
+    "disown", "hash",
+];
+
+fn create_readline() -> rustyline::Editor<ReadlineHelper> {
+    // List the candidates like bash does, rather than cycling through them
+    let config = rustyline::Config::builder()
+        .completion_type(rustyline::CompletionType::List)
+        .build();
+    let mut readline = rustyline::Editor::<ReadlineHelper>::with_config(config);
+    readline.set_helper(Some(ReadlineHelper {
+        completer: ShellCompleter::new(),
+    }));
 
+    return readline;
+}
+
+/// Loads history if we have any
+fn load_history(readline: &mut rustyline::Editor<ReadlineHelper>) {
     let maybe_homedir = env::var_os("HOME");
-    if maybe_homedir == None {
+    if maybe_homedir.is_none() {
         println!("WARNING: Home directory not set, cannot load history");
-        return readline;
+        return;
     }
 
     let mut history_path = PathBuf::from(maybe_homedir.unwrap());
@@ -51,17 +167,11 @@ fn create_readline() -> rustyline::Editor<()> {
             );
         }
     }
-
-    return readline;
 }
 
-/// Save history
-fn drop_readline(readline: &mut rustyline::Editor<()>) {
-    // FIXME: Take this value from some internal HOME variable? So if the user
-    // sets HOME to some value while inside of the shell that's the HOME we'll
-    // use for saving?
-    let maybe_homedir = env::var_os("HOME");
-    if maybe_homedir == None {
+/// Save history into `$HOME/.vpixsh/history`, using our own value for `HOME`
+fn drop_readline(readline: &mut rustyline::Editor<ReadlineHelper>, maybe_homedir: Option<String>) {
+    if maybe_homedir.is_none() {
         println!("WARNING: Home directory not set, cannot save history");
         return;
     }
@@ -90,6 +200,16 @@ fn drop_readline(readline: &mut rustyline::Editor<()>) {
 
 impl Shell {
     fn new() -> Self {
+        let mut shell = Shell::without_terminal();
+        load_history(&mut shell.readline);
+        shell.save_history = true;
+        shell.terminal = open_terminal();
+        return shell;
+    }
+
+    /// A shell that doesn't do job control and doesn't touch the history
+    /// file, as needed by tests. `new()` sets up the rest.
+    fn without_terminal() -> Self {
         let mut current_dir = PathBuf::new();
         let current_dir_result = env::current_dir();
         if let Err(error) = current_dir_result {
@@ -100,15 +220,103 @@ impl Shell {
             current_dir = current_dir_result.unwrap();
         }
 
+        let mut variables = Variables::from_environment();
+
+        // Color BSD "ls" output.
+        // FIXME: This isn't very generic. Maybe put this in the default config
+        // file with an associated comment?
+        if variables.get("CLICOLOR").is_none() {
+            variables.set("CLICOLOR", "1");
+            variables.export("CLICOLOR");
+        }
+
+        // Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_03
+        if variables.get("PS1").is_none() {
+            if nix::unistd::geteuid().is_root() {
+                variables.set("PS1", "# ");
+            } else {
+                variables.set("PS1", "$ ");
+            }
+        }
+        if variables.get("PS2").is_none() {
+            variables.set("PS2", "> ");
+        }
+
         return Shell {
             oldpwd: current_dir,
             readline: create_readline(),
+            save_history: false,
             last_command_exit_description: "".to_string(),
+            last_pipestatus: Vec::new(),
+            last_exit_code: 0,
+            pid: std::process::id(),
+            exec_in_place: false,
+            variables,
+            functions: HashMap::new(),
+            positional_parameters: Vec::new(),
+            interpreter_state: InterpreterState::default(),
+            terminal: None,
+            jobs: Jobs::default(),
+            foreground_text: "".to_string(),
+            terminal_modes: None,
+            last_background_pid: None,
         };
     }
 
+    /// Describes how the last command line went, for the prompt
+    fn describe_exit_status(&self, status: &ExitStatus) -> String {
+        // FIXME: Get this setting from some config file
+        let show_pipestatus = self.variables.get("VPIXSH_PIPESTATUS").is_some();
+        if !show_pipestatus || self.last_pipestatus.len() < 2 {
+            return status.description.to_string();
+        }
+        return describe_pipestatus(&self.last_pipestatus);
+    }
+
+    /// Returns the value of a prompt variable like `PS1`. Prompts are subject
+    /// to parameter expansion, if that fails the prompt is shown as-is.
+    fn expand_prompt(&mut self, name: &str) -> String {
+        let prompt = self.variables.get(name).unwrap_or_default().to_string();
+        return expand(self, &prompt).unwrap_or(prompt);
+    }
+
+    /// Reads a command line. For as long as what we have is incomplete, like
+    /// after a trailing `|` or inside of quotes, more lines are read using
+    /// the `PS2` prompt.
+    ///
+    /// FIXME: rustyline's `Validator` would let the user edit all lines as one
+    /// buffer, but as of rustyline 9 it can't show a continuation prompt.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_03
+    fn read_commandline(&mut self, prompt: &str) -> Result<String, ReadlineError> {
+        let mut commandline = self.readline.readline(prompt)?;
+        while matches!(parse(&commandline), Err(error) if error.incomplete) {
+            let ps2 = self.expand_prompt("PS2");
+            match self.readline.readline(&ps2) {
+                Ok(line) => {
+                    commandline.push('\n');
+                    commandline.push_str(&line);
+                }
+                Err(ReadlineError::Eof) => {
+                    // Let the parse error tell the user what was missing
+                    println!();
+                    break;
+                }
+                Err(error) => return Err(error),
+            }
+        }
+
+        return Ok(commandline);
+    }
+
     fn run(&mut self) {
         loop {
+            if take_child_status_changed() {
+                self.jobs.update();
+            }
+            self.jobs.notify();
+            self.update_completer();
+
             // FIXME: Print a colorful prompt with VCS info when available
             println!();
             match env::current_dir() {
@@ -124,25 +332,36 @@ impl Shell {
                 );
             }
 
-            // FIXME: The "$" should be a "#" if we're root
-            let prompt = format!("{}$ ", error_prefix);
+            let prompt = format!("{}{}", error_prefix, self.expand_prompt("PS1"));
 
             // Flush our prompt so the user can see it, necessary since the prompt
             // doesn't end with a newline
             io::stdout().flush().unwrap();
 
-            // Read a line from stdin
-            match self.readline.readline(&prompt) {
+            // Read a command line from stdin
+            match self.read_commandline(&prompt) {
                 Ok(line) => {
                     self.readline.add_history_entry(&line);
-                    if let Err(error) = parse(&line, self) {
-                        println!("Parse error: {}", error);
+                    match parse(&line) {
+                        Ok(parsed) => {
+                            if let Some(terminal) = self.terminal {
+                                self.terminal_modes = tcgetattr(terminal).ok();
+                            }
+
+                            // Ctrl-c from before this command line is no reason
+                            // to stop running it
+                            take_interrupted();
+
+                            let status = run(self, &parsed.program);
+                            self.interpreter_state.control_flow = ControlFlow::Normal;
+                            self.last_command_exit_description = self.describe_exit_status(&status);
+                        }
+                        Err(error) => println!("Parse error: {}", error),
                     }
                 }
                 Err(ReadlineError::Interrupted) => {
-                    // FIXME: How should we handle this?
-                    println!("CTRL-C");
-                    break;
+                    // Forget about the current line and start over
+                    continue;
                 }
                 Err(ReadlineError::Eof) => {
                     // EOF, seeya!
@@ -157,72 +376,249 @@ impl Shell {
         }
     }
 
-    /// Returns an error message for the prompt, or "" on success
-    fn do_execute(&mut self, executable: &str, args: &[String]) -> String {
-        let mut command_with_args = vec![executable.to_string()];
-
-        let mut command = Command::new(executable);
+    /// Returns `None` if `executable` isn't a builtin
+    fn run_builtin(&mut self, executable: &str, args: &[String]) -> Option<ExitStatus> {
+        let problem = match executable {
+            "cd" => self.cd(args),
+            "export" => self.export(args),
+            "unset" => self.unset(args),
+            "local" => self.local(args),
+            "." | "source" => return Some(source(self, args)),
+            "[" | "test" => return Some(testcommand::test(executable, args)),
+            "jobs" => self.list_jobs(args),
+            "fg" => return Some(self.fg(args)),
+            "bg" => self.bg(args),
+            "wait" => return Some(self.wait(args)),
+            "disown" => self.disown(args),
+
+            "hash" => self.hash(),
+
+            _ => return None,
+        };
 
-        // Color BSD "ls" output.
-        // FIXME: This isn't very generic. Maybe put this in the default config
-        // file with an associated comment?
-        command.env("CLICOLOR", "1");
+        if problem.is_empty() {
+            return Some(ExitStatus::success());
+        }
+        return Some(ExitStatus::failure(1, &problem));
+    }
 
-        for arg in args {
-            command_with_args.push(arg.to_string());
-            command.arg(arg);
+    fn do_execute(
+        &mut self,
+        executable: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus {
+        if SPECIAL_BUILTINS.contains(&executable) {
+            for (name, value) in assignments {
+                self.variables.set(name, value);
+            }
+            return self.run_builtin(executable, args).unwrap();
         }
 
-        if executable == "cd" {
-            return self.cd(args);
+        let saved = self.variables.set_temporarily(assignments);
+        let status = if let Some(body) = self.functions.get(executable).cloned() {
+            // The function may run any number of commands, none of which
+            // should replace this process
+            self.exec_in_place = false;
+            self.variables.push_scope();
+            let status = call_function(self, &body, args);
+            self.variables.pop_scope();
+            status
+        } else {
+            match self.run_builtin(executable, args) {
+                Some(status) => status,
+                None => self.spawn(executable, args),
+            }
+        };
+        self.variables.restore(saved);
+
+        return status;
+    }
+
+    fn spawn(&mut self, executable: &str, args: &[String]) -> ExitStatus {
+        let exec_in_place = std::mem::take(&mut self.exec_in_place);
+
+        let mut command = Command::new(executable);
+        command.env_clear();
+        command.envs(self.variables.environment());
+
+        command.args(args);
+
+        let terminal = self.terminal;
+        unsafe {
+            command.pre_exec(move || {
+                if let Some(terminal) = terminal {
+                    // A job of its own, done both here and in the parent to
+                    // avoid races
+                    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+                    let _ = tcsetpgrp(terminal, getpgrp());
+                }
+                restore_default_signal_handlers();
+                return Ok(());
+            });
         }
 
-        println!("About to do: exec('{}')", command_with_args.join("', '"));
-        let exec_result = command.spawn();
+        let exec_result = if exec_in_place {
+            // Only returns on failure
+            Err(command.exec())
+        } else {
+            command.spawn()
+        };
         if let Err(error) = exec_result {
+            if let Some(terminal) = terminal {
+                // The child may have taken the terminal before failing to exec
+                let _ = tcsetpgrp(terminal, getpgrp());
+            }
+
             if let Some(os_error) = error.raw_os_error() {
                 if os_error == 2 {
                     // "2" == ENOENT
-                    return "Not found".to_string();
+                    return ExitStatus::failure(127, "Not found");
                 }
             }
-            return error.to_string();
+            return ExitStatus::failure(126, &error.to_string());
         }
 
-        let mut child = exec_result.unwrap();
-        let wait_result = child.wait();
-        if let Err(error) = wait_result {
-            println!("Awaiting child process failed: {}", error);
-            return error.to_string();
+        let pid = Pid::from_raw(exec_result.unwrap().id() as i32);
+        let mut pgid = getpgrp();
+        if terminal.is_some() {
+            let _ = setpgid(pid, pid);
+            pgid = pid;
         }
 
-        let exit_status = wait_result.unwrap();
-        if exit_status.success() {
-            return "".to_string();
-        } else if let Some(signal) = exit_status.signal() {
-            // FIXME: Present pretty signal names when available
-            return format!("SIG{}", signal);
-        } else if let Some(exitcode) = exit_status.code() {
-            return format!("{}", exitcode);
-        } else {
-            return format!("{}", exit_status);
-        }
+        let text = self.foreground_text.clone();
+        let statuses = self.wait_for_foreground(Job::new(pgid, &[pid], &text));
+        return statuses.last().unwrap().clone();
     }
 }
 
 impl Drop for Shell {
     fn drop(&mut self) {
-        drop_readline(&mut self.readline);
+        if !self.save_history {
+            return;
+        }
+
+        let maybe_homedir = self.variables.get("HOME").map(str::to_string);
+        drop_readline(&mut self.readline, maybe_homedir);
     }
 }
 
 impl Executor for Shell {
-    fn execute(&mut self, executable: &str, args: &[String]) {
-        self.last_command_exit_description = self.do_execute(executable, args);
+    fn execute(
+        &mut self,
+        executable: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus {
+        return self.do_execute(executable, args, assignments);
+    }
+
+    fn execute_in_place(
+        &mut self,
+        executable: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus {
+        self.exec_in_place = true;
+        let status = self.do_execute(executable, args, assignments);
+        self.exec_in_place = false;
+        return status;
+    }
+
+    fn execute_pipeline(&mut self, pipeline: &ast::Pipeline) -> Vec<ExitStatus> {
+        if take_interrupted() {
+            // Ctrl-c while we were busy running builtins
+            self.interpreter_state.control_flow = ControlFlow::Interrupt;
+            return vec![ExitStatus::from_signal(Signal::SIGINT as i32)];
+        }
+
+        self.last_pipestatus = self.run_pipeline(pipeline);
+        return self.last_pipestatus.clone();
+    }
+
+    fn execute_background(&mut self, and_or: &ast::AndOr) -> ExitStatus {
+        return self.run_background(and_or);
+    }
+
+    fn redirect(
+        &mut self,
+        redirects: &[redirection::Redirect],
+    ) -> Result<Vec<redirection::SavedFd>, ExitStatus> {
+        return redirection::redirect(redirects);
+    }
+
+    fn restore(&mut self, saved: Vec<redirection::SavedFd>) {
+        redirection::restore(saved);
+    }
+
+    fn get_variable(&self, name: &str) -> Option<String> {
+        // Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_02
+        match name {
+            "?" => return Some(self.last_exit_code.to_string()),
+            "$" => return Some(self.pid.to_string()),
+            "!" => return self.last_background_pid.map(|pid| pid.to_string()),
+            "0" => return Some(env::args().next().unwrap_or_else(|| "vpixsh".to_string())),
+
+            "#" => return Some(self.positional_parameters.len().to_string()),
+            "@" | "*" => return Some(self.positional_parameters.join(" ")),
+            _ => {}
+        }
+
+        if name.chars().all(|c| c.is_ascii_digit()) {
+            // Positional parameter, numbered from 1
+            let index = name.parse::<usize>().ok()?;
+            return self
+                .positional_parameters
+                .get(index.checked_sub(1)?)
+                .cloned();
+        }
+
+        return self.variables.get(name).map(str::to_string);
+    }
+
+    fn set_variable(&mut self, name: &str, value: &str) {
+        self.variables.set(name, value);
+    }
+
+    fn set_exit_status(&mut self, status: &ExitStatus) {
+        self.last_exit_code = status.code;
+    }
+
+    fn execute_subshell(&mut self, program: &ast::List) -> ExitStatus {
+        return self.run_subshell(program);
+    }
+
+    fn capture(&mut self, program: &ast::List) -> (String, ExitStatus) {
+        return self.capture_output(program);
+    }
+
+    fn define_function(&mut self, name: &str, body: Rc<ast::Command>) {
+        self.functions.insert(name.to_string(), body);
+    }
+
+    fn set_positional_parameters(&mut self, parameters: Vec<String>) -> Vec<String> {
+        return std::mem::replace(&mut self.positional_parameters, parameters);
+    }
+
+    fn get_oldpwd(&self) -> Option<String> {
+        if self.oldpwd.as_os_str().is_empty() {
+            return None;
+        }
+        return Some(self.oldpwd.to_string_lossy().to_string());
+    }
+
+    fn get_positional_parameters(&self) -> Vec<String> {
+        return self.positional_parameters.clone();
+    }
+
+    fn state(&mut self) -> &mut InterpreterState {
+        return &mut self.interpreter_state;
     }
 }
 
 fn main() {
+    ignore_shell_signals();
+
     let mut shell = Shell::new();
     shell.run();
 }
@@ -1,109 +1,949 @@
-use crate::tokenizer::{to_tokens, TokenizerError};
+use std::fmt::Display;
+use std::rc::Rc;
 
-pub(crate) trait Executor {
-    /// command is the binary to executs
-    ///
-    /// argv is all the command line arguments. argv does *not* include the
-    /// command itself, and will be empty if no arguments are required.
-    fn execute(&mut self, command: &str, args: &[String]);
+use nom::Slice;
+use nom_locate::LocatedSpan;
+
+use crate::ast::{
+    AndOr, AndOrOperator, Assignment, CaseItem, Command, CompoundCommand, FunctionDefinition, List,
+    Pipeline, Redirection, RedirectionOperator, Separator, SimpleCommand, Word,
+};
+use crate::diagnostics::render;
+use crate::expansion::is_name;
+use crate::tokenizer::{to_tokens, Token, TokenizerError};
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct ParseError<'a> {
+    /// The whole command line, for showing the error in context
+    pub commandline: &'a str,
+
+    /// The part of the command line that we failed to parse
+    pub text: LocatedSpan<&'a str, ()>,
+    pub message: String,
+
+    /// A hint on how to fix the problem, shown next to the `^^^` markers
+    pub help: Option<String>,
+
+    /// True if the command line ended too early, like after an opening quote
+    /// or a trailing `|`. More input could make such a command line valid.
+    pub incomplete: bool,
 }
 
-/// Returns a string of the same length as the command line, containing
-/// highlighting information.
-///
-/// # Highlighting codes
-/// * `0` Executable command
-/// * `a` First argument, third, fifth etc...
-/// * `A` Second argument, fourth, sixth etc...
-/// * `c` Comment
-/// * `x` Operator
-pub(crate) fn parse<'a>(
+impl<'a> Display for ParseError<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&render(
+            self.commandline,
+            self.text.location_offset(),
+            self.text.fragment().len(),
+            &self.message,
+            self.help.as_deref(),
+        ))
+    }
+}
+
+impl<'a> From<TokenizerError<'a>> for ParseError<'a> {
+    fn from(error: TokenizerError<'a>) -> Self {
+        return ParseError {
+            commandline: error.commandline,
+            text: error.token.text,
+            message: error.message,
+            help: error.help,
+
+            // All tokenizer errors are about something not being terminated
+            incomplete: true,
+        };
+    }
+}
+
+/// The result of parsing a command line
+#[derive(Debug)]
+pub(crate) struct Parsed {
+    pub program: List,
+}
+
+struct Parser<'a> {
     commandline: &'a str,
-    executor: &'a mut dyn Executor,
-) -> Result<String, TokenizerError<'a>> {
-    let tokens_result = to_tokens(commandline);
-    if let Err(error) = tokens_result {
-        return Err(error);
+
+    /// All tokens except comments
+    tokens: Vec<Token<'a>>,
+
+    /// Index into `tokens` of the next token to parse
+    position: usize,
+
+    /// One highlighting code per character of the command line
+    highlights: Vec<u8>,
+}
+
+/// Words that mean something to the parser when they come first in a command
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_04
+///
+/// `in` is missing since it is only special after `for name` and `case word`,
+/// see `peek_word()`.
+static RESERVED_WORDS: [&str; 14] = [
+    "{", "}", "if", "then", "elif", "else", "fi", "while", "until", "for", "do", "done", "case",
+    "esac",
+];
+
+/// Reserved words that end whatever list comes before them
+static CLOSING_RESERVED_WORDS: [&str; 8] =
+    ["}", "then", "elif", "else", "fi", "do", "done", "esac"];
+
+fn redirection_operator(operator: &str) -> Option<RedirectionOperator> {
+    return match operator {
+        "<" => Some(RedirectionOperator::Input),
+        ">" => Some(RedirectionOperator::Output),
+        ">|" => Some(RedirectionOperator::Clobber),
+        ">>" => Some(RedirectionOperator::Append),
+        "<>" => Some(RedirectionOperator::ReadWrite),
+        "<&" => Some(RedirectionOperator::DuplicateInput),
+        ">&" => Some(RedirectionOperator::DuplicateOutput),
+        _ => None,
+    };
+}
+
+fn is_here_document_operator(operator: &str) -> bool {
+    return operator == "<<" || operator == "<<-";
+}
+
+/// Splits `NAME=value` into an assignment, or returns `None` if the token
+/// isn't one.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_10_02
+fn to_assignment(token: &Token) -> Option<Assignment> {
+    let (name, value) = token.text.fragment().split_once('=')?;
+    if !is_name(name) {
+        // This also rules out quoted names, like in "FOO"=1
+        return None;
+    }
+
+    return Some(Assignment {
+        name: name.to_string(),
+        value: Word {
+            text: value.to_string(),
+            offset: token.text.location_offset() + name.len() + 1,
+        },
+    });
+}
+
+/// Sets the highlighting codes for the characters of `span`, which must be
+/// part of `commandline`
+fn highlight_span(
+    commandline: &str,
+    highlights: &mut [u8],
+    span: &LocatedSpan<&str, ()>,
+    highlighting_code: u8,
+) {
+    let first_char_index = commandline[..span.location_offset()].chars().count();
+    let char_count = span.chars().count();
+    for highlight in highlights
+        .iter_mut()
+        .skip(first_char_index)
+        .take(char_count)
+    {
+        *highlight = highlighting_code;
+    }
+}
+
+impl<'a> Parser<'a> {
+    fn new(commandline: &'a str, tokens: Vec<Token<'a>>) -> Self {
+        let mut parser = Parser {
+            commandline,
+            tokens: Vec::new(),
+            position: 0,
+            highlights: vec![b' '; commandline.chars().count()],
+        };
+
+        for token in tokens {
+            if token.is_comment {
+                parser.highlight(&token, b'c');
+            } else {
+                parser.tokens.push(token);
+            }
+        }
+
+        return parser;
+    }
+
+    fn highlight(&mut self, token: &Token, highlighting_code: u8) {
+        highlight_span(
+            self.commandline,
+            &mut self.highlights,
+            &token.text,
+            highlighting_code,
+        );
+    }
+
+    fn peek(&self) -> Option<Token<'a>> {
+        return self.tokens.get(self.position).copied();
+    }
+
+    /// If the next token is an operator, returns its text
+    fn peek_operator(&self) -> Option<&'a str> {
+        if let Some(token) = self.peek() {
+            if token.is_operator() {
+                return Some(token.text.fragment());
+            }
+        }
+        return None;
+    }
+
+    /// If the next token is a reserved word, returns its text. Only call this
+    /// where a command could start, anywhere else reserved words are just
+    /// words.
+    fn peek_reserved_word(&self) -> Option<&'a str> {
+        if let Some(token) = self.peek() {
+            if !token.is_quoted
+                && !token.is_operator()
+                && RESERVED_WORDS.contains(token.text.fragment())
+            {
+                return Some(token.text.fragment());
+            }
+        }
+        return None;
+    }
+
+    /// True if the next token is the unquoted word `word`. Use this for words
+    /// that are only special in some places, like `in`.
+    fn peek_word(&self, word: &str) -> bool {
+        if let Some(token) = self.peek() {
+            return !token.is_quoted && !token.is_operator() && *token.text.fragment() == word;
+        }
+        return false;
+    }
+
+    /// Consumes the next token if it's a word rather than an operator
+    fn consume_word(&mut self) -> Option<Token<'a>> {
+        let token = self.peek()?;
+        if token.is_operator() {
+            return None;
+        }
+        return Some(self.consume());
+    }
+
+    /// True if the next token is one of `terminators`, which can be both
+    /// operators and reserved words.
+    ///
+    /// Reserved words like `fi` that can only close something always end the
+    /// list, so that whoever is expecting something else can say what.
+    fn at_terminator(&self, terminators: &[&str]) -> bool {
+        if let Some(operator) = self.peek_operator() {
+            return terminators.contains(&operator);
+        }
+        if let Some(word) = self.peek_reserved_word() {
+            return terminators.contains(&word) || CLOSING_RESERVED_WORDS.contains(&word);
+        }
+        return false;
+    }
+
+    /// Consumes the next token, which must exist
+    fn consume(&mut self) -> Token<'a> {
+        let token = self.tokens[self.position];
+        self.position += 1;
+        return token;
+    }
+
+    /// Consumes the next token and highlights it as an operator
+    fn consume_operator(&mut self) -> Token<'a> {
+        let token = self.consume();
+        self.highlight(&token, b'x');
+        return token;
+    }
+
+    /// Consumes the next token and highlights it as a reserved word
+    fn consume_reserved_word(&mut self) -> Token<'a> {
+        let token = self.consume();
+        self.highlight(&token, b'k');
+        return token;
+    }
+
+    /// Consumes the reserved word `word`, which should close whatever
+    /// `opening` started
+    fn expect_reserved_word(
+        &mut self,
+        word: &str,
+        opening: &Token<'a>,
+    ) -> Result<Token<'a>, ParseError<'a>> {
+        if self.peek_reserved_word() == Some(word) {
+            return Ok(self.consume_reserved_word());
+        }
+
+        if self.peek().is_none() {
+            return Err(self.unclosed_error(opening, opening.text.fragment(), word));
+        }
+        return Err(self.error_at_next(&format!("Expected {} here", word)));
+    }
+
+    fn skip_newlines(&mut self) {
+        while self.peek_operator() == Some("\n") {
+            self.consume();
+        }
+    }
+
+    fn error(&self, token: &Token<'a>, message: &str) -> ParseError<'a> {
+        return ParseError {
+            commandline: self.commandline,
+            text: token.text,
+            message: message.to_string(),
+            help: None,
+            incomplete: false,
+        };
+    }
+
+    /// Returns an error pointing out an `opening` token that `closing` never
+    /// came after
+    fn unclosed_error(&self, opening: &Token<'a>, what: &str, closing: &str) -> ParseError<'a> {
+        let mut error = self.error(opening, &format!("Unclosed {}", what));
+        error.help = Some(format!("Add a {} somewhere after this one", closing));
+        error.incomplete = self.peek().is_none();
+        return error;
+    }
+
+    /// Returns an error pointing at the next token, or at the end of the
+    /// command line if there are no more tokens. In the latter case the
+    /// command line is incomplete.
+    fn error_at_next(&self, message: &str) -> ParseError<'a> {
+        if let Some(token) = self.peek() {
+            return self.error(&token, message);
+        }
+
+        return ParseError {
+            commandline: self.commandline,
+            text: LocatedSpan::new(self.commandline).slice(self.commandline.len()..),
+            message: message.to_string(),
+            help: None,
+            incomplete: true,
+        };
+    }
+
+    /// Parses and-or lists until either the end of the input or until one of
+    /// the `terminators`, which can be operators or reserved words. The
+    /// terminator is not consumed.
+    fn parse_list(&mut self, terminators: &[&str]) -> Result<List, ParseError<'a>> {
+        let mut items: Vec<(AndOr, Separator)> = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek().is_none() {
+                break;
+            }
+            if self.at_terminator(terminators) {
+                break;
+            }
+
+            let and_or = self.parse_and_or()?;
+
+            let separator = match self.peek_operator() {
+                Some(";") => {
+                    self.consume_operator();
+                    Separator::Sequential
+                }
+                Some("\n") => {
+                    self.consume();
+                    Separator::Sequential
+                }
+                Some("&") => {
+                    self.consume_operator();
+                    Separator::Background
+                }
+                _ => {
+                    if self.peek().is_some() && !self.at_terminator(terminators) {
+                        return Err(self.error_at_next("Expected ; or & or a newline here"));
+                    }
+                    Separator::Sequential
+                }
+            };
+
+            items.push((and_or, separator));
+        }
+
+        return Ok(List { items });
+    }
+
+    fn parse_and_or(&mut self) -> Result<AndOr, ParseError<'a>> {
+        let first = self.parse_pipeline()?;
+        let mut rest: Vec<(AndOrOperator, Pipeline)> = Vec::new();
+
+        loop {
+            let operator = match self.peek_operator() {
+                Some("&&") => AndOrOperator::And,
+                Some("||") => AndOrOperator::Or,
+                _ => break,
+            };
+            self.consume_operator();
+            self.skip_newlines();
+
+            rest.push((operator, self.parse_pipeline()?));
+        }
+
+        return Ok(AndOr { first, rest });
+    }
+
+    fn parse_pipeline(&mut self) -> Result<Pipeline, ParseError<'a>> {
+        let start = match self.peek() {
+            Some(token) => token.text.location_offset(),
+            None => self.commandline.len(),
+        };
+
+        let mut bang = false;
+        if let Some(token) = self.peek() {
+            if !token.is_quoted && *token.text.fragment() == "!" {
+                self.consume_operator();
+                bang = true;
+            }
+        }
+
+        let mut commands = vec![self.parse_command()?];
+        while self.peek_operator() == Some("|") {
+            self.consume_operator();
+            self.skip_newlines();
+
+            commands.push(self.parse_command()?);
+        }
+
+        let last = self.tokens[self.position - 1];
+        let end = last.text.location_offset() + last.text.fragment().len();
+        let text = self.commandline[start..end].to_string();
+
+        return Ok(Pipeline {
+            bang,
+            commands,
+            text,
+        });
     }
 
-    let tokens = tokens_result.unwrap();
-    if tokens.is_empty() {
-        return Ok(" ".repeat(commandline.len()));
+    /// Like `parse_list()`, but fails if the list is empty
+    fn parse_nonempty_list(&mut self, terminators: &[&str]) -> Result<List, ParseError<'a>> {
+        let list = self.parse_list(terminators)?;
+        if list.items.is_empty() {
+            return Err(self.error_at_next("Expected a command here"));
+        }
+        return Ok(list);
     }
 
-    let mut words: Vec<String> = Vec::new();
-    for token in &tokens {
-        if !token.is_comment {
-            words.push(token.text.to_string())
+    /// True if the next tokens are `name ( )`
+    fn at_function_definition(&self) -> bool {
+        let name = self.peek();
+        if name.is_none() || name.unwrap().is_quoted || !is_name(name.unwrap().text.fragment()) {
+            return false;
         }
+
+        let is_operator = |index: usize, operator: &str| -> bool {
+            return self
+                .tokens
+                .get(index)
+                .map(|token| token.is_operator() && *token.text.fragment() == operator)
+                .unwrap_or(false);
+        };
+        return is_operator(self.position + 1, "(") && is_operator(self.position + 2, ")");
     }
-    executor.execute(&words[0], &words[1..]);
 
-    let mut highlights = vec![b' '; commandline.chars().count()];
-    let mut word_index: usize = 0;
-    for token in tokens.iter() {
-        let highlighting_code: u8;
-        if token.is_comment {
-            highlighting_code = b'c';
+    fn parse_command(&mut self) -> Result<Command, ParseError<'a>> {
+        if self.peek_operator() == Some("(") {
+            return self.parse_compound_command();
+        }
+
+        match self.peek_reserved_word() {
+            Some("{") | Some("if") | Some("while") | Some("until") | Some("for") | Some("case") => {
+                return self.parse_compound_command()
+            }
+            Some(word) => return Err(self.error_at_next(&format!("Unexpected {} here", word))),
+            None => {}
+        }
+
+        if self.at_function_definition() {
+            return self.parse_function_definition();
+        }
+
+        return self.parse_simple_command();
+    }
+
+    /// Parses a compound command and any redirections after it
+    fn parse_compound_command(&mut self) -> Result<Command, ParseError<'a>> {
+        let compound = if self.peek_operator() == Some("(") {
+            self.parse_subshell()?
         } else {
-            // This is a word, not a comment
-            if word_index == 0 {
-                highlighting_code = b'0';
-            } else if word_index % 2 == 1 {
-                highlighting_code = b'a';
-            } else {
-                highlighting_code = b'A';
+            match self.peek_reserved_word() {
+                Some("{") => self.parse_brace_group()?,
+                Some("if") => self.parse_if()?,
+                Some("while") | Some("until") => self.parse_while_until()?,
+                Some("for") => self.parse_for()?,
+                Some("case") => self.parse_case()?,
+                _ => {
+                    return Err(self.error_at_next(
+                        "Expected a compound command here, like { ...; } or ( ... )",
+                    ))
+                }
             }
-            word_index += 1;
+        };
+
+        let mut redirections: Vec<Redirection> = Vec::new();
+        let mut argument_index = 1;
+        while let Some(redirection) = self.parse_redirection(&mut argument_index)? {
+            redirections.push(redirection);
         }
 
-        let first_char_index = token.text.get_utf8_column() - 1;
-        let last_char_index = token.text.get_utf8_column() + token.text.chars().count() - 2;
-        #[allow(clippy::needless_range_loop)]
-        for i in first_char_index..(last_char_index + 1) {
-            highlights[i] = highlighting_code;
+        return Ok(Command::Compound(compound, redirections));
+    }
+
+    fn parse_subshell(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let opening_paren = self.consume_operator();
+
+        let list = self.parse_list(&[")"])?;
+        if self.peek_operator() != Some(")") {
+            return Err(self.unclosed_error(&opening_paren, "parenthesis", ")"));
+        }
+        self.consume_operator();
+
+        if list.items.is_empty() {
+            return Err(self.error(&opening_paren, "Empty subshell, put a command inside"));
         }
+
+        return Ok(CompoundCommand::Subshell(list));
     }
 
-    return Ok(String::from_utf8(highlights).unwrap());
-}
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_01
+    fn parse_brace_group(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let opening_brace = self.consume_reserved_word();
 
-#[cfg(test)]
-mod tests {
-    // Note this useful idiom: importing names from outer (for mod tests) scope.
-    use super::*;
+        let list = self.parse_list(&["}"])?;
+        self.expect_reserved_word("}", &opening_brace)?;
+
+        if list.items.is_empty() {
+            return Err(self.error(&opening_brace, "Empty braces, put a command inside"));
+        }
+
+        return Ok(CompoundCommand::BraceGroup(list));
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_07
+    fn parse_if(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let if_token = self.consume_reserved_word();
+
+        let mut conditionals: Vec<(List, List)> = Vec::new();
+        loop {
+            let condition = self.parse_nonempty_list(&["then"])?;
+            self.expect_reserved_word("then", &if_token)?;
+            let body = self.parse_nonempty_list(&["elif", "else", "fi"])?;
+            conditionals.push((condition, body));
+
+            if self.peek_reserved_word() != Some("elif") {
+                break;
+            }
+            self.consume_reserved_word();
+        }
+
+        let mut otherwise: Option<List> = None;
+        if self.peek_reserved_word() == Some("else") {
+            self.consume_reserved_word();
+            otherwise = Some(self.parse_nonempty_list(&["fi"])?);
+        }
+
+        self.expect_reserved_word("fi", &if_token)?;
+
+        return Ok(CompoundCommand::If {
+            conditionals,
+            otherwise,
+        });
+    }
+
+    /// Parses `do list; done`, which is the body of all loops. `opening` is
+    /// the `while`, `until` or `for` that started the loop.
+    fn parse_do_group(&mut self, opening: &Token<'a>) -> Result<List, ParseError<'a>> {
+        self.expect_reserved_word("do", opening)?;
+        let body = self.parse_nonempty_list(&["done"])?;
+        self.expect_reserved_word("done", opening)?;
+        return Ok(body);
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_09
+    fn parse_while_until(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let loop_token = self.consume_reserved_word();
+
+        let condition = self.parse_nonempty_list(&["do"])?;
+        let body = self.parse_do_group(&loop_token)?;
+
+        if *loop_token.text.fragment() == "until" {
+            return Ok(CompoundCommand::Until { condition, body });
+        }
+        return Ok(CompoundCommand::While { condition, body });
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_03
+    fn parse_for(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let for_token = self.consume_reserved_word();
+
+        let name = self.peek();
+        if name.is_none() || name.unwrap().is_operator() || !is_name(name.unwrap().text.fragment())
+        {
+            return Err(self.error_at_next("Expected a variable name here"));
+        }
+        let name = self.consume();
+        self.highlight(&name, b'v');
+        self.skip_newlines();
+
+        let mut words: Option<Vec<Word>> = None;
+        if self.peek_word("in") {
+            self.consume_reserved_word();
+
+            let mut argument_index = 1;
+            let mut in_words: Vec<Word> = Vec::new();
+            while let Some(token) = self.consume_word() {
+                self.highlight_argument(&token, &mut argument_index);
+                in_words.push(Word {
+                    text: token.text.to_string(),
+                    offset: token.text.location_offset(),
+                });
+            }
+            words = Some(in_words);
+
+            match self.peek_operator() {
+                Some(";") => {
+                    self.consume_operator();
+                }
+                Some("\n") => {
+                    self.consume();
+                }
+                _ => return Err(self.error_at_next("Expected ; or a newline here")),
+            }
+        } else if self.peek_operator() == Some(";") {
+            self.consume_operator();
+        }
+        self.skip_newlines();
+
+        let body = self.parse_do_group(&for_token)?;
+
+        return Ok(CompoundCommand::For {
+            name: name.text.to_string(),
+            words,
+            body,
+        });
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_04_05
+    fn parse_case(&mut self) -> Result<CompoundCommand, ParseError<'a>> {
+        let case_token = self.consume_reserved_word();
 
-    struct TestExecutor {
-        executions: Vec<String>,
+        let word = self.consume_word();
+        if word.is_none() {
+            return Err(self.error_at_next("Expected a word after case"));
+        }
+        let word = word.unwrap();
+        let mut argument_index = 1;
+        self.highlight_argument(&word, &mut argument_index);
+
+        self.skip_newlines();
+        if !self.peek_word("in") {
+            return Err(self.error_at_next("Expected in here"));
+        }
+        self.consume_reserved_word();
+
+        let mut items: Vec<CaseItem> = Vec::new();
+        loop {
+            self.skip_newlines();
+            if self.peek().is_none() || self.peek_reserved_word() == Some("esac") {
+                break;
+            }
+
+            if self.peek_operator() == Some("(") {
+                self.consume_operator();
+            }
+
+            let mut patterns: Vec<Word> = Vec::new();
+            loop {
+                let pattern = self.consume_word();
+                if pattern.is_none() {
+                    return Err(self.error_at_next("Expected a pattern here"));
+                }
+                let pattern = pattern.unwrap();
+                self.highlight_argument(&pattern, &mut argument_index);
+                patterns.push(Word {
+                    text: pattern.text.to_string(),
+                    offset: pattern.text.location_offset(),
+                });
+
+                if self.peek_operator() != Some("|") {
+                    break;
+                }
+                self.consume_operator();
+            }
+
+            if self.peek_operator() != Some(")") {
+                return Err(self.error_at_next("Expected ) after the pattern"));
+            }
+            self.consume_operator();
+
+            let body = self.parse_list(&[";;", "esac"])?;
+            items.push(CaseItem { patterns, body });
+
+            if self.peek_operator() != Some(";;") {
+                // Only the last item can do without the ;;
+                break;
+            }
+            self.consume_operator();
+        }
+
+        self.expect_reserved_word("esac", &case_token)?;
+
+        return Ok(CompoundCommand::Case {
+            word: Word {
+                text: word.text.to_string(),
+                offset: word.text.location_offset(),
+            },
+            items,
+        });
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_05
+    fn parse_function_definition(&mut self) -> Result<Command, ParseError<'a>> {
+        let name = self.consume();
+        self.highlight(&name, b'0');
+        self.consume_operator();
+        self.consume_operator();
+        self.skip_newlines();
+
+        let body = self.parse_compound_command()?;
+
+        return Ok(Command::FunctionDefinition(FunctionDefinition {
+            name: name.text.to_string(),
+            body: Rc::new(body),
+        }));
     }
 
-    impl Executor for TestExecutor {
-        fn execute(&mut self, command: &str, args: &[String]) {
-            let mut command_with_args: Vec<String> = vec![command.to_owned()];
+    /// Returns `Ok(None)` if the next token(s) are not a redirection.
+    ///
+    /// `argument_index` is used for highlighting the redirection target.
+    fn parse_redirection(
+        &mut self,
+        argument_index: &mut usize,
+    ) -> Result<Option<Redirection>, ParseError<'a>> {
+        let mut fd: Option<i32> = None;
+        let mut operator_position = self.position;
+
+        let next = self.peek();
+        if next.is_none() {
+            return Ok(None);
+        }
+        let next = next.unwrap();
+
+        if !next.is_operator() {
+            // Might be an IO number, as in the "2" in "2>&1"
+            // Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_10_01
+            if next.is_quoted || !next.text.chars().all(|c| c.is_ascii_digit()) {
+                return Ok(None);
+            }
+
+            let after = self.tokens.get(self.position + 1);
+            if after.is_none() {
+                return Ok(None);
+            }
+            let after = after.unwrap();
+            let adjacent =
+                next.text.location_offset() + next.text.len() == after.text.location_offset();
+            if !adjacent || !after.is_operator() {
+                return Ok(None);
+            }
+            if redirection_operator(after.text.fragment()).is_none()
+                && !is_here_document_operator(after.text.fragment())
+            {
+                return Ok(None);
+            }
 
-            for arg in args {
-                command_with_args.push(arg.to_owned());
+            let parsed_fd = next.text.parse::<i32>();
+            if parsed_fd.is_err() {
+                return Err(self.error(&next, "File descriptor number too large"));
             }
+            fd = Some(parsed_fd.unwrap());
+            operator_position += 1;
+        }
+
+        let operator_token = self.tokens[operator_position];
+        if !operator_token.is_operator() {
+            return Ok(None);
+        }
+        if is_here_document_operator(operator_token.text.fragment()) {
+            return Err(self.error(&operator_token, "Here-documents are not supported"));
+        }
+        let operator = redirection_operator(operator_token.text.fragment());
+        if operator.is_none() {
+            return Ok(None);
+        }
+
+        if fd.is_some() {
+            self.consume_operator();
+        }
+        self.consume_operator();
 
-            self.executions
-                .push(format!("exec('{}')", command_with_args.join("', '")));
+        let target = self.peek();
+        if target.is_none() || target.unwrap().is_operator() {
+            return Err(self.error_at_next(&format!(
+                "Expected a file name after {}",
+                operator_token.text.fragment()
+            )));
         }
+        let target = self.consume();
+        self.highlight_argument(&target, argument_index);
+
+        return Ok(Some(Redirection {
+            fd,
+            operator: operator.unwrap(),
+            target: Word {
+                text: target.text.to_string(),
+                offset: target.text.location_offset(),
+            },
+        }));
     }
 
-    impl TestExecutor {
-        fn new() -> TestExecutor {
-            return TestExecutor {
-                executions: Vec::new(),
+    /// Highlights arguments as `a`, `A`, `a`, `A`... depending on the index
+    fn highlight_argument(&mut self, token: &Token, argument_index: &mut usize) {
+        if *argument_index % 2 == 1 {
+            self.highlight(token, b'a');
+        } else {
+            self.highlight(token, b'A');
+        }
+        *argument_index += 1;
+    }
+
+    fn parse_simple_command(&mut self) -> Result<Command, ParseError<'a>> {
+        let mut assignments: Vec<Assignment> = Vec::new();
+        let mut words: Vec<Word> = Vec::new();
+        let mut redirections: Vec<Redirection> = Vec::new();
+
+        let mut argument_index: usize = 1;
+
+        // Redirections before the command name, as in ">out echo hi", get
+        // their own count so that the arguments still start at one
+        let mut leading_index: usize = 1;
+
+        loop {
+            let index = if words.is_empty() {
+                &mut leading_index
+            } else {
+                &mut argument_index
             };
+            if let Some(redirection) = self.parse_redirection(index)? {
+                redirections.push(redirection);
+                continue;
+            }
+
+            let token = self.peek();
+            if token.is_none() || token.unwrap().is_operator() {
+                break;
+            }
+            let token = self.consume();
+
+            if words.is_empty() {
+                // Assignments are only recognized before the command name
+                if let Some(assignment) = to_assignment(&token) {
+                    self.highlight(&token, b'v');
+                    assignments.push(assignment);
+                    continue;
+                }
+            }
+
+            if words.is_empty() {
+                self.highlight(&token, b'0');
+            } else {
+                self.highlight_argument(&token, &mut argument_index);
+            }
+
+            words.push(Word {
+                text: token.text.to_string(),
+                offset: token.text.location_offset(),
+            });
+        }
+
+        if assignments.is_empty() && words.is_empty() && redirections.is_empty() {
+            return Err(self.error_at_next("Expected a command here"));
+        }
+
+        return Ok(Command::Simple(SimpleCommand {
+            assignments,
+            words,
+            redirections,
+        }));
+    }
+}
+
+/// Parses the command line. Returns the program together with one
+/// highlighting code per character of the command line, see `highlight()`.
+///
+/// On failure, the highlighting codes are valid up to where parsing failed.
+fn parse_with_highlights(commandline: &str) -> (Result<List, ParseError<'_>>, Vec<u8>) {
+    let tokens = match to_tokens(commandline) {
+        Ok(tokens) => tokens,
+        Err(error) => return (Err(error.into()), vec![b' '; commandline.chars().count()]),
+    };
+
+    let mut parser = Parser::new(commandline, tokens);
+    let mut program = parser.parse_list(&[]);
+    if program.is_ok() {
+        if let Some(word) = parser.peek_reserved_word() {
+            // Something like a "fi" without an "if"
+            program = Err(parser.error_at_next(&format!("Unexpected {} here", word)));
         }
     }
 
+    return (program, parser.highlights);
+}
+
+/// Parses a command line into a syntax tree.
+///
+/// Nothing gets executed by this function, see `interpreter::run()` for that.
+pub(crate) fn parse(commandline: &str) -> Result<Parsed, ParseError<'_>> {
+    let (program, _) = parse_with_highlights(commandline);
+    return Ok(Parsed { program: program? });
+}
+
+/// Returns a string of the same length as the command line, containing
+/// highlighting information. This works for command lines that don't parse
+/// as well, so that it can be used while the user is typing.
+///
+/// Nothing is executed or expanded.
+///
+/// # Highlighting codes
+/// * `0` Executable command
+/// * `a` First argument, third, fifth etc...
+/// * `A` Second argument, fourth, sixth etc...
+/// * `c` Comment
+/// * `e` Where parsing failed
+/// * `k` Reserved word, like `if` or `{`
+/// * `v` Variable assignment
+/// * `x` Operator
+pub(crate) fn highlight(commandline: &str) -> String {
+    let (program, mut highlights) = parse_with_highlights(commandline);
+    if let Err(error) = program {
+        highlight_span(commandline, &mut highlights, &error.text, b'e');
+    }
+
+    return String::from_utf8(highlights).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::exitstatus::ExitStatus;
+    use crate::interpreter::run;
+    use crate::testexecutor::TestExecutor;
+
     /// Returns a vector of commands to be executed given this command line
     fn record_execs(commandline: &str) -> (Vec<String>, String) {
         let mut test_executor: TestExecutor = TestExecutor::new();
-        let highlights = parse(commandline, &mut test_executor).unwrap();
+        let parsed = parse(commandline).unwrap();
+        run(&mut test_executor, &parsed.program);
+
+        return (test_executor.executions, highlight(commandline));
+    }
 
-        return (test_executor.executions, highlights);
+    fn highlights(commandline: &str) -> String {
+        assert!(parse(commandline).is_ok());
+        return highlight(commandline);
+    }
+
+    fn assert_parse_error(commandline: &str, failed_offset: usize, message: &str) {
+        let error = parse(commandline).unwrap_err();
+        assert_eq!(error.message, message);
+        assert_eq!(error.text.location_offset(), failed_offset);
     }
 
     #[test]
@@ -152,6 +992,289 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_empty() {
+        assert_eq!(record_execs(""), (vec![], "".to_string()));
+        assert_eq!(record_execs("  "), (vec![], "  ".to_string()));
+        assert_eq!(record_execs("# hej"), (vec![], "ccccc".to_string()));
+    }
+
+    #[test]
+    fn test_parse_quotes() {
+        assert_eq!(
+            record_execs(r"echo\ hej"),
+            (
+                vec!["exec('echo hej')".to_string()],
+                "000000000".to_string()
+            )
+        );
+
+        assert_eq!(
+            record_execs(r#"git commit -m "fix bug""#),
+            (
+                vec!["exec('git', 'commit', '-m', 'fix bug')".to_string()],
+                "000 aaaaaa AA aaaaaaaaa".to_string()
+            )
+        );
+
+        assert_eq!(
+            record_execs("echo 'hello world'"),
+            (
+                vec!["exec('echo', 'hello world')".to_string()],
+                "0000 aaaaaaaaaaaaa".to_string()
+            )
+        );
+    }
+
+    #[test]
+    fn test_pipeline() {
+        assert_eq!(
+            record_execs("ls | wc -l"),
+            (
+                vec![
+                    "exec('ls')".to_string(),
+                    "|".to_string(),
+                    "exec('wc', '-l')".to_string()
+                ],
+                "00 x 00 aa".to_string()
+            )
+        );
+    }
+
+    fn execs(commandline: &str) -> Vec<String> {
+        return record_execs(commandline).0;
+    }
+
+    #[test]
+    fn test_lists() {
+        assert_eq!(
+            execs("echo a ; echo b"),
+            vec!["exec('echo', 'a')", "exec('echo', 'b')"]
+        );
+        assert_eq!(
+            execs("false; echo b;"),
+            vec!["exec('false')", "exec('echo', 'b')"]
+        );
+
+        assert_eq!(
+            execs("true && echo a"),
+            vec!["exec('true')", "exec('echo', 'a')"]
+        );
+        assert_eq!(execs("false && echo a"), vec!["exec('false')"]);
+        assert_eq!(execs("true || echo a"), vec!["exec('true')"]);
+        assert_eq!(
+            execs("false || echo a"),
+            vec!["exec('false')", "exec('echo', 'a')"]
+        );
+
+        // && and || have equal precedence and are left associative
+        assert_eq!(
+            execs("make && ./run || echo failed"),
+            vec!["exec('make')", "exec('./run')"]
+        );
+        assert_eq!(
+            execs("false && ./run || echo failed"),
+            vec!["exec('false')", "exec('echo', 'failed')"]
+        );
+        assert_eq!(
+            execs("false || false || echo x && echo y"),
+            vec![
+                "exec('false')",
+                "exec('false')",
+                "exec('echo', 'x')",
+                "exec('echo', 'y')"
+            ]
+        );
+
+        assert_eq!(execs("! true && echo a"), vec!["exec('true')"]);
+        assert_eq!(
+            execs("! false && echo a"),
+            vec!["exec('false')", "exec('echo', 'a')"]
+        );
+    }
+
+    #[test]
+    fn test_list_exit_status() {
+        let mut test_executor: TestExecutor = TestExecutor::new();
+
+        let parsed = parse("true; false").unwrap();
+        assert_eq!(
+            run(&mut test_executor, &parsed.program),
+            ExitStatus::from_code(1)
+        );
+
+        let parsed = parse("false || true").unwrap();
+        assert_eq!(
+            run(&mut test_executor, &parsed.program),
+            ExitStatus::success()
+        );
+
+        let parsed = parse("false && true").unwrap();
+        assert_eq!(
+            run(&mut test_executor, &parsed.program),
+            ExitStatus::from_code(1)
+        );
+
+        let parsed = parse("false | true").unwrap();
+        assert_eq!(
+            run(&mut test_executor, &parsed.program),
+            ExitStatus::success()
+        );
+
+        let parsed = parse("! true").unwrap();
+        assert_eq!(
+            run(&mut test_executor, &parsed.program),
+            ExitStatus::from_code(1)
+        );
+    }
+
+    #[test]
+    fn test_redirections() {
+        assert_eq!(
+            execs("echo hej >x 2>&1"),
+            vec!["redirect(1>x 2>&1)", "exec('echo', 'hej')"]
+        );
+        assert_eq!(
+            execs("<in cat 2>/dev/null >>'my log'"),
+            vec!["redirect(0<in 2>/dev/null 1>>my log)", "exec('cat')"]
+        );
+        assert_eq!(
+            execs("cat 3<>file 4<&3 4>&-"),
+            vec!["redirect(3<>file 4<&3 4>&-)", "exec('cat')"]
+        );
+        assert_eq!(execs("> empty"), vec!["redirect(1>empty)"]);
+
+        assert_eq!(highlights("echo hi >out"), "0000 aa xAAA");
+        assert_eq!(highlights(">out echo hi"), "xaaa 0000 aa");
+        assert_eq!(highlights("<in cat a b"), "xaa 000 a A");
+    }
+
+    #[test]
+    fn test_parameter_expansion() {
+        assert_eq!(
+            execs("echo ${UNSET:-nej} $UNSET \"$UNSET\""),
+            vec!["exec('echo', 'nej', '')"]
+        );
+        assert_eq!(
+            execs("false; echo $?; echo $?"),
+            vec!["exec('false')", "exec('echo', '1')", "exec('echo', '0')"]
+        );
+        assert_eq!(
+            execs("echo ${UNSET:?oops}; echo hej"),
+            vec!["exec('echo', 'hej')"]
+        );
+        assert_eq!(highlights("echo ${X:-a b}"), "0000 aaaaaaaaa");
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        assert_eq!(
+            execs("echo \"$(ls | wc -l)\""),
+            vec!["exec('ls')", "|", "exec('wc', '-l')", "exec('echo', '')"]
+        );
+        assert_eq!(
+            execs("echo `echo hej`"),
+            vec!["exec('echo', 'hej')", "exec('echo', 'hej')"]
+        );
+        assert_eq!(highlights("echo $(ls | wc -l)"), "0000 aaaaaaaaaaaaa");
+    }
+
+    #[test]
+    fn test_assignments() {
+        assert_eq!(
+            record_execs("FOO=1 BAR=$X env FOO=2"),
+            (
+                vec!["FOO=1 BAR= exec('env', 'FOO=2')".to_string()],
+                "vvvvv vvvvvv 000 aaaaa".to_string()
+            )
+        );
+        assert_eq!(
+            execs("X=1; echo $X; X=$(echo a b) Y=$X; echo \"$X$Y\""),
+            vec![
+                "exec('echo', '1')",
+                "exec('echo', 'a', 'b')",
+                "exec('echo', 'a ba b')"
+            ]
+        );
+
+        // Not assignments
+        assert_eq!(execs("\"X\"=1"), vec!["exec('X=1')"]);
+        assert_eq!(execs("=1"), vec!["exec('=1')"]);
+        assert_eq!(execs("1X=1"), vec!["exec('1X=1')"]);
+
+        // The exit status of an assignment comes from its command
+        // substitutions
+        assert_eq!(
+            execs("X=$(false) || echo $?; X=$(false)$(true) && echo $?; false; X=1 && echo $?"),
+            vec![
+                "exec('false')",
+                "exec('echo', '1')",
+                "exec('false')",
+                "exec('true')",
+                "exec('echo', '0')",
+                "exec('false')",
+                "exec('echo', '0')"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ast_assignments() {
+        let program = parse("A=1 >x B=\"2 3\" cmd C=4").unwrap().program;
+        let and_or = &program.items[0].0;
+        if let Command::Simple(command) = &and_or.first.commands[0] {
+            assert_eq!(
+                command.assignments,
+                vec![
+                    Assignment {
+                        name: "A".to_string(),
+                        value: Word {
+                            text: "1".to_string(),
+                            offset: 2
+                        }
+                    },
+                    Assignment {
+                        name: "B".to_string(),
+                        value: Word {
+                            text: "\"2 3\"".to_string(),
+                            offset: 9
+                        }
+                    },
+                ]
+            );
+            assert_eq!(command.words.len(), 2);
+            assert_eq!(command.redirections.len(), 1);
+        } else {
+            panic!("Expected a simple command");
+        }
+    }
+
+    #[test]
+    fn test_subshell() {
+        assert_eq!(
+            execs("(echo a; echo b) > x"),
+            vec![
+                "redirect(1>x)",
+                "(",
+                "exec('echo', 'a')",
+                "exec('echo', 'b')",
+                ")"
+            ]
+        );
+        assert_eq!(
+            execs("(false) || echo $?"),
+            vec!["(", "exec('false')", ")", "exec('echo', '1')"]
+        );
+    }
+
+    #[test]
+    fn test_highlight_errors() {
+        assert_eq!(highlight("echo \"hej"), "     e   ");
+        assert_eq!(highlight("if true; then echo"), "ee 0000x kkkk 0000");
+        assert_eq!(highlight("echo a | | b"), "0000 a x e  ");
+        assert_eq!(highlight("echo fi; fi"), "0000 aax ee");
+    }
+
     #[test]
     fn test_comment() {
         assert_eq!(
@@ -170,4 +1293,311 @@ mod tests {
             )
         );
     }
+
+    fn simple_command(words: &[(&str, usize)]) -> Command {
+        return Command::Simple(SimpleCommand {
+            assignments: vec![],
+            words: words
+                .iter()
+                .map(|(text, offset)| Word {
+                    text: text.to_string(),
+                    offset: *offset,
+                })
+                .collect(),
+            redirections: vec![],
+        });
+    }
+
+    fn pipeline(text: &str, commands: Vec<Command>) -> Pipeline {
+        return Pipeline {
+            bang: false,
+            commands,
+            text: text.to_string(),
+        };
+    }
+
+    #[test]
+    fn test_ast_list() {
+        assert_eq!(
+            parse("echo a ; echo b &").unwrap().program,
+            List {
+                items: vec![
+                    (
+                        AndOr {
+                            first: pipeline(
+                                "echo a",
+                                vec![simple_command(&[("echo", 0), ("a", 5)])]
+                            ),
+                            rest: vec![],
+                        },
+                        Separator::Sequential
+                    ),
+                    (
+                        AndOr {
+                            first: pipeline(
+                                "echo b",
+                                vec![simple_command(&[("echo", 9), ("b", 14)])]
+                            ),
+                            rest: vec![],
+                        },
+                        Separator::Background
+                    ),
+                ]
+            }
+        );
+
+        assert_eq!(highlights("echo a ; echo b &"), "0000 a x 0000 a x");
+        assert_eq!(highlights("a;b"), "0x0");
+    }
+
+    #[test]
+    fn test_ast_and_or_pipeline() {
+        assert_eq!(
+            parse("! a | b && c").unwrap().program,
+            List {
+                items: vec![(
+                    AndOr {
+                        first: Pipeline {
+                            bang: true,
+                            commands: vec![
+                                simple_command(&[("a", 2)]),
+                                simple_command(&[("b", 6)])
+                            ],
+                            text: "! a | b".to_string(),
+                        },
+                        rest: vec![(
+                            AndOrOperator::And,
+                            pipeline("c", vec![simple_command(&[("c", 11)])])
+                        )],
+                    },
+                    Separator::Sequential
+                )]
+            }
+        );
+
+        assert_eq!(highlights("! a | b && c"), "x 0 x 0 xx 0");
+        assert_eq!(highlights("a ||\nb"), "0 xx 0");
+        assert_eq!(highlights("a\nb"), "0 0");
+
+        // For describing jobs
+        assert_eq!(
+            parse("! a |  b ||\n  { c; }").unwrap().program.items[0]
+                .0
+                .text(),
+            "! a |  b || { c; }"
+        );
+    }
+
+    #[test]
+    fn test_ast_subshell() {
+        assert_eq!(
+            parse("(a)").unwrap().program.items[0].0.first.commands[0],
+            Command::Compound(
+                CompoundCommand::Subshell(List {
+                    items: vec![(
+                        AndOr {
+                            first: pipeline("a", vec![simple_command(&[("a", 1)])]),
+                            rest: vec![]
+                        },
+                        Separator::Sequential
+                    )]
+                }),
+                vec![]
+            )
+        );
+        assert_eq!(highlights("(a; b) >x"), "x0x 0x xa");
+    }
+
+    #[test]
+    fn test_ast_redirections() {
+        let program = parse("echo hej 2>&1 >>log <in").unwrap().program;
+        if let Command::Simple(command) = &program.items[0].0.first.commands[0] {
+            assert_eq!(command.words.len(), 2);
+            assert_eq!(
+                command.redirections,
+                vec![
+                    Redirection {
+                        fd: Some(2),
+                        operator: RedirectionOperator::DuplicateOutput,
+                        target: Word {
+                            text: "1".to_string(),
+                            offset: 12
+                        }
+                    },
+                    Redirection {
+                        fd: None,
+                        operator: RedirectionOperator::Append,
+                        target: Word {
+                            text: "log".to_string(),
+                            offset: 16
+                        }
+                    },
+                    Redirection {
+                        fd: None,
+                        operator: RedirectionOperator::Input,
+                        target: Word {
+                            text: "in".to_string(),
+                            offset: 21
+                        }
+                    },
+                ]
+            );
+        } else {
+            panic!("Expected a simple command");
+        }
+
+        assert_eq!(highlights("echo hej 2>&1"), "0000 aaa xxxA");
+
+        // Not an IO number since there's a space before the operator
+        assert_eq!(highlights("echo 2 >x"), "0000 a xA");
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_parse_error("echo |", 6, "Expected a command here");
+        assert_parse_error("| echo", 0, "Expected a command here");
+        assert_parse_error("echo ;; x", 5, "Expected ; or & or a newline here");
+        assert_parse_error("(echo", 0, "Unclosed parenthesis");
+        assert_parse_error("echo )", 5, "Expected ; or & or a newline here");
+        assert_parse_error("echo >", 6, "Expected a file name after >");
+        assert_parse_error("cat <<EOF", 4, "Here-documents are not supported");
+        assert_parse_error("echo 'hej", 5, "Unterminated single quote");
+        assert_parse_error("if true; then echo", 0, "Unclosed if");
+        assert_parse_error("if true; echo; fi", 15, "Expected then here");
+        assert_parse_error("if then; fi", 3, "Expected a command here");
+        assert_parse_error("fi", 0, "Unexpected fi here");
+        assert_parse_error("{ echo }", 0, "Unclosed {");
+        assert_parse_error(
+            "f() echo",
+            4,
+            "Expected a compound command here, like { ...; } or ( ... )",
+        );
+        assert_parse_error("while true; do echo", 0, "Unclosed while");
+        assert_parse_error("until true; done", 12, "Expected do here");
+        assert_parse_error("for 1 in a; do b; done", 4, "Expected a variable name here");
+        assert_parse_error(
+            "for x in a | b; do c; done",
+            11,
+            "Expected ; or a newline here",
+        );
+        assert_parse_error("case x esac", 7, "Expected in here");
+        assert_parse_error(
+            "case x in a b) c;; esac",
+            12,
+            "Expected ) after the pattern",
+        );
+        assert_parse_error("case x in a) b;;", 0, "Unclosed case");
+        assert_parse_error("done", 0, "Unexpected done here");
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let is_incomplete = |commandline| parse(commandline).unwrap_err().incomplete;
+
+        assert!(is_incomplete("echo 'hej"));
+        assert!(is_incomplete("echo \"hej"));
+        assert!(is_incomplete("echo $(ls"));
+        assert!(is_incomplete("echo a \\"));
+        assert!(is_incomplete("echo a |"));
+        assert!(is_incomplete("true &&"));
+        assert!(is_incomplete("false ||\n"));
+        assert!(is_incomplete("if true; then"));
+        assert!(is_incomplete("if true\nthen\necho"));
+        assert!(is_incomplete("for x in a b"));
+        assert!(is_incomplete("for x in a b; do echo $x"));
+        assert!(is_incomplete("(echo"));
+
+        assert!(!is_incomplete("| echo"));
+        assert!(!is_incomplete("fi"));
+        assert!(!is_incomplete("if true; echo; fi"));
+        assert!(!is_incomplete("echo )"));
+
+        // The continuation lines make these complete
+        assert!(parse("echo a \\\nb").is_ok());
+        assert!(parse("echo a |\nwc -l").is_ok());
+        assert!(parse("if true\nthen\necho\nfi").is_ok());
+    }
+
+    #[test]
+    fn test_display_parse_error() {
+        assert_eq!(
+            parse("if true; then echo").unwrap_err().to_string(),
+            "Unclosed if\n  if true; then echo\n  ^^ Add a fi somewhere after this one"
+        );
+        assert_eq!(
+            parse("echo a\necho b |").unwrap_err().to_string(),
+            "Expected a command here\n  echo b |\n          ^"
+        );
+        assert_eq!(
+            parse("echo 'x").unwrap_err().to_string(),
+            "Unterminated single quote\n  echo 'x\n       ^ Did you mean to close the quote opened here?"
+        );
+    }
+
+    #[test]
+    fn test_compound_commands() {
+        assert_eq!(
+            highlights("if a; then b; elif c; then d; else e; fi >x"),
+            "kk 0x kkkk 0x kkkk 0x kkkk 0x kkkk 0x kk xa"
+        );
+        assert_eq!(highlights("{ a\n}"), "k 0 k");
+        assert_eq!(highlights("f() { a; }"), "0xx k 0x k");
+
+        // Reserved words are only special first in a command
+        assert_eq!(execs("echo if }"), vec!["exec('echo', 'if', '}')"]);
+        assert_eq!(execs("'if'"), vec!["exec('if')"]);
+
+        assert_eq!(
+            execs("{ echo a; echo b; } > x"),
+            vec!["redirect(1>x)", "exec('echo', 'a')", "exec('echo', 'b')"]
+        );
+    }
+
+    #[test]
+    fn test_ast_function_definition() {
+        let program = parse("f ( )\n(a)").unwrap().program;
+        if let Command::FunctionDefinition(definition) = &program.items[0].0.first.commands[0] {
+            assert_eq!(definition.name, "f");
+            assert!(matches!(
+                *definition.body,
+                Command::Compound(CompoundCommand::Subshell(_), _)
+            ));
+        } else {
+            panic!("Expected a function definition");
+        }
+    }
+
+    #[test]
+    fn test_loops() {
+        assert_eq!(highlights("while a; do b; done"), "kkkkk 0x kk 0x kkkk");
+        assert_eq!(
+            highlights("for x in a b; do c; done"),
+            "kkk v kk a Ax kk 0x kkkk"
+        );
+        assert_eq!(
+            highlights("case $x in a|b) c;; (d) ;; esac"),
+            "kkkk aa kk Axax 0xx xAx xx kkkk"
+        );
+
+        // "in" is only special after "for name" and "case word"
+        assert_eq!(execs("in for"), vec!["exec('in', 'for')"]);
+        assert_eq!(
+            execs("for x in in do; do echo $x; done"),
+            vec!["exec('echo', 'in')", "exec('echo', 'do')"]
+        );
+    }
+
+    #[test]
+    fn test_ast_for() {
+        let program = parse("for x\ndo a; done").unwrap().program;
+        if let Command::Compound(CompoundCommand::For { name, words, body }, _) =
+            &program.items[0].0.first.commands[0]
+        {
+            assert_eq!(name, "x");
+            assert_eq!(*words, None);
+            assert_eq!(body.items.len(), 1);
+        } else {
+            panic!("Expected a for loop");
+        }
+    }
 }
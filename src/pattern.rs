@@ -0,0 +1,322 @@
+#[derive(Debug, Clone, PartialEq)]
+enum BracketItem {
+    Character(char),
+
+    /// Inclusive range, as in `a-z`
+    Range(char, char),
+
+    /// A character class name, as in `alpha` from `[:alpha:]`
+    Class(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Element {
+    Literal(char),
+
+    /// `?`
+    AnyCharacter,
+
+    /// `*`
+    AnyString,
+
+    /// `[...]`, or `[!...]` if `negated` is true
+    Bracket {
+        negated: bool,
+        items: Vec<BracketItem>,
+    },
+}
+
+/// A [shell pattern][1], as used by `case`, pathname expansion and
+/// `${VAR%pattern}`-style parameter expansions.
+///
+/// Backslashes in the pattern escape the next character. This is how quoted
+/// characters end up in a pattern: quoted `*` characters should be passed as
+/// `\*`.
+///
+/// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_13
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Pattern {
+    elements: Vec<Element>,
+}
+
+fn class_matches(class: &str, character: char) -> bool {
+    return match class {
+        "alnum" => character.is_alphanumeric(),
+        "alpha" => character.is_alphabetic(),
+        "blank" => character == ' ' || character == '\t',
+        "cntrl" => character.is_control(),
+        "digit" => character.is_ascii_digit(),
+        "graph" => !character.is_whitespace() && !character.is_control(),
+        "lower" => character.is_lowercase(),
+        "print" => !character.is_control(),
+        "punct" => character.is_ascii_punctuation(),
+        "space" => character.is_whitespace(),
+        "upper" => character.is_uppercase(),
+        "xdigit" => character.is_ascii_hexdigit(),
+        _ => false,
+    };
+}
+
+/// Parses the inside of a bracket expression, starting right after the `[`.
+///
+/// Returns the element and the index after the closing `]`, or `None` if there
+/// is no closing `]`, in which case the `[` should be treated as a literal.
+fn parse_bracket(chars: &[char], start: usize) -> Option<(Element, usize)> {
+    let mut index = start;
+    let mut negated = false;
+    if index < chars.len() && (chars[index] == '!' || chars[index] == '^') {
+        negated = true;
+        index += 1;
+    }
+
+    let mut items: Vec<BracketItem> = Vec::new();
+    let mut first = true;
+    loop {
+        if index >= chars.len() {
+            return None;
+        }
+
+        let mut character = chars[index];
+        if character == ']' && !first {
+            return Some((Element::Bracket { negated, items }, index + 1));
+        }
+        first = false;
+
+        if character == '[' && chars.get(index + 1) == Some(&':') {
+            // Character class, as in "[:alpha:]"
+            let name_start = index + 2;
+            let mut name_end = name_start;
+            while name_end + 1 < chars.len()
+                && !(chars[name_end] == ':' && chars[name_end + 1] == ']')
+            {
+                name_end += 1;
+            }
+            if name_end + 1 < chars.len() {
+                items.push(BracketItem::Class(
+                    chars[name_start..name_end].iter().collect(),
+                ));
+                index = name_end + 2;
+                continue;
+            }
+        }
+
+        if character == '\\' && index + 1 < chars.len() {
+            index += 1;
+            character = chars[index];
+        }
+        index += 1;
+
+        if index + 1 < chars.len() && chars[index] == '-' && chars[index + 1] != ']' {
+            let mut last = chars[index + 1];
+            index += 2;
+            if last == '\\' && index < chars.len() {
+                last = chars[index];
+                index += 1;
+            }
+            items.push(BracketItem::Range(character, last));
+            continue;
+        }
+
+        items.push(BracketItem::Character(character));
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Self {
+        let chars: Vec<char> = pattern.chars().collect();
+        let mut elements: Vec<Element> = Vec::new();
+
+        let mut index = 0;
+        while index < chars.len() {
+            let character = chars[index];
+            index += 1;
+
+            match character {
+                '\\' => {
+                    if index < chars.len() {
+                        elements.push(Element::Literal(chars[index]));
+                        index += 1;
+                    } else {
+                        elements.push(Element::Literal('\\'));
+                    }
+                }
+                '?' => elements.push(Element::AnyCharacter),
+                '*' => {
+                    // Multiple stars in a row mean the same thing as one
+                    if elements.last() != Some(&Element::AnyString) {
+                        elements.push(Element::AnyString);
+                    }
+                }
+                '[' => {
+                    if let Some((bracket, next_index)) = parse_bracket(&chars, index) {
+                        elements.push(bracket);
+                        index = next_index;
+                    } else {
+                        elements.push(Element::Literal('['));
+                    }
+                }
+                _ => elements.push(Element::Literal(character)),
+            }
+        }
+
+        return Pattern { elements };
+    }
+
+    /// True if the whole text matches this pattern
+    pub fn matches(&self, text: &str) -> bool {
+        let chars: Vec<char> = text.chars().collect();
+        return matches_from(&self.elements, &chars);
+    }
+
+    /// Removes the shortest (or longest) prefix matching this pattern. Returns
+    /// `None` if no prefix matches.
+    pub fn remove_prefix(&self, text: &str, longest: bool) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut lengths: Vec<usize> = (0..=chars.len()).collect();
+        if longest {
+            lengths.reverse();
+        }
+
+        for length in lengths {
+            if matches_from(&self.elements, &chars[..length]) {
+                return Some(chars[length..].iter().collect());
+            }
+        }
+        return None;
+    }
+
+    /// Removes the shortest (or longest) suffix matching this pattern. Returns
+    /// `None` if no suffix matches.
+    pub fn remove_suffix(&self, text: &str, longest: bool) -> Option<String> {
+        let chars: Vec<char> = text.chars().collect();
+        let mut starts: Vec<usize> = (0..=chars.len()).collect();
+        if !longest {
+            starts.reverse();
+        }
+
+        for start in starts {
+            if matches_from(&self.elements, &chars[start..]) {
+                return Some(chars[..start].iter().collect());
+            }
+        }
+        return None;
+    }
+}
+
+fn bracket_matches(negated: bool, items: &[BracketItem], character: char) -> bool {
+    let found = items.iter().any(|item| match item {
+        BracketItem::Character(candidate) => *candidate == character,
+        BracketItem::Range(first, last) => *first <= character && character <= *last,
+        BracketItem::Class(class) => class_matches(class, character),
+    });
+    return found != negated;
+}
+
+fn matches_from(elements: &[Element], text: &[char]) -> bool {
+    if elements.is_empty() {
+        return text.is_empty();
+    }
+
+    match &elements[0] {
+        Element::AnyString => {
+            // Try matching the rest of the pattern at every possible position
+            for start in 0..=text.len() {
+                if matches_from(&elements[1..], &text[start..]) {
+                    return true;
+                }
+            }
+            return false;
+        }
+        Element::Literal(literal) => {
+            return !text.is_empty()
+                && text[0] == *literal
+                && matches_from(&elements[1..], &text[1..]);
+        }
+        Element::AnyCharacter => {
+            return !text.is_empty() && matches_from(&elements[1..], &text[1..]);
+        }
+        Element::Bracket { negated, items } => {
+            return !text.is_empty()
+                && bracket_matches(*negated, items, text[0])
+                && matches_from(&elements[1..], &text[1..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> bool {
+        return Pattern::new(pattern).matches(text);
+    }
+
+    #[test]
+    fn test_literal() {
+        assert!(matches("hej", "hej"));
+        assert!(!matches("hej", "hejsan"));
+        assert!(matches("", ""));
+        assert!(matches("ödla", "ödla"));
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*.rs", "main.rs"));
+        assert!(matches("*.rs", ".rs"));
+        assert!(!matches("*.rs", "main.rs~"));
+        assert!(matches("a*b*c", "aXXbYYc"));
+        assert!(matches("a**", "a"));
+        assert!(matches("?", "ö"));
+        assert!(!matches("?", ""));
+        assert!(!matches("?", "ab"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches(r"\*", "*"));
+        assert!(!matches(r"\*", "x"));
+        assert!(matches(r"a\?", "a?"));
+    }
+
+    #[test]
+    fn test_brackets() {
+        assert!(matches("[abc]", "b"));
+        assert!(!matches("[abc]", "d"));
+        assert!(matches("[!abc]", "d"));
+        assert!(matches("[^abc]", "d"));
+        assert!(matches("[a-z]x", "qx"));
+        assert!(!matches("[a-z]", "Q"));
+        assert!(matches("[]]", "]"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[[:digit:]]*", "1abc"));
+        assert!(!matches("[[:digit:]]", "a"));
+
+        // Unterminated brackets are literal
+        assert!(matches("[ab", "[ab"));
+    }
+
+    #[test]
+    fn test_remove_prefix() {
+        let pattern = Pattern::new("*/");
+        assert_eq!(
+            pattern.remove_prefix("a/b/c", false),
+            Some("b/c".to_string())
+        );
+        assert_eq!(pattern.remove_prefix("a/b/c", true), Some("c".to_string()));
+        assert_eq!(pattern.remove_prefix("abc", true), None);
+    }
+
+    #[test]
+    fn test_remove_suffix() {
+        let pattern = Pattern::new(".*");
+        assert_eq!(
+            pattern.remove_suffix("file.tar.gz", false),
+            Some("file.tar".to_string())
+        );
+        assert_eq!(
+            pattern.remove_suffix("file.tar.gz", true),
+            Some("file".to_string())
+        );
+        assert_eq!(pattern.remove_suffix("file", true), None);
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+use std::process;
+
+use nix::sys::wait::{waitpid, WaitStatus};
+use nix::unistd::{close, dup2, fork, getpgrp, pipe, setpgid, tcsetpgrp, ForkResult, Pid};
+
+use crate::ast::{Command, Pipeline};
+use crate::exitstatus::ExitStatus;
+use crate::interpreter::{run_command, run_command_in_place};
+use crate::jobs::{Job, Jobs};
+use crate::signals::restore_default_signal_handlers;
+use crate::Shell;
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+
+/// Blocks until the process is done
+pub(crate) fn wait_for(pid: Pid) -> ExitStatus {
+    loop {
+        match waitpid(pid, None) {
+            Ok(WaitStatus::Exited(_, code)) => return ExitStatus::from_code(code),
+            Ok(WaitStatus::Signaled(_, signal, false)) => {
+                return ExitStatus::from_signal(signal as i32)
+            }
+            Ok(WaitStatus::Signaled(_, signal, true)) => {
+                return ExitStatus::from_core_dump(signal as i32)
+            }
+            Ok(_) => {
+                // Stopped or continued, keep waiting
+                continue;
+            }
+            Err(nix::errno::Errno::EINTR) => continue,
+            Err(error) => {
+                println!("Awaiting child process failed: {}", error);
+                return ExitStatus::failure(1, &error.to_string());
+            }
+        }
+    }
+}
+
+impl Shell {
+    /// Runs each command in its own child process, with the output of each
+    /// command connected to the input of the next one through a pipe.
+    ///
+    /// With job control, all children are put in the same process group,
+    /// which gets the terminal while the pipeline runs.
+    pub(crate) fn run_pipeline(&mut self, pipeline: &Pipeline) -> Vec<ExitStatus> {
+        // Describes the job if the user stops it
+        self.foreground_text = pipeline.text.clone();
+
+        let commands = &pipeline.commands;
+        if commands.len() == 1 {
+            // Run in this process, so that for example "cd" works
+            return vec![run_command(self, &commands[0])];
+        }
+
+        let terminal = self.terminal;
+        let job_control = terminal.is_some();
+
+        // Anything left in our output buffer would otherwise be printed once by
+        // each child as well
+        io::stdout().flush().unwrap();
+
+        let mut pgid: Option<Pid> = None;
+        let mut children: Vec<Pid> = Vec::new();
+        let mut failures: Vec<ExitStatus> = Vec::new();
+
+        // Read end of the pipe from the previous command
+        let mut previous_output: Option<RawFd> = None;
+
+        for (index, command) in commands.iter().enumerate() {
+            let mut output: Option<(RawFd, RawFd)> = None;
+            if index < commands.len() - 1 {
+                match pipe() {
+                    Ok(read_write) => output = Some(read_write),
+                    Err(error) => {
+                        println!("ERROR: Creating pipe failed: {}", error);
+                        failures.push(ExitStatus::failure(1, "Pipe failed"));
+                        break;
+                    }
+                }
+            }
+
+            match unsafe { fork() } {
+                Ok(ForkResult::Child) => {
+                    self.run_pipeline_child(
+                        command,
+                        job_control.then_some(pgid),
+                        terminal,
+                        previous_output,
+                        output,
+                    );
+                }
+                Ok(ForkResult::Parent { child }) => {
+                    if job_control {
+                        // Done both here and in the child to avoid races
+                        let _ = setpgid(child, pgid.unwrap_or(child));
+                    }
+                    if pgid.is_none() {
+                        pgid = Some(child);
+                        if let Some(terminal) = terminal {
+                            let _ = tcsetpgrp(terminal, child);
+                        }
+                    }
+                    children.push(child);
+                }
+                Err(error) => {
+                    println!("ERROR: Forking failed: {}", error);
+                    failures.push(ExitStatus::failure(1, "Fork failed"));
+                }
+            }
+
+            if let Some(fd) = previous_output {
+                let _ = close(fd);
+            }
+            previous_output = None;
+            if let Some((read, write)) = output {
+                let _ = close(write);
+                previous_output = Some(read);
+            }
+
+            if !failures.is_empty() {
+                break;
+            }
+        }
+        if let Some(fd) = previous_output {
+            let _ = close(fd);
+        }
+
+        let mut statuses: Vec<ExitStatus> = Vec::new();
+        if !children.is_empty() {
+            let pgid = if job_control {
+                pgid.unwrap()
+            } else {
+                getpgrp()
+            };
+            statuses = self.wait_for_foreground(Job::new(pgid, &children, &pipeline.text));
+        }
+        statuses.append(&mut failures);
+
+        return statuses;
+    }
+
+    /// Never returns, exits the child process when the command is done.
+    ///
+    /// With job control, `pgid` is set, and contains the process group to
+    /// join, or `None` for the first child, which starts a new one.
+    fn run_pipeline_child(
+        &mut self,
+        command: &Command,
+        pgid: Option<Option<Pid>>,
+        terminal: Option<RawFd>,
+        input: Option<RawFd>,
+        output: Option<(RawFd, RawFd)>,
+    ) -> ! {
+        if let Some(pgid) = pgid {
+            let _ = setpgid(Pid::from_raw(0), pgid.unwrap_or_else(|| Pid::from_raw(0)));
+        }
+        if let Some(terminal) = terminal {
+            let _ = tcsetpgrp(terminal, getpgrp());
+        }
+        restore_default_signal_handlers();
+
+        // Jobs are managed by the interactive shell only
+        self.terminal = None;
+        self.jobs = Jobs::default();
+
+        if let Some(fd) = input {
+            let _ = dup2(fd, STDIN);
+            let _ = close(fd);
+        }
+        if let Some((read, write)) = output {
+            let _ = dup2(write, STDOUT);
+            let _ = close(write);
+            let _ = close(read);
+        }
+
+        // If this is an external command, we can replace this child process
+        // with it rather than spawning yet another process and waiting for that
+        let status = run_command_in_place(self, command);
+
+        // Note that exit() won't run any destructors, which is what we want.
+        // For example, the shell history should be saved by our parent, not
+        // by us.
+        io::stdout().flush().unwrap_or_default();
+        process::exit(status.code);
+    }
+}
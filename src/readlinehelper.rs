@@ -0,0 +1,111 @@
+use std::borrow::Cow;
+
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use crate::completion::ShellCompleter;
+use crate::parser::highlight;
+
+const RESET: &str = "\x1b[0m";
+
+/// The ANSI color sequence for a highlighting code from `parser::highlight()`,
+/// or `None` for text that should be left as-is
+fn color(highlighting_code: char) -> Option<&'static str> {
+    return match highlighting_code {
+        '0' => Some("\x1b[34m"),   // Blue
+        'a' => Some("\x1b[36m"),   // Cyan
+        'A' => Some("\x1b[96m"),   // Bright cyan
+        'c' => Some("\x1b[90m"),   // Gray
+        'e' => Some("\x1b[4;31m"), // Underlined red
+        'k' => Some("\x1b[35m"),   // Magenta
+        'v' => Some("\x1b[33m"),   // Yellow
+        'x' => Some("\x1b[32m"),   // Green
+        _ => None,
+    };
+}
+
+/// Colors the command line according to its highlighting codes, one per
+/// character
+fn colorize(commandline: &str, highlights: &str) -> String {
+    let mut colorized = String::new();
+    let mut current: Option<&str> = None;
+    for (character, highlighting_code) in commandline.chars().zip(highlights.chars()) {
+        let wanted = color(highlighting_code);
+        if wanted != current {
+            if current.is_some() {
+                colorized.push_str(RESET);
+            }
+            if let Some(wanted) = wanted {
+                colorized.push_str(wanted);
+            }
+            current = wanted;
+        }
+        colorized.push(character);
+    }
+    if current.is_some() {
+        colorized.push_str(RESET);
+    }
+
+    return colorized;
+}
+
+/// Plugs our parser into the line editor, so that the command line is
+/// syntax highlighted as the user types, and completes words on tab.
+///
+/// Ref: https://docs.rs/rustyline/9.1.1/rustyline/trait.Helper.html
+pub(crate) struct ReadlineHelper {
+    pub completer: ShellCompleter,
+}
+
+impl Highlighter for ReadlineHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        return Cow::Owned(colorize(line, &highlight(line)));
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        // Any character can change how the whole line parses
+        return true;
+    }
+}
+
+impl Completer for ReadlineHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        return Ok(self.completer.complete(line, pos));
+    }
+}
+
+impl Hinter for ReadlineHelper {
+    type Hint = String;
+}
+
+impl Validator for ReadlineHelper {}
+
+impl Helper for ReadlineHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_colorize() {
+        assert_eq!(
+            colorize("ls  -l", "00  aa"),
+            "\x1b[34mls\x1b[0m  \x1b[36m-l\x1b[0m"
+        );
+        assert_eq!(
+            colorize("a|b", "0x0"),
+            "\x1b[34ma\x1b[0m\x1b[32m|\x1b[0m\x1b[34mb\x1b[0m"
+        );
+        assert_eq!(colorize("åäö", "   "), "åäö");
+    }
+}
@@ -0,0 +1,201 @@
+use std::io::{self, Write};
+use std::os::unix::io::RawFd;
+
+use nix::errno::Errno;
+use nix::fcntl::{fcntl, open, FcntlArg, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup2};
+
+use crate::ast::RedirectionOperator;
+use crate::exitstatus::ExitStatus;
+
+/// A redirection with its target expanded, ready to be applied
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Redirect {
+    /// The file descriptor being redirected
+    pub fd: RawFd,
+
+    pub operator: RedirectionOperator,
+
+    /// File name or file descriptor number
+    pub target: String,
+}
+
+impl Redirect {
+    /// The file descriptor a redirection applies to when there is no IO number
+    /// in front of the operator.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_07
+    pub fn default_fd(operator: RedirectionOperator) -> RawFd {
+        return match operator {
+            RedirectionOperator::Input
+            | RedirectionOperator::ReadWrite
+            | RedirectionOperator::DuplicateInput => 0,
+            RedirectionOperator::Output
+            | RedirectionOperator::Clobber
+            | RedirectionOperator::Append
+            | RedirectionOperator::DuplicateOutput => 1,
+        };
+    }
+}
+
+/// How to undo one redirection
+pub(crate) struct SavedFd {
+    fd: RawFd,
+
+    /// A copy of what `fd` was before the redirection, or `None` if `fd`
+    /// wasn't open before
+    copy: Option<RawFd>,
+}
+
+/// Short error descriptions for the prompt
+fn describe_errno(errno: Errno) -> String {
+    return match errno {
+        Errno::ENOENT => "No such file".to_string(),
+        Errno::EACCES => "Permission denied".to_string(),
+        Errno::EISDIR => "Is a directory".to_string(),
+        Errno::EBADF => "Bad file descriptor".to_string(),
+        _ => errno.desc().to_string(),
+    };
+}
+
+fn open_flags(operator: RedirectionOperator) -> OFlag {
+    return match operator {
+        RedirectionOperator::Input => OFlag::O_RDONLY,
+        // FIXME: Make > fail on existing files if "set -C" is active
+        RedirectionOperator::Output | RedirectionOperator::Clobber => {
+            OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_TRUNC
+        }
+        RedirectionOperator::Append => OFlag::O_WRONLY | OFlag::O_CREAT | OFlag::O_APPEND,
+        RedirectionOperator::ReadWrite => OFlag::O_RDWR | OFlag::O_CREAT,
+        RedirectionOperator::DuplicateInput | RedirectionOperator::DuplicateOutput => {
+            panic!("Duplications are not opened")
+        }
+    };
+}
+
+/// Make a close-on-exec copy of `fd` so that we can restore it later
+fn save(fd: RawFd) -> SavedFd {
+    // Stay above 10, shell users are allowed to use 0-9 for their own purposes
+    let copy = fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(10));
+    return SavedFd {
+        fd,
+        copy: copy.ok(),
+    };
+}
+
+/// On failure, returns an error message together with the exit status. The
+/// message isn't printed here, since stdout may be redirected by now.
+fn apply(redirect: &Redirect) -> Result<(), (String, ExitStatus)> {
+    let is_duplication = redirect.operator == RedirectionOperator::DuplicateInput
+        || redirect.operator == RedirectionOperator::DuplicateOutput;
+
+    if is_duplication {
+        if redirect.target == "-" {
+            // Closing an fd that isn't open is fine
+            let _ = close(redirect.fd);
+            return Ok(());
+        }
+
+        let source = redirect.target.parse::<RawFd>();
+        if source.is_err() {
+            return Err((
+                format!(
+                    "Expected a file descriptor number or - after {}, got: {}",
+                    redirect.operator, redirect.target
+                ),
+                ExitStatus::failure(1, "Bad redirect"),
+            ));
+        }
+
+        if let Err(errno) = dup2(source.unwrap(), redirect.fd) {
+            return Err((
+                format!(
+                    "Cannot duplicate file descriptor {}: {}",
+                    redirect.target, errno
+                ),
+                ExitStatus::failure(1, &describe_errno(errno)),
+            ));
+        }
+        return Ok(());
+    }
+
+    let mode = Mode::from_bits_truncate(0o666);
+    let opened = open(
+        redirect.target.as_str(),
+        open_flags(redirect.operator) | OFlag::O_CLOEXEC,
+        mode,
+    );
+    if let Err(errno) = opened {
+        return Err((
+            format!("Cannot open {}: {}", redirect.target, errno),
+            ExitStatus::failure(1, &describe_errno(errno)),
+        ));
+    }
+
+    let opened = opened.unwrap();
+    if opened == redirect.fd {
+        // We got the fd we wanted, just make sure it survives exec
+        let _ = fcntl(opened, FcntlArg::F_SETFD(nix::fcntl::FdFlag::empty()));
+        return Ok(());
+    }
+
+    let result = dup2(opened, redirect.fd);
+    let _ = close(opened);
+    if let Err(errno) = result {
+        return Err((
+            format!(
+                "Cannot redirect file descriptor {} to {}: {}",
+                redirect.fd, redirect.target, errno
+            ),
+            ExitStatus::failure(1, &describe_errno(errno)),
+        ));
+    }
+
+    return Ok(());
+}
+
+/// Applies the redirections to the current process, in order.
+///
+/// On success, returns what needs to be passed to `restore()` to undo the
+/// redirections. On failure, all redirections have already been undone.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_07
+pub(crate) fn redirect(redirects: &[Redirect]) -> Result<Vec<SavedFd>, ExitStatus> {
+    // Whatever has been printed so far should go where it was meant to go
+    io::stdout().flush().unwrap_or_default();
+
+    let mut saved: Vec<SavedFd> = Vec::new();
+    for redirect in redirects {
+        saved.push(save(redirect.fd));
+
+        if let Err((message, status)) = apply(redirect) {
+            // Back to the terminal before telling the user
+            restore(saved);
+            println!("ERROR: {}", message);
+            return Err(status);
+        }
+    }
+
+    return Ok(saved);
+}
+
+/// Undoes whatever `redirect()` did
+pub(crate) fn restore(saved: Vec<SavedFd>) {
+    // Whatever has been printed so far should go to the redirection target
+    io::stdout().flush().unwrap_or_default();
+
+    // Restore in reverse order, in case the same fd has been redirected more
+    // than once
+    for saved_fd in saved.into_iter().rev() {
+        match saved_fd.copy {
+            Some(copy) => {
+                let _ = dup2(copy, saved_fd.fd);
+                let _ = close(copy);
+            }
+            None => {
+                let _ = close(saved_fd.fd);
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use nix::libc::c_int;
+use nix::sys::signal::{sigaction, signal, SaFlags, SigAction, SigHandler, SigSet, Signal};
+
+/// Signals that the shell itself ignores, but that should have their default
+/// behavior in child processes
+static SHELL_IGNORED_SIGNALS: [Signal; 4] = [
+    // Needed for taking back the terminal after a foreground pipeline is done
+    Signal::SIGTTOU,
+    // Ctrl-Z should stop the foreground job, not us
+    Signal::SIGTSTP,
+    // Reading from the terminal while a job has it should not stop us
+    Signal::SIGTTIN,
+    // Ctrl-\ is for quitting the foreground job
+    Signal::SIGQUIT,
+];
+
+/// Set by our SIGCHLD handler, see `take_child_status_changed()`
+static CHILD_STATUS_CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Set by our SIGINT handler, see `take_interrupted()`
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_sigchld(_signal: c_int) {
+    CHILD_STATUS_CHANGED.store(true, Ordering::SeqCst);
+}
+
+extern "C" fn on_sigint(_signal: c_int) {
+    INTERRUPTED.store(true, Ordering::SeqCst);
+}
+
+/// Installs a signal handler. With `SA_RESTART` in `flags`, any system calls
+/// it interrupts are restarted rather than failing with `EINTR`.
+fn handle(signal: Signal, handler: extern "C" fn(c_int), flags: SaFlags) {
+    let action = SigAction::new(SigHandler::Handler(handler), flags, SigSet::empty());
+    if let Err(error) = unsafe { sigaction(signal, &action) } {
+        println!("WARNING: Failed to handle {}: {}", signal, error);
+    }
+}
+
+/// Call at shell startup
+pub(crate) fn ignore_shell_signals() {
+    for ignored in SHELL_IGNORED_SIGNALS {
+        if let Err(error) = unsafe { signal(ignored, SigHandler::SigIgn) } {
+            println!("WARNING: Failed to ignore {}: {}", ignored, error);
+        }
+    }
+
+    // Nobody wants to see reading a line fail just because some background
+    // job finished
+    handle(Signal::SIGCHLD, on_sigchld, SaFlags::SA_RESTART);
+
+    // Rather than dying, we stop running the current command line. At the
+    // prompt, ctrl-c is handled by the line editor, and foreground jobs get
+    // their own process groups that receive it instead of us.
+    //
+    // No SA_RESTART, so that ctrl-c can get us out of blocking calls, like
+    // waiting for background jobs in `wait`.
+    handle(Signal::SIGINT, on_sigint, SaFlags::empty());
+}
+
+/// True if some child process has exited, stopped or continued since the
+/// last call
+pub(crate) fn take_child_status_changed() -> bool {
+    return CHILD_STATUS_CHANGED.swap(false, Ordering::SeqCst);
+}
+
+/// True if we got a SIGINT since the last call
+pub(crate) fn take_interrupted() -> bool {
+    return INTERRUPTED.swap(false, Ordering::SeqCst);
+}
+
+/// Like `take_interrupted()`, but leaves the SIGINT for someone else to take
+pub(crate) fn is_interrupted() -> bool {
+    return INTERRUPTED.load(Ordering::SeqCst);
+}
+
+/// Call in child processes, before running any commands.
+///
+/// This function is async-signal-safe, so it can be used in
+/// `Command::pre_exec()`.
+pub(crate) fn restore_default_signal_handlers() {
+    for ignored in SHELL_IGNORED_SIGNALS {
+        // Nothing sensible we can do on failure, just go on
+        let _ = unsafe { signal(ignored, SigHandler::SigDfl) };
+    }
+
+    // Rust ignores SIGPIPE by default, but in a pipeline we want writers to
+    // die when their reader goes away
+    let _ = unsafe { signal(Signal::SIGPIPE, SigHandler::SigDfl) };
+
+    // Exec resets handled signals, but forked shells running builtins should
+    // die from ctrl-c just like any other command
+    let _ = unsafe { signal(Signal::SIGINT, SigHandler::SigDfl) };
+}
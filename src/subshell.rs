@@ -0,0 +1,239 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::process;
+
+use nix::fcntl::{open, OFlag};
+use nix::sys::stat::Mode;
+use nix::unistd::{close, dup2, fork, getpgrp, pipe, setpgid, tcsetpgrp, ForkResult, Pid};
+
+use crate::ast::{AndOr, Command, List, Separator};
+use crate::exitstatus::ExitStatus;
+use crate::interpreter::{run, run_and_or, run_command_in_place};
+use crate::jobs::{Job, Jobs};
+use crate::pipeline::wait_for;
+use crate::signals::restore_default_signal_handlers;
+use crate::Shell;
+
+const STDIN: RawFd = 0;
+const STDOUT: RawFd = 1;
+
+/// If the and-or list is nothing but one command, returns that command
+fn single_command(and_or: &AndOr) -> Option<&Command> {
+    if !and_or.rest.is_empty() || and_or.first.bang || and_or.first.commands.len() != 1 {
+        return None;
+    }
+    return Some(&and_or.first.commands[0]);
+}
+
+/// Runs the and-or list, replacing the current process if possible
+fn run_and_or_in_place(shell: &mut Shell, and_or: &AndOr) -> ExitStatus {
+    match single_command(and_or) {
+        // No need to spawn yet another process and wait for it
+        Some(command) => return run_command_in_place(shell, command),
+        None => return run_and_or(shell, and_or),
+    }
+}
+
+/// Runs the program, replacing the current process if possible
+fn run_in_place(shell: &mut Shell, program: &List) -> ExitStatus {
+    if program.items.len() != 1 || program.items[0].1 != Separator::Sequential {
+        return run(shell, program);
+    }
+    return run_and_or_in_place(shell, &program.items[0].0);
+}
+
+/// How a forked child should relate to the terminal
+#[derive(Clone, Copy, PartialEq)]
+enum ChildJob {
+    /// Stay in our process group, as for command substitutions
+    SameGroup,
+
+    /// Get a process group of its own and the terminal
+    Foreground,
+
+    /// Get a process group of its own, but leave the terminal to us
+    Background,
+}
+
+impl Shell {
+    /// Starts a child process running `body`. The child gets a copy of all
+    /// our state, so whatever it changes, our own state stays the same.
+    ///
+    /// If `output` is set, the child's stdout is connected to its write end.
+    fn fork_subshell(
+        &mut self,
+        body: impl FnOnce(&mut Shell) -> ExitStatus,
+        job: ChildJob,
+        output: Option<(RawFd, RawFd)>,
+    ) -> Result<Pid, ExitStatus> {
+        let new_group = self.terminal.is_some() && job != ChildJob::SameGroup;
+        let terminal = self.terminal.filter(|_| job == ChildJob::Foreground);
+
+        // Anything left in our output buffer would otherwise be printed by the
+        // child as well
+        io::stdout().flush().unwrap_or_default();
+
+        match unsafe { fork() } {
+            Ok(ForkResult::Child) => {
+                if new_group {
+                    let _ = setpgid(Pid::from_raw(0), Pid::from_raw(0));
+                    if let Some(terminal) = terminal {
+                        let _ = tcsetpgrp(terminal, getpgrp());
+                    }
+                    restore_default_signal_handlers();
+                }
+
+                // Jobs are managed by the interactive shell only
+                self.terminal = None;
+                self.jobs = Jobs::default();
+
+                // Whatever our parent was about to replace itself with, the
+                // child has its own commands to run
+                self.exec_in_place = false;
+
+                if let Some((read, write)) = output {
+                    let _ = dup2(write, STDOUT);
+                    let _ = close(write);
+                    let _ = close(read);
+                }
+
+                let status = body(self);
+
+                // Note that exit() won't run any destructors, which is what we
+                // want. For example, the shell history should be saved by our
+                // parent, not by us.
+                io::stdout().flush().unwrap_or_default();
+                process::exit(status.code);
+            }
+            Ok(ForkResult::Parent { child }) => {
+                if new_group {
+                    // Done both here and in the child to avoid races
+                    let _ = setpgid(child, child);
+                }
+                return Ok(child);
+            }
+            Err(error) => {
+                println!("ERROR: Forking failed: {}", error);
+                return Err(ExitStatus::failure(1, "Fork failed"));
+            }
+        }
+    }
+
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_12
+    pub(crate) fn run_subshell(&mut self, program: &List) -> ExitStatus {
+        let child = match self.fork_subshell(
+            |shell| run_in_place(shell, program),
+            ChildJob::Foreground,
+            None,
+        ) {
+            Ok(child) => child,
+            Err(status) => return status,
+        };
+
+        let pgid = if self.terminal.is_some() {
+            child
+        } else {
+            getpgrp()
+        };
+        let text = self.foreground_text.clone();
+        let statuses = self.wait_for_foreground(Job::new(pgid, &[child], &text));
+        return statuses.last().unwrap().clone();
+    }
+
+    /// Starts running the and-or list in a child process, without waiting for
+    /// it.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_09_03_02
+    pub(crate) fn run_background(&mut self, and_or: &AndOr) -> ExitStatus {
+        let job_control = self.terminal.is_some();
+        let child = self.fork_subshell(
+            |shell| {
+                if !job_control {
+                    // POSIX says background jobs shouldn't read from our
+                    // stdin unless they can be brought to the foreground
+                    if let Ok(null) = open("/dev/null", OFlag::O_RDONLY, Mode::empty()) {
+                        let _ = dup2(null, STDIN);
+                        let _ = close(null);
+                    }
+                }
+                return run_and_or_in_place(shell, and_or);
+            },
+            ChildJob::Background,
+            None,
+        );
+        let child = match child {
+            Ok(child) => child,
+            Err(status) => return status,
+        };
+
+        self.last_background_pid = Some(child);
+        let pgid = if job_control { child } else { getpgrp() };
+        self.add_background_job(Job::new(pgid, &[child], &and_or.text()));
+        return ExitStatus::success();
+    }
+
+    /// Runs the program in a subshell, and returns what it printed to stdout
+    /// together with its exit status.
+    ///
+    /// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+    pub(crate) fn capture_output(&mut self, program: &List) -> (String, ExitStatus) {
+        let (read, write) = match pipe() {
+            Ok(read_write) => read_write,
+            Err(error) => {
+                println!("ERROR: Creating pipe failed: {}", error);
+                return (String::new(), ExitStatus::failure(1, "Pipe failed"));
+            }
+        };
+
+        let child = self.fork_subshell(
+            |shell| run_in_place(shell, program),
+            ChildJob::SameGroup,
+            Some((read, write)),
+        );
+
+        // Otherwise we'd never see the end of the child's output
+        let _ = close(write);
+
+        // Read everything before waiting, or a child printing more than fits
+        // in the pipe would block forever
+        let mut bytes: Vec<u8> = Vec::new();
+        let mut file = unsafe { File::from_raw_fd(read) };
+        if let Err(error) = file.read_to_end(&mut bytes) {
+            println!("WARNING: Reading command output failed: {}", error);
+        }
+        drop(file);
+
+        let status = match child {
+            Ok(child) => wait_for(child),
+            Err(status) => status,
+        };
+        return (String::from_utf8_lossy(&bytes).to_string(), status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parser::parse;
+
+    /// Runs the command line in a subshell and returns what it printed
+    fn output_of(commandline: &str) -> String {
+        let mut shell = Shell::without_terminal();
+        let parsed = parse(commandline).unwrap();
+        return shell.capture_output(&parsed.program).0;
+    }
+
+    #[test]
+    fn test_substitution_in_pipeline() {
+        assert_eq!(output_of("echo $(echo a; echo b) | cat"), "a b\n");
+        assert_eq!(output_of("echo x | echo $(echo a; echo b)"), "a b\n");
+    }
+
+    #[test]
+    fn test_substitution_in_subshell() {
+        assert_eq!(output_of("(echo $(echo a; echo b))"), "a b\n");
+        assert_eq!(output_of("(echo $(echo a; echo b)) && echo c"), "a b\nc\n");
+    }
+}
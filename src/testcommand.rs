@@ -0,0 +1,223 @@
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+use nix::unistd::{access, AccessFlags};
+
+use crate::exitstatus::ExitStatus;
+
+/// Exit status of `test` when the arguments don't make sense
+const USAGE_ERROR: i32 = 2;
+
+fn usage_error(message: &str) -> Result<bool, ExitStatus> {
+    println!("ERROR: test: {}", message);
+    return Err(ExitStatus::failure(USAGE_ERROR, message));
+}
+
+fn is_unary_operator(operator: &str) -> bool {
+    return matches!(
+        operator,
+        "-b" | "-c"
+            | "-d"
+            | "-e"
+            | "-f"
+            | "-g"
+            | "-h"
+            | "-L"
+            | "-n"
+            | "-p"
+            | "-r"
+            | "-S"
+            | "-s"
+            | "-u"
+            | "-w"
+            | "-x"
+            | "-z"
+    );
+}
+
+fn is_binary_operator(operator: &str) -> bool {
+    return matches!(
+        operator,
+        "=" | "!=" | "-eq" | "-ne" | "-gt" | "-ge" | "-lt" | "-le"
+    );
+}
+
+fn unary(operator: &str, operand: &str) -> bool {
+    match operator {
+        "-n" => return !operand.is_empty(),
+        "-z" => return operand.is_empty(),
+        "-r" => return access(operand, AccessFlags::R_OK).is_ok(),
+        "-w" => return access(operand, AccessFlags::W_OK).is_ok(),
+        "-x" => return access(operand, AccessFlags::X_OK).is_ok(),
+        "-h" | "-L" => {
+            return fs::symlink_metadata(operand)
+                .map(|metadata| metadata.file_type().is_symlink())
+                .unwrap_or(false)
+        }
+        _ => {}
+    }
+
+    // The rest are about what the operand points to
+    let metadata = fs::metadata(operand);
+    if metadata.is_err() {
+        return false;
+    }
+    let metadata = metadata.unwrap();
+    let file_type = metadata.file_type();
+    let mode = metadata.permissions().mode();
+
+    return match operator {
+        "-b" => file_type.is_block_device(),
+        "-c" => file_type.is_char_device(),
+        "-d" => file_type.is_dir(),
+        "-e" => true,
+        "-f" => file_type.is_file(),
+        "-g" => mode & 0o2000 != 0,
+        "-p" => file_type.is_fifo(),
+        "-S" => file_type.is_socket(),
+        "-s" => metadata.len() > 0,
+        "-u" => mode & 0o4000 != 0,
+        _ => panic!("Not a unary operator: {}", operator),
+    };
+}
+
+fn parse_integer(text: &str) -> Result<i64, ExitStatus> {
+    match text.trim().parse::<i64>() {
+        Ok(number) => return Ok(number),
+        Err(_) => {
+            let message = format!("Integer expected, got: {}", text);
+            println!("ERROR: test: {}", message);
+            return Err(ExitStatus::failure(USAGE_ERROR, &message));
+        }
+    }
+}
+
+fn binary(left: &str, operator: &str, right: &str) -> Result<bool, ExitStatus> {
+    match operator {
+        "=" => return Ok(left == right),
+        "!=" => return Ok(left != right),
+        _ => {}
+    }
+
+    let left = parse_integer(left)?;
+    let right = parse_integer(right)?;
+    return Ok(match operator {
+        "-eq" => left == right,
+        "-ne" => left != right,
+        "-gt" => left > right,
+        "-ge" => left >= right,
+        "-lt" => left < right,
+        "-le" => left <= right,
+        _ => panic!("Not a binary operator: {}", operator),
+    });
+}
+
+/// Evaluates the expression using the POSIX rules, which depend on the number
+/// of arguments
+fn evaluate(args: &[&str]) -> Result<bool, ExitStatus> {
+    match args.len() {
+        0 => return Ok(false),
+        1 => return Ok(!args[0].is_empty()),
+        2 => {
+            if args[0] == "!" {
+                return Ok(!evaluate(&args[1..])?);
+            }
+            if is_unary_operator(args[0]) {
+                return Ok(unary(args[0], args[1]));
+            }
+            return usage_error(&format!("Unknown unary operator: {}", args[0]));
+        }
+        3 => {
+            if is_binary_operator(args[1]) {
+                return binary(args[0], args[1], args[2]);
+            }
+            if args[0] == "!" {
+                return Ok(!evaluate(&args[1..])?);
+            }
+            if args[0] == "(" && args[2] == ")" {
+                return evaluate(&args[1..2]);
+            }
+            return usage_error(&format!("Unknown binary operator: {}", args[1]));
+        }
+        4 => {
+            if args[0] == "!" {
+                return Ok(!evaluate(&args[1..])?);
+            }
+            if args[0] == "(" && args[3] == ")" {
+                return evaluate(&args[1..3]);
+            }
+            return usage_error("Too many arguments");
+        }
+        _ => return usage_error("Too many arguments"),
+    }
+}
+
+/// The `test` and `[` builtins. `command` is the name we were called as, `[`
+/// wants a closing `]` as its last argument.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/test.html
+pub(crate) fn test(command: &str, args: &[String]) -> ExitStatus {
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if command == "[" {
+        if args.last() != Some(&"]") {
+            println!("ERROR: [: Missing closing ]");
+            return ExitStatus::failure(USAGE_ERROR, "Missing ]");
+        }
+        args.pop();
+    }
+
+    match evaluate(&args) {
+        Ok(true) => return ExitStatus::success(),
+        Ok(false) => return ExitStatus::from_code(1),
+        Err(status) => return status,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_code(command: &str, args: &[&str]) -> i32 {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        return test(command, &args).code;
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(test_code("test", &[]), 1);
+        assert_eq!(test_code("test", &["x"]), 0);
+        assert_eq!(test_code("test", &[""]), 1);
+        assert_eq!(test_code("[", &["-n", "", "]"]), 1);
+        assert_eq!(test_code("[", &["-z", "", "]"]), 0);
+        assert_eq!(test_code("[", &["a", "=", "a", "]"]), 0);
+        assert_eq!(test_code("[", &["!", "a", "=", "b", "]"]), 0);
+        assert_eq!(test_code("[", &["a", "!=", "a", "]"]), 1);
+
+        // One argument is always a string, even if it looks like an operator
+        assert_eq!(test_code("test", &["-n"]), 0);
+        assert_eq!(test_code("test", &["!"]), 0);
+    }
+
+    #[test]
+    fn test_integers() {
+        assert_eq!(test_code("test", &["10", "-gt", "9"]), 0);
+        assert_eq!(test_code("test", &["1", "-eq", " 1"]), 0);
+        assert_eq!(test_code("test", &["1", "-le", "0"]), 1);
+        assert_eq!(test_code("test", &["x", "-lt", "1"]), 2);
+    }
+
+    #[test]
+    fn test_files() {
+        assert_eq!(test_code("test", &["-d", "/"]), 0);
+        assert_eq!(test_code("test", &["-f", "/"]), 1);
+        assert_eq!(test_code("test", &["-e", "/does/not/exist"]), 1);
+    }
+
+    #[test]
+    fn test_usage_errors() {
+        assert_eq!(test_code("[", &["x"]), 2);
+        assert_eq!(test_code("test", &["a", "-foo", "b"]), 2);
+        assert_eq!(test_code("test", &["a", "b", "c", "d", "e"]), 2);
+        assert_eq!(test_code("test", &["(", "x", ")"]), 0);
+    }
+}
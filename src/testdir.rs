@@ -0,0 +1,48 @@
+use std::env;
+use std::fs;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::process;
+
+/// A directory for tests to create files in. It's removed together with
+/// everything in it when dropped, so that failing tests don't leave anything
+/// behind either.
+///
+/// Dereferences to its `Path`, and can be passed to `std::fs` functions.
+pub(crate) struct TestDir {
+    path: PathBuf,
+}
+
+impl TestDir {
+    /// Tests run in parallel, so `name` should be unique to the test
+    pub fn new(name: &str) -> Self {
+        let path = env::temp_dir().join(format!("vpixsh-{}-{}", name, process::id()));
+
+        // Left over from some earlier test run with our process ID
+        let _ = fs::remove_dir_all(&path);
+
+        fs::create_dir_all(&path).unwrap();
+        return TestDir { path };
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        return &self.path;
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        // The test may have removed it already
+        let _ = fs::remove_dir_all(&self.path);
+    }
+}
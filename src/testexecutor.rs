@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use nix::sys::signal::Signal;
+
+use crate::ast::{AndOr, Command, List, Pipeline};
+use crate::exitstatus::ExitStatus;
+use crate::interpreter::{
+    call_function, run, run_and_or, run_command, source, ControlFlow, Executor, InterpreterState,
+};
+use crate::redirection::{Redirect, SavedFd};
+use crate::testcommand::test;
+
+/// An executor that records what would have been executed rather than
+/// executing anything.
+///
+/// Functions, sourcing, `test` and enough of `export` and `unset` to run
+/// scripts like the `venv` `activate` script are emulated. The `interrupted`
+/// command acts like a command the user pressed ctrl-c at.
+pub(crate) struct TestExecutor {
+    pub executions: Vec<String>,
+    variables: HashMap<String, String>,
+    functions: HashMap<String, Rc<Command>>,
+    positional_parameters: Vec<String>,
+
+    /// One buffer per command substitution we're currently in. `echo`
+    /// commands write to the innermost one.
+    captures: Vec<String>,
+
+    state: InterpreterState,
+}
+
+impl Executor for TestExecutor {
+    fn execute(
+        &mut self,
+        command: &str,
+        args: &[String],
+        assignments: &[(String, String)],
+    ) -> ExitStatus {
+        let mut command_with_args: Vec<String> = vec![command.to_owned()];
+
+        for arg in args {
+            command_with_args.push(arg.to_owned());
+        }
+
+        // Like "FOO=1 exec('env')"
+        let mut execution = String::new();
+        for (name, value) in assignments {
+            execution.push_str(&format!("{}={} ", name, value));
+        }
+        execution.push_str(&format!("exec('{}')", command_with_args.join("', '")));
+        self.executions.push(execution);
+
+        if command == "echo" {
+            if let Some(capture) = self.captures.last_mut() {
+                capture.push_str(&args.join(" "));
+                capture.push('\n');
+            }
+        }
+
+        if let Some(body) = self.functions.get(command).cloned() {
+            return call_function(self, &body, args);
+        }
+
+        match command {
+            "false" => return ExitStatus::from_code(1),
+            "interrupted" => {
+                // Like a command the user pressed ctrl-c at
+                self.state.control_flow = ControlFlow::Interrupt;
+                return ExitStatus::from_signal(Signal::SIGINT as i32);
+            }
+            "[" | "test" => return test(command, args),
+            "." | "source" => return source(self, args),
+            "export" => {
+                for arg in args {
+                    if let Some((name, value)) = arg.split_once('=') {
+                        self.set_variable(name, value);
+                    }
+                }
+            }
+            "unset" => {
+                if args.first().map(String::as_str) == Some("-f") {
+                    for name in &args[1..] {
+                        self.functions.remove(name);
+                    }
+                } else {
+                    for name in args {
+                        self.variables.remove(name);
+                    }
+                }
+            }
+            _ => {}
+        }
+        return ExitStatus::success();
+    }
+
+    fn execute_pipeline(&mut self, pipeline: &Pipeline) -> Vec<ExitStatus> {
+        let mut statuses: Vec<ExitStatus> = Vec::new();
+        for (index, command) in pipeline.commands.iter().enumerate() {
+            if index > 0 {
+                self.executions.push("|".to_string());
+            }
+            statuses.push(run_command(self, command));
+        }
+        return statuses;
+    }
+
+    /// Runs the and-or list right away, like a subshell would
+    fn execute_background(&mut self, and_or: &AndOr) -> ExitStatus {
+        let variables = self.variables.clone();
+
+        self.executions.push("(".to_string());
+        run_and_or(self, and_or);
+        self.executions.push(") &".to_string());
+
+        self.variables = variables;
+        self.state.control_flow = ControlFlow::Normal;
+        return ExitStatus::success();
+    }
+
+    fn redirect(&mut self, redirects: &[Redirect]) -> Result<Vec<SavedFd>, ExitStatus> {
+        if redirects.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let descriptions: Vec<String> = redirects
+            .iter()
+            .map(|redirect| format!("{}{}{}", redirect.fd, redirect.operator, redirect.target))
+            .collect();
+        self.executions
+            .push(format!("redirect({})", descriptions.join(" ")));
+        return Ok(vec![]);
+    }
+
+    fn restore(&mut self, _saved: Vec<SavedFd>) {}
+
+    fn get_variable(&self, name: &str) -> Option<String> {
+        match name {
+            "#" => return Some(self.positional_parameters.len().to_string()),
+            "@" | "*" => return Some(self.positional_parameters.join(" ")),
+            _ => {}
+        }
+        if let Ok(index) = name.parse::<usize>() {
+            if index > 0 {
+                return self.positional_parameters.get(index - 1).cloned();
+            }
+        }
+
+        return self.variables.get(name).cloned();
+    }
+
+    fn set_variable(&mut self, name: &str, value: &str) {
+        self.variables.insert(name.to_string(), value.to_string());
+    }
+
+    fn set_exit_status(&mut self, status: &ExitStatus) {
+        self.set_variable("?", &status.code.to_string());
+    }
+
+    fn execute_subshell(&mut self, program: &List) -> ExitStatus {
+        let variables = self.variables.clone();
+
+        self.executions.push("(".to_string());
+        let status = run(self, program);
+        self.executions.push(")".to_string());
+
+        // A real subshell is a separate process, nothing it does can make us
+        // return
+        self.variables = variables;
+        self.state.control_flow = ControlFlow::Normal;
+        return status;
+    }
+
+    fn define_function(&mut self, name: &str, body: Rc<Command>) {
+        self.functions.insert(name.to_string(), body);
+    }
+
+    fn set_positional_parameters(&mut self, parameters: Vec<String>) -> Vec<String> {
+        return std::mem::replace(&mut self.positional_parameters, parameters);
+    }
+
+    fn get_oldpwd(&self) -> Option<String> {
+        // Real shells keep this in $OLDPWD, pretend we do too
+        return self.variables.get("OLDPWD").cloned();
+    }
+
+    fn get_positional_parameters(&self) -> Vec<String> {
+        return self.positional_parameters.clone();
+    }
+
+    fn state(&mut self) -> &mut InterpreterState {
+        return &mut self.state;
+    }
+
+    fn capture(&mut self, program: &List) -> (String, ExitStatus) {
+        let variables = self.variables.clone();
+
+        self.captures.push(String::new());
+        let status = run(self, program);
+        let output = self.captures.pop().unwrap();
+
+        self.variables = variables;
+        self.state.control_flow = ControlFlow::Normal;
+        return (output, status);
+    }
+}
+
+impl TestExecutor {
+    pub fn has_function(&self, name: &str) -> bool {
+        return self.functions.contains_key(name);
+    }
+
+    pub fn new() -> TestExecutor {
+        return TestExecutor {
+            executions: Vec::new(),
+            variables: HashMap::new(),
+            functions: HashMap::new(),
+            positional_parameters: Vec::new(),
+            captures: Vec::new(),
+            state: InterpreterState::default(),
+        };
+    }
+}
@@ -1,23 +1,48 @@
-use std::{fmt::Display, str::CharIndices};
+use std::{fmt::Display, ops::Range, str::CharIndices};
 
 use nom::Slice;
 use nom_locate::LocatedSpan;
 
+use crate::diagnostics::render;
+
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub(crate) struct Token<'a> {
     pub text: LocatedSpan<&'a str, ()>,
     pub is_comment: bool,
+
+    /// True if any part of this token was quoted, using either backslash,
+    /// single quotes or double quotes
+    pub is_quoted: bool,
+}
+
+impl<'a> Token<'a> {
+    /// True if this token is an unquoted operator, like `|` or `>>`
+    pub fn is_operator(&self) -> bool {
+        return !self.is_comment && !self.is_quoted && is_operator(&self.text);
+    }
 }
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct TokenizerError<'a> {
-    token: Token<'a>,
-    message: String,
+    /// The whole command line, for showing the error in context
+    pub commandline: &'a str,
+
+    pub token: Token<'a>,
+    pub message: String,
+
+    /// A hint on how to fix the problem, shown next to the `^^^` markers
+    pub help: Option<String>,
 }
 
 impl<'a> Display for TokenizerError<'a> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{}\n{}", "FIXME", self.message))
+        f.write_str(&render(
+            self.commandline,
+            self.token.text.location_offset(),
+            self.token.text.fragment().len(),
+            &self.message,
+            self.help.as_deref(),
+        ))
     }
 }
 
@@ -59,6 +84,9 @@ struct Tokenizer<'a> {
     /// Character we're currently at, updated in `next()`
     character: char,
 
+    /// Whether any part of the token we're currently building was quoted
+    token_is_quoted: bool,
+
     result: Vec<Token<'a>>,
 }
 
@@ -71,6 +99,7 @@ impl<'a> Tokenizer<'a> {
             byteindex: 0,
             iterator: input.char_indices(),
             character: '\0',
+            token_is_quoted: false,
         };
     }
 
@@ -84,16 +113,23 @@ impl<'a> Tokenizer<'a> {
         return false;
     }
 
+    /// Returns the character after the current one, without moving forward
+    fn peek(&self) -> Option<char> {
+        return self.iterator.clone().next().map(|(_, character)| character);
+    }
+
     fn delimit_token(&mut self) {
         if self.token_start < self.byteindex {
             // We are building a token, delimit that
             self.result.push(Token {
                 text: self.input.slice(self.token_start..self.byteindex),
                 is_comment: false,
+                is_quoted: self.token_is_quoted,
             });
         }
 
         self.token_start = self.byteindex;
+        self.token_is_quoted = false;
     }
 
     /// Ref:
@@ -107,21 +143,375 @@ impl<'a> Tokenizer<'a> {
             panic!("Must be at a backslash when calling this method");
         }
         if !self.next() {
-            return Some(Err(TokenizerError {
-                token: Token {
-                    text: self.input.slice(self.byteindex..),
-                    is_comment: false,
-                },
-                message:
-                    "Backslash can't be last, remove the backslash or add more characters after it"
-                        .to_string(),
-            }));
+            return Some(Err(self.error(
+                self.byteindex..self.input.len(),
+                "Backslash can't be last",
+                "Remove the backslash or add more characters after it",
+            )));
         }
 
         // Doing nothing here means we keep building the current token, so let's do nothing!
+        self.token_is_quoted = true;
         return None;
     }
 
+    /// Creates an error pointing out the part of the input at `range`
+    fn error(&self, range: Range<usize>, message: &str, help: &str) -> TokenizerError<'a> {
+        return TokenizerError {
+            commandline: self.input.fragment(),
+            token: Token {
+                text: self.input.slice(range),
+                is_comment: false,
+                is_quoted: false,
+            },
+            message: message.to_string(),
+            help: Some(help.to_string()),
+        };
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_02
+    ///
+    /// Consumes everything up to and including the closing single quote.
+    ///
+    /// This function returns `Some(Err(TokenizerError{...}))` on failure, `None`
+    /// otherwise.
+    #[must_use]
+    fn tokenize_single_quote(&mut self) -> Option<Result<Vec<Token<'a>>, TokenizerError<'a>>> {
+        if self.character != '\'' {
+            panic!("Must be at a single quote when calling this method");
+        }
+        let quote_index = self.byteindex;
+
+        while self.next() {
+            if self.character == '\'' {
+                self.token_is_quoted = true;
+                return None;
+            }
+        }
+
+        return Some(Err(self.error(
+            quote_index..(quote_index + 1),
+            "Unterminated single quote",
+            "Did you mean to close the quote opened here?",
+        )));
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_02_03
+    ///
+    /// Consumes everything up to and including the closing double quote.
+    /// Backslashes inside of the double quotes escape the next character, so
+    /// `"\""` is one token.
+    ///
+    /// This function returns `Some(Err(TokenizerError{...}))` on failure, `None`
+    /// otherwise.
+    #[must_use]
+    fn tokenize_double_quote(&mut self) -> Option<Result<Vec<Token<'a>>, TokenizerError<'a>>> {
+        if self.character != '"' {
+            panic!("Must be at a double quote when calling this method");
+        }
+        let quote_index = self.byteindex;
+
+        while self.next() {
+            if self.character == '\\' {
+                // Whatever comes after the backslash can't end the quoted
+                // section, skip it
+                if !self.next() {
+                    break;
+                }
+                continue;
+            }
+
+            if self.character == '"' {
+                self.token_is_quoted = true;
+                return None;
+            }
+
+            if self.character == '$' {
+                if let Some(error) = self.tokenize_dollar() {
+                    return Some(error);
+                }
+            }
+
+            if self.character == '`' {
+                if let Some(error) = self.tokenize_backticks() {
+                    return Some(error);
+                }
+            }
+        }
+
+        return Some(Err(self.error(
+            quote_index..(quote_index + 1),
+            "Unterminated double quote",
+            "Did you mean to close the quote opened here?",
+        )));
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_02
+    ///
+    /// Consumes a `${...}` parameter expansion or a `$(...)` command
+    /// substitution, including any nested quotes and expansions, so that
+    /// `${X:-a b}` ends up in one token. Other `$` constructs need no special
+    /// treatment.
+    ///
+    /// This function returns `Some(Err(TokenizerError{...}))` on failure, `None`
+    /// otherwise.
+    #[must_use]
+    fn tokenize_dollar(&mut self) -> Option<Result<Vec<Token<'a>>, TokenizerError<'a>>> {
+        if self.character != '$' {
+            panic!("Must be at a dollar sign when calling this method");
+        }
+        if self.peek() == Some('(') {
+            if let Some(result) = self.tokenize_arithmetic() {
+                return result.err().map(Err);
+            }
+            return self.tokenize_command_substitution();
+        }
+        if self.peek() == Some('#') {
+            // "$#", not a comment
+            self.next();
+            return None;
+        }
+        if self.peek() != Some('{') {
+            return None;
+        }
+        let dollar_index = self.byteindex;
+        self.next();
+
+        while self.next() {
+            if self.character == '\\' {
+                // Whatever comes after the backslash can't end the expansion
+                if !self.next() {
+                    break;
+                }
+                continue;
+            }
+
+            match self.character {
+                '}' => return None,
+                '\'' => {
+                    if let Some(error) = self.tokenize_single_quote() {
+                        return Some(error);
+                    }
+                }
+                '"' => {
+                    if let Some(error) = self.tokenize_double_quote() {
+                        return Some(error);
+                    }
+                }
+                '$' => {
+                    if let Some(error) = self.tokenize_dollar() {
+                        return Some(error);
+                    }
+                }
+                '`' => {
+                    if let Some(error) = self.tokenize_backticks() {
+                        return Some(error);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        return Some(Err(self.error(
+            dollar_index..(dollar_index + 2),
+            "Unterminated ${",
+            "Add a closing } somewhere after this",
+        )));
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_04
+    ///
+    /// Consumes a `$((...))` arithmetic expansion.
+    ///
+    /// Returns `None` without consuming anything if this turns out to be a
+    /// command substitution starting with a subshell, as in
+    /// `$((cd /tmp); ls)`. That's the case when the first unbalanced `)` isn't
+    /// directly followed by another `)`.
+    #[must_use]
+    fn tokenize_arithmetic(&mut self) -> Option<Result<(), TokenizerError<'a>>> {
+        if self.character != '$' {
+            panic!("Must be at a dollar sign when calling this method");
+        }
+        let dollar_index = self.byteindex;
+        let saved_iterator = self.iterator.clone();
+
+        self.next();
+        if self.peek() != Some('(') {
+            self.iterator = saved_iterator;
+            self.byteindex = dollar_index;
+            self.character = '$';
+            return None;
+        }
+        self.next();
+
+        // Quotes inside of the expansion don't make our token quoted
+        let token_was_quoted = self.token_is_quoted;
+
+        let mut depth = 0;
+        while self.next() {
+            let nested_error = match self.character {
+                '(' => {
+                    depth += 1;
+                    None
+                }
+                ')' if depth > 0 => {
+                    depth -= 1;
+                    None
+                }
+                ')' => {
+                    if self.peek() == Some(')') {
+                        self.next();
+                        self.token_is_quoted = token_was_quoted;
+                        return Some(Ok(()));
+                    }
+
+                    // Not arithmetic after all, back off
+                    self.iterator = saved_iterator;
+                    self.byteindex = dollar_index;
+                    self.character = '$';
+                    self.token_is_quoted = token_was_quoted;
+                    return None;
+                }
+                '"' => self.tokenize_double_quote(),
+                '$' => self.tokenize_dollar(),
+                '`' => self.tokenize_backticks(),
+                _ => None,
+            };
+            if let Some(Err(error)) = nested_error {
+                return Some(Err(error));
+            }
+        }
+
+        return Some(Err(self.error(
+            dollar_index..(dollar_index + 3),
+            "Unterminated $((",
+            "Add a closing )) somewhere after this",
+        )));
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+    ///
+    /// Consumes a `$(...)` command substitution, so that `$(ls | wc -l)` ends
+    /// up in one token. The substitution ends at the first `)` that doesn't
+    /// close a `(` inside of it.
+    ///
+    /// This function returns `Some(Err(TokenizerError{...}))` on failure, `None`
+    /// otherwise.
+    #[must_use]
+    fn tokenize_command_substitution(
+        &mut self,
+    ) -> Option<Result<Vec<Token<'a>>, TokenizerError<'a>>> {
+        if self.character != '$' {
+            panic!("Must be at a dollar sign when calling this method");
+        }
+        let dollar_index = self.byteindex;
+        self.next();
+
+        // Quotes inside of the substitution don't make our token quoted
+        let token_was_quoted = self.token_is_quoted;
+
+        // FIXME: Case patterns like "a)" inside of the substitution will end
+        // it prematurely
+        let mut depth = 0;
+        let mut previous = self.character;
+        while self.next() {
+            let character = self.character;
+            if character == '\\' {
+                // Whatever comes after the backslash can't end the substitution
+                if !self.next() {
+                    break;
+                }
+                previous = self.character;
+                continue;
+            }
+
+            match character {
+                '\'' => {
+                    if let Some(error) = self.tokenize_single_quote() {
+                        return Some(error);
+                    }
+                }
+                '"' => {
+                    if let Some(error) = self.tokenize_double_quote() {
+                        return Some(error);
+                    }
+                }
+                '$' => {
+                    if let Some(error) = self.tokenize_dollar() {
+                        return Some(error);
+                    }
+                }
+                '`' => {
+                    if let Some(error) = self.tokenize_backticks() {
+                        return Some(error);
+                    }
+                }
+                '#' if previous == ' ' || previous == '\n' || previous == '(' => {
+                    // Comment, which may contain anything up to the end of the line
+                    while self.peek().is_some() && self.peek() != Some('\n') {
+                        self.next();
+                    }
+                }
+                '(' => depth += 1,
+                ')' => {
+                    if depth == 0 {
+                        self.token_is_quoted = token_was_quoted;
+                        return None;
+                    }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+            previous = character;
+        }
+
+        return Some(Err(self.error(
+            dollar_index..(dollar_index + 2),
+            "Unterminated $(",
+            "Add a closing ) somewhere after this",
+        )));
+    }
+
+    /// Ref:
+    /// https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_06_03
+    ///
+    /// Consumes everything up to and including the closing backtick of a
+    /// legacy `` `...` `` command substitution.
+    ///
+    /// This function returns `Some(Err(TokenizerError{...}))` on failure, `None`
+    /// otherwise.
+    #[must_use]
+    fn tokenize_backticks(&mut self) -> Option<Result<Vec<Token<'a>>, TokenizerError<'a>>> {
+        if self.character != '`' {
+            panic!("Must be at a backtick when calling this method");
+        }
+        let backtick_index = self.byteindex;
+
+        while self.next() {
+            if self.character == '\\' {
+                if !self.next() {
+                    break;
+                }
+                continue;
+            }
+
+            if self.character == '`' {
+                return None;
+            }
+        }
+
+        return Some(Err(self.error(
+            backtick_index..(backtick_index + 1),
+            "Unterminated backtick",
+            "Did you mean to close the backtick opened here?",
+        )));
+    }
+
     /// Fills in Tokenizer.result by following [these ten steps][1].
     ///
     /// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_03
@@ -148,12 +538,48 @@ impl<'a> Tokenizer<'a> {
             }
 
             // Rule 4
+            if self.character == '\\'
+                && self.peek() == Some('\n')
+                && self.token_start == self.byteindex
+            {
+                // Line continuation between words, skip both characters.
+                // Inside of words the expansion code removes them.
+                self.next();
+                self.token_start = self.byteindex + 1;
+                continue;
+            }
             if self.character == '\\' {
                 if let Some(error) = self.tokenize_backslash_escape() {
                     return error;
                 }
                 continue;
             }
+            if self.character == '\'' {
+                if let Some(error) = self.tokenize_single_quote() {
+                    return error;
+                }
+                continue;
+            }
+            if self.character == '"' {
+                if let Some(error) = self.tokenize_double_quote() {
+                    return error;
+                }
+                continue;
+            }
+
+            // Rule 5
+            if self.character == '$' {
+                if let Some(error) = self.tokenize_dollar() {
+                    return error;
+                }
+                continue;
+            }
+            if self.character == '`' {
+                if let Some(error) = self.tokenize_backticks() {
+                    return error;
+                }
+                continue;
+            }
 
             // Rule 6
             if is_start_of_operator(self.character) {
@@ -161,7 +587,7 @@ impl<'a> Tokenizer<'a> {
             }
 
             // Rule 7
-            if self.character == ' ' {
+            if self.character == ' ' || self.character == '\t' {
                 self.delimit_token();
 
                 // Rule 10, try starting a new token at the next character
@@ -173,12 +599,21 @@ impl<'a> Tokenizer<'a> {
             if self.character == '#' {
                 self.delimit_token();
 
+                // The comment goes on until the end of the line, the newline
+                // itself is an operator token
+                let comment_start = self.byteindex;
+                while self.peek().is_some() && self.peek() != Some('\n') {
+                    self.next();
+                }
+                let comment_end = self.byteindex + self.character.len_utf8();
                 self.result.push(Token {
-                    text: self.input.slice(self.byteindex..),
+                    text: self.input.slice(comment_start..comment_end),
                     is_comment: true,
+                    is_quoted: false,
                 });
 
-                return Ok(self.result);
+                self.token_start = comment_end;
+                continue;
             }
 
             // Rule 8, no code needed for this, we're inside a word, just keep
@@ -196,10 +631,48 @@ impl<'a> Tokenizer<'a> {
 /// Implementation of [these ten steps][1]:
 ///
 /// [1]: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_03
-pub(crate) fn to_tokens(input: &str) -> Result<Vec<Token>, TokenizerError> {
+pub(crate) fn to_tokens(input: &str) -> Result<Vec<Token<'_>>, TokenizerError<'_>> {
     return Tokenizer::new(input).tokenize();
 }
 
+/// Returns the byte length of the `${...}`, `$(...)` or `` `...` `` construct
+/// at the start of `text`, or `None` if it is unterminated.
+///
+/// This makes the expansion code agree with the tokenizer about where each
+/// construct ends.
+pub(crate) fn substitution_length(text: &str) -> Option<usize> {
+    let mut tokenizer = Tokenizer::new(text);
+    if !tokenizer.next() {
+        return None;
+    }
+
+    let error = match tokenizer.character {
+        '$' => tokenizer.tokenize_dollar(),
+        '`' => tokenizer.tokenize_backticks(),
+        _ => return None,
+    };
+    if error.is_some() {
+        return None;
+    }
+
+    return Some(tokenizer.byteindex + tokenizer.character.len_utf8());
+}
+
+/// Returns the byte length of the `$((...))` arithmetic expansion at the start
+/// of `text`, or `None` if there is none, or if it's a `$((` command
+/// substitution starting with a subshell.
+pub(crate) fn arithmetic_length(text: &str) -> Option<usize> {
+    let mut tokenizer = Tokenizer::new(text);
+    if !tokenizer.next() || tokenizer.character != '$' {
+        return None;
+    }
+
+    match tokenizer.tokenize_arithmetic() {
+        Some(Ok(())) => return Some(tokenizer.byteindex + tokenizer.character.len_utf8()),
+        _ => return None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
@@ -229,6 +702,7 @@ mod tests {
         failed_part: &str,
         failed_offset: usize,
         message: &str,
+        help: &str,
     ) {
         let error = to_tokens(commandline).unwrap_err();
 
@@ -239,11 +713,14 @@ mod tests {
             assert_eq!(
                 error,
                 TokenizerError {
+                    commandline,
                     token: Token {
                         text: expected_span,
                         is_comment: false,
+                        is_quoted: false,
                     },
                     message: message.to_string(),
+                    help: Some(help.to_string()),
                 }
             );
         }
@@ -266,15 +743,213 @@ mod tests {
             r"apa\",
             r"\",
             3,
-            "Backslash can't be last, remove the backslash or add more characters after it",
+            "Backslash can't be last",
+            "Remove the backslash or add more characters after it",
+        );
+    }
+
+    #[test]
+    fn test_backslash_newline() {
+        assert_eq!(to_token_strings("echo a \\\n  b"), vec!["echo", "a", "b"]);
+        assert_eq!(
+            to_token_strings("echo a\\\nb c"),
+            vec!["echo", "a\\\nb", "c"]
+        );
+    }
+
+    #[test]
+    fn test_multiple_lines() {
+        assert_eq!(
+            to_token_strings("echo a # comment\n\techo\tb"),
+            vec!["echo", "a", "# comment", "\n", "echo", "b"]
         );
+        assert_eq!(to_token_strings("echo $#"), vec!["echo", "$#"]);
     }
 
-    // FIXME: Add backslash-newline continuation marker test(s)
+    #[test]
+    fn test_single_quotes() {
+        assert_eq!(
+            to_token_strings("echo 'hello world'"),
+            vec!["echo", "'hello world'"]
+        );
+        assert_eq!(
+            to_token_strings("echo a'b c'd e"),
+            vec!["echo", "a'b c'd", "e"]
+        );
 
-    // FIXME: Write tests for all the quoting rules (step 4):
-    // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_03
+        // Backslashes and operators have no special meaning inside single quotes
+        assert_eq!(to_token_strings(r"echo '\' x"), vec!["echo", r"'\'", "x"]);
+        assert_eq!(to_token_strings("echo '>;|'"), vec!["echo", "'>;|'"]);
+        assert_eq!(to_token_strings("echo ''"), vec!["echo", "''"]);
+
+        assert_parse_error(
+            "echo 'hej",
+            "'",
+            5,
+            "Unterminated single quote",
+            "Did you mean to close the quote opened here?",
+        );
+    }
 
-    // FIXME: Write expansion / substitution (step 5) tests:
-    // https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_03
+    #[test]
+    fn test_double_quotes() {
+        assert_eq!(
+            to_token_strings(r#"git commit -m "fix bug""#),
+            vec!["git", "commit", "-m", r#""fix bug""#]
+        );
+        assert_eq!(
+            to_token_strings(r#"echo "a \" b" c"#),
+            vec!["echo", r#""a \" b""#, "c"]
+        );
+        assert_eq!(
+            to_token_strings(r#"echo "it's""#),
+            vec!["echo", r#""it's""#]
+        );
+        assert_eq!(
+            to_token_strings(r##"echo "#"x"##),
+            vec!["echo", r##""#"x"##]
+        );
+
+        assert_parse_error(
+            r#"echo "hej \""#,
+            "\"",
+            5,
+            "Unterminated double quote",
+            "Did you mean to close the quote opened here?",
+        );
+    }
+
+    #[test]
+    fn test_quoted_state() {
+        let tokens = to_tokens(r#"echo 'a' "b" \c d"#).unwrap();
+        let quoted: Vec<bool> = tokens.iter().map(|token| token.is_quoted).collect();
+        assert_eq!(quoted, vec![false, true, true, true, false]);
+    }
+
+    #[test]
+    fn test_parameter_expansion() {
+        assert_eq!(to_token_strings("echo $HOME"), vec!["echo", "$HOME"]);
+        assert_eq!(
+            to_token_strings("echo ${X:-a b}c"),
+            vec!["echo", "${X:-a b}c"]
+        );
+        assert_eq!(
+            to_token_strings("echo ${X:-${Y:-;}} x"),
+            vec!["echo", "${X:-${Y:-;}}", "x"]
+        );
+        assert_eq!(
+            to_token_strings(r#"echo "${X:-"}"}""#),
+            vec!["echo", r#""${X:-"}"}""#]
+        );
+        assert_eq!(
+            to_token_strings(r"echo ${X:-\}}"),
+            vec!["echo", r"${X:-\}}"]
+        );
+        assert_eq!(to_token_strings("echo $ x"), vec!["echo", "$", "x"]);
+
+        assert_parse_error(
+            "echo ${X:-a",
+            "${",
+            5,
+            "Unterminated ${",
+            "Add a closing } somewhere after this",
+        );
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        assert_eq!(
+            to_token_strings("echo $(ls | wc -l)"),
+            vec!["echo", "$(ls | wc -l)"]
+        );
+        assert_eq!(
+            to_token_strings("echo a$(echo $(echo b) (c))d e"),
+            vec!["echo", "a$(echo $(echo b) (c))d", "e"]
+        );
+        assert_eq!(
+            to_token_strings("echo $(echo ')' \\) \")\")"),
+            vec!["echo", "$(echo ')' \\) \")\")"]
+        );
+        assert_eq!(
+            to_token_strings("echo $(echo a # )\n)"),
+            vec!["echo", "$(echo a # )\n)"]
+        );
+        assert_eq!(
+            to_token_strings("echo \"$(echo \"a b\")\""),
+            vec!["echo", "\"$(echo \"a b\")\""]
+        );
+        assert_eq!(
+            to_token_strings("echo `ls | wc -l` x"),
+            vec!["echo", "`ls | wc -l`", "x"]
+        );
+        assert_eq!(
+            to_token_strings(r"echo `echo \`x\``"),
+            vec!["echo", r"`echo \`x\``"]
+        );
+
+        // Quotes inside of the substitution don't quote the token
+        assert!(!to_tokens("$(echo 'a')").unwrap()[0].is_quoted);
+
+        assert_parse_error(
+            "echo $(ls",
+            "$(",
+            5,
+            "Unterminated $(",
+            "Add a closing ) somewhere after this",
+        );
+        assert_parse_error(
+            "echo `ls",
+            "`",
+            5,
+            "Unterminated backtick",
+            "Did you mean to close the backtick opened here?",
+        );
+    }
+
+    #[test]
+    fn test_substitution_length() {
+        assert_eq!(substitution_length("${X}y"), Some(4));
+        assert_eq!(substitution_length("$(a (b) c)d"), Some(10));
+        assert_eq!(substitution_length("`a`b"), Some(3));
+        assert_eq!(substitution_length("$(a"), None);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(
+            to_token_strings("echo $(( (1 + 2) * 3 ))x y"),
+            vec!["echo", "$(( (1 + 2) * 3 ))x", "y"]
+        );
+        assert_eq!(
+            to_token_strings("echo $((i + $(echo 1)))"),
+            vec!["echo", "$((i + $(echo 1)))"]
+        );
+
+        // A command substitution starting with a subshell
+        assert_eq!(
+            to_token_strings("echo $((cd /) ; (pwd))"),
+            vec!["echo", "$((cd /) ; (pwd))"]
+        );
+
+        assert_eq!(arithmetic_length("$((1+2))x"), Some(8));
+        assert_eq!(arithmetic_length("$(( (1) ))"), Some(10));
+        assert_eq!(arithmetic_length("$((cd /) ; (pwd))"), None);
+        assert_eq!(arithmetic_length("$(echo)"), None);
+
+        let error = to_tokens("echo $((1 + 2").unwrap_err();
+        assert_eq!(error.message, "Unterminated $((");
+        assert_eq!(error.token.text.location_offset(), 5);
+    }
+
+    #[test]
+    fn test_display_error() {
+        assert_eq!(
+            to_tokens("echo \"hej").unwrap_err().to_string(),
+            "Unterminated double quote\n  echo \"hej\n       ^ Did you mean to close the quote opened here?"
+        );
+        assert_eq!(
+            to_tokens("echo ${X:-a").unwrap_err().to_string(),
+            "Unterminated ${\n  echo ${X:-a\n       ^^ Add a closing } somewhere after this"
+        );
+    }
 }
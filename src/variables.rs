@@ -0,0 +1,240 @@
+use std::collections::BTreeMap;
+use std::env;
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Variable {
+    /// `None` for variables that have been exported but never set, as after
+    /// `export X`
+    pub value: Option<String>,
+
+    /// Exported variables are passed on to the commands we run
+    pub exported: bool,
+}
+
+/// What a set of variables looked like before `set_temporarily()`, pass this to
+/// `restore()` to go back
+pub(crate) type SavedVariables = Vec<(String, Option<Variable>)>;
+
+/// The shell's variables. Exported ones make up the environment of every
+/// command we run.
+///
+/// Ref: https://pubs.opengroup.org/onlinepubs/9699919799/utilities/V3_chap02.html#tag_18_05_03
+pub(crate) struct Variables {
+    /// Sorted by name, for `export -p`
+    variables: BTreeMap<String, Variable>,
+
+    /// One entry per function call we're in, with what the variables made
+    /// `local` in that function looked like before
+    scopes: Vec<SavedVariables>,
+}
+
+impl Variables {
+    pub fn new() -> Self {
+        return Variables {
+            variables: BTreeMap::new(),
+            scopes: Vec::new(),
+        };
+    }
+
+    /// All variables from our own environment, exported
+    pub fn from_environment() -> Self {
+        let mut variables = Variables::new();
+        for (name, value) in env::vars_os() {
+            let name = name.into_string();
+            let value = value.into_string();
+            if name.is_err() || value.is_err() {
+                // FIXME: Support non-UTF-8 environment variables
+                continue;
+            }
+
+            let name = name.unwrap();
+            variables.set(&name, &value.unwrap());
+            variables.export(&name);
+        }
+
+        return variables;
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        return self
+            .variables
+            .get(name)
+            .and_then(|variable| variable.value.as_deref());
+    }
+
+    /// Sets the value, exported variables stay exported
+    pub fn set(&mut self, name: &str, value: &str) {
+        let variable = self.variables.entry(name.to_string()).or_insert(Variable {
+            value: None,
+            exported: false,
+        });
+        variable.value = Some(value.to_string());
+    }
+
+    /// Marks the variable as exported, whether it's set or not
+    pub fn export(&mut self, name: &str) {
+        let variable = self.variables.entry(name.to_string()).or_insert(Variable {
+            value: None,
+            exported: false,
+        });
+        variable.exported = true;
+    }
+
+    pub fn unset(&mut self, name: &str) {
+        self.variables.remove(name);
+    }
+
+    /// All variables, sorted by name
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &Variable)> {
+        return self.variables.iter();
+    }
+
+    /// The exported variables that have values, for passing to commands
+    pub fn environment(&self) -> impl Iterator<Item = (&String, &String)> {
+        return self.variables.iter().filter_map(|(name, variable)| {
+            if !variable.exported {
+                return None;
+            }
+            return variable.value.as_ref().map(|value| (name, value));
+        });
+    }
+
+    /// Sets and exports the variables until `restore()` is called, as in
+    /// `FOO=1 cmd`
+    pub fn set_temporarily(&mut self, assignments: &[(String, String)]) -> SavedVariables {
+        let mut saved: SavedVariables = Vec::new();
+        for (name, value) in assignments {
+            saved.push((name.to_string(), self.variables.get(name).cloned()));
+            self.set(name, value);
+            self.export(name);
+        }
+        return saved;
+    }
+
+    /// Undoes what `set_temporarily()` did
+    pub fn restore(&mut self, saved: SavedVariables) {
+        // Restore in reverse order, in case the same variable was assigned
+        // more than once
+        for (name, variable) in saved.into_iter().rev() {
+            match variable {
+                Some(variable) => {
+                    self.variables.insert(name, variable);
+                }
+                None => {
+                    self.variables.remove(&name);
+                }
+            }
+        }
+    }
+
+    /// Starts a new scope for `local` variables, call this when entering a
+    /// function
+    pub fn push_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    /// Restores all variables made `local` since the matching `push_scope()`
+    pub fn pop_scope(&mut self) {
+        let saved = self.scopes.pop().expect("pop_scope() without push_scope()");
+        self.restore(saved);
+    }
+
+    /// Unsets the variable until the current scope is popped. Returns `false`
+    /// if we are not in any scope.
+    pub fn make_local(&mut self, name: &str) -> bool {
+        let scope = match self.scopes.last_mut() {
+            Some(scope) => scope,
+            None => return false,
+        };
+
+        // Only the first save is the one from outside of this scope
+        if !scope.iter().any(|(saved_name, _)| saved_name == name) {
+            scope.push((name.to_string(), self.variables.remove(name)));
+        } else {
+            self.variables.remove(name);
+        }
+        return true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn environment(variables: &Variables) -> Vec<String> {
+        return variables
+            .environment()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+    }
+
+    #[test]
+    fn test_export() {
+        let mut variables = Variables::new();
+        variables.set("B", "b");
+        variables.set("A", "a");
+        assert_eq!(variables.get("A"), Some("a"));
+        assert!(environment(&variables).is_empty());
+
+        variables.export("B");
+        variables.export("A");
+        variables.export("UNSET");
+        assert_eq!(environment(&variables), vec!["A=a", "B=b"]);
+        assert_eq!(variables.get("UNSET"), None);
+
+        // Exported variables stay exported when changed
+        variables.set("A", "x");
+        assert_eq!(environment(&variables), vec!["A=x", "B=b"]);
+
+        variables.unset("A");
+        assert_eq!(variables.get("A"), None);
+        assert_eq!(environment(&variables), vec!["B=b"]);
+    }
+
+    #[test]
+    fn test_set_temporarily() {
+        let mut variables = Variables::new();
+        variables.set("LOCAL", "local");
+
+        let saved = variables.set_temporarily(&[
+            ("LOCAL".to_string(), "1".to_string()),
+            ("NEW".to_string(), "2".to_string()),
+            ("NEW".to_string(), "3".to_string()),
+        ]);
+        assert_eq!(environment(&variables), vec!["LOCAL=1", "NEW=3"]);
+
+        variables.restore(saved);
+        assert_eq!(variables.get("LOCAL"), Some("local"));
+        assert_eq!(variables.get("NEW"), None);
+        assert!(environment(&variables).is_empty());
+    }
+
+    #[test]
+    fn test_local() {
+        let mut variables = Variables::new();
+        assert!(!variables.make_local("X"));
+
+        variables.set("X", "global");
+        variables.export("X");
+        variables.push_scope();
+        assert!(variables.make_local("X"));
+        assert_eq!(variables.get("X"), None);
+        variables.set("X", "outer");
+
+        variables.push_scope();
+        assert!(variables.make_local("X"));
+        variables.set("X", "inner");
+        assert!(variables.make_local("X"));
+        assert!(variables.make_local("NEW"));
+        variables.set("NEW", "new");
+        assert!(environment(&variables).is_empty());
+        variables.pop_scope();
+
+        assert_eq!(variables.get("X"), Some("outer"));
+        assert_eq!(variables.get("NEW"), None);
+        variables.pop_scope();
+
+        assert_eq!(variables.get("X"), Some("global"));
+        assert_eq!(environment(&variables), vec!["X=global"]);
+    }
+}